@@ -0,0 +1,399 @@
+/*
+ * NSS module resolving hostnames registered in vmc_server.
+ *
+ *   $ cargo build --release -p libnss_vmc
+ *   $ sudo cp target/release/libnss_vmc.so /lib/x86_64-linux-gnu/libnss_vmc.so.2
+ *   and add vmc to the hosts line of /etc/nsswitch.conf:
+ *     hosts: files vmc dns
+ */
+mod resolver;
+
+use libc::{c_char, c_int, hostent, size_t, AF_INET, AF_INET6, AF_UNSPEC, ERANGE};
+use std::ffi::{CStr, CString};
+use std::mem::{align_of, size_of};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::panic::catch_unwind;
+use std::ptr;
+use vmc_common::types::MachineInfo;
+
+use crate::resolver::{lookup, Lookup};
+
+// enum nss_status in <nss.h>
+type NssStatus = c_int;
+const NSS_STATUS_TRYAGAIN: NssStatus = -2;
+const NSS_STATUS_UNAVAIL: NssStatus = -1;
+const NSS_STATUS_NOTFOUND: NssStatus = 0;
+const NSS_STATUS_SUCCESS: NssStatus = 1;
+
+// h_errno values in <netdb.h>
+const HOST_NOT_FOUND: c_int = 1;
+const TRY_AGAIN: c_int = 2;
+const NO_RECOVERY: c_int = 3;
+
+// struct gaih_addrtuple in <nss.h>
+#[repr(C)]
+pub struct GaihAddrtuple {
+    next: *mut GaihAddrtuple,
+    name: *mut c_char,
+    family: c_int,
+    addr: [u32; 4],
+    scopeid: u32,
+}
+
+struct HostAddr {
+    family: c_int,
+    bytes: [u8; 16],
+    len: usize,
+    scope_id: u32,
+}
+
+impl HostAddr {
+    fn v4(addr: Ipv4Addr) -> Self {
+        let mut bytes = [0; 16];
+        bytes[..4].copy_from_slice(&addr.octets());
+        Self {
+            family: AF_INET,
+            bytes,
+            len: 4,
+            scope_id: 0,
+        }
+    }
+
+    fn v6(addr: Ipv6Addr, scope_id: u32) -> Self {
+        Self {
+            family: AF_INET6,
+            bytes: addr.octets(),
+            len: 16,
+            scope_id,
+        }
+    }
+}
+
+// ipv6 addrs are reported as "fe80::1%eth0", the scope name is resolved on this machine
+fn parse_ipv6(s: &str) -> Option<(Ipv6Addr, u32)> {
+    let (addr, scope) = match s.split_once('%') {
+        Some((addr, scope)) => (addr, Some(scope)),
+        None => (s, None),
+    };
+    let addr = addr.parse().ok()?;
+    let scope_id = scope
+        .and_then(|scope| CString::new(scope).ok())
+        .map(|scope| unsafe { libc::if_nametoindex(scope.as_ptr()) })
+        .unwrap_or(0);
+
+    Some((addr, scope_id))
+}
+
+fn host_addrs(mi: &MachineInfo, family: c_int) -> Vec<HostAddr> {
+    let mut addrs = vec![];
+
+    if family == AF_INET || family == AF_UNSPEC {
+        if let Ok(addr) = mi.ipv4_addr.parse() {
+            addrs.push(HostAddr::v4(addr));
+        }
+    }
+    if family == AF_INET6 || family == AF_UNSPEC {
+        if let Some((addr, scope_id)) = mi.ipv6_addr.as_deref().and_then(parse_ipv6) {
+            addrs.push(HostAddr::v6(addr, scope_id));
+        }
+    }
+
+    addrs
+}
+
+// hands out aligned chunks of the caller supplied buffer
+struct Buffer {
+    ptr: *mut u8,
+    len: usize,
+    offset: usize,
+}
+
+impl Buffer {
+    fn alloc(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        let start = (self.ptr as usize + self.offset).next_multiple_of(align) - self.ptr as usize;
+        if start + size > self.len {
+            return None;
+        }
+        self.offset = start + size;
+
+        Some(unsafe { self.ptr.add(start) })
+    }
+
+    fn push_bytes(&mut self, bytes: &[u8]) -> Option<*mut u8> {
+        let p = self.alloc(bytes.len(), 1)?;
+        unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), p, bytes.len()) };
+
+        Some(p)
+    }
+
+    fn push_str(&mut self, s: &CStr) -> Option<*mut c_char> {
+        self.push_bytes(s.to_bytes_with_nul())
+            .map(|p| p as *mut c_char)
+    }
+
+    fn push_ptr_array(&mut self, ptrs: &[*mut c_char]) -> Option<*mut *mut c_char> {
+        let p = self.alloc(
+            size_of::<*mut c_char>() * (ptrs.len() + 1),
+            align_of::<*mut c_char>(),
+        )? as *mut *mut c_char;
+        unsafe {
+            ptr::copy_nonoverlapping(ptrs.as_ptr(), p, ptrs.len());
+            *p.add(ptrs.len()) = ptr::null_mut();
+        }
+
+        Some(p)
+    }
+}
+
+enum Resolved {
+    Found(MachineInfo),
+    Status(NssStatus, c_int),
+}
+
+fn resolve(name: *const c_char) -> Resolved {
+    let Ok(hostname) = unsafe { CStr::from_ptr(name) }.to_str() else {
+        return Resolved::Status(NSS_STATUS_NOTFOUND, HOST_NOT_FOUND);
+    };
+
+    match catch_unwind(|| lookup(hostname)) {
+        Ok(Lookup::Found(mi)) => Resolved::Found(mi),
+        Ok(Lookup::NotFound) => Resolved::Status(NSS_STATUS_NOTFOUND, HOST_NOT_FOUND),
+        Ok(Lookup::Unavailable) => Resolved::Status(NSS_STATUS_UNAVAIL, TRY_AGAIN),
+        Err(_) => Resolved::Status(NSS_STATUS_UNAVAIL, NO_RECOVERY),
+    }
+}
+
+fn fill_hostent(
+    name: &CStr,
+    family: c_int,
+    addrs: &[HostAddr],
+    result: &mut hostent,
+    buf: &mut Buffer,
+) -> Option<()> {
+    let h_name = buf.push_str(name)?;
+    let h_aliases = buf.push_ptr_array(&[])?;
+    let addr_ptrs = addrs
+        .iter()
+        .map(|addr| {
+            buf.push_bytes(&addr.bytes[..addr.len])
+                .map(|p| p as *mut c_char)
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let h_addr_list = buf.push_ptr_array(&addr_ptrs)?;
+
+    result.h_name = h_name;
+    result.h_aliases = h_aliases;
+    result.h_addrtype = family;
+    result.h_length = if family == AF_INET6 { 16 } else { 4 };
+    result.h_addr_list = h_addr_list;
+
+    Some(())
+}
+
+/// # Safety
+/// Called by glibc with the arguments described in nss(5).
+#[no_mangle]
+pub unsafe extern "C" fn _nss_vmc_gethostbyname2_r(
+    name: *const c_char,
+    af: c_int,
+    result: *mut hostent,
+    buffer: *mut c_char,
+    buflen: size_t,
+    errnop: *mut c_int,
+    h_errnop: *mut c_int,
+) -> NssStatus {
+    let family = if af == AF_UNSPEC { AF_INET } else { af };
+    if family != AF_INET && family != AF_INET6 {
+        *h_errnop = NO_RECOVERY;
+        return NSS_STATUS_UNAVAIL;
+    }
+
+    let mi = match resolve(name) {
+        Resolved::Found(mi) => mi,
+        Resolved::Status(status, h_errno) => {
+            *h_errnop = h_errno;
+            return status;
+        }
+    };
+
+    // hostent can hold addrs of only one family
+    let addrs: Vec<_> = host_addrs(&mi, family)
+        .into_iter()
+        .filter(|addr| addr.family == family)
+        .collect();
+    if addrs.is_empty() {
+        *h_errnop = HOST_NOT_FOUND;
+        return NSS_STATUS_NOTFOUND;
+    }
+
+    let mut buf = Buffer {
+        ptr: buffer as *mut u8,
+        len: buflen,
+        offset: 0,
+    };
+    if fill_hostent(CStr::from_ptr(name), family, &addrs, &mut *result, &mut buf).is_none() {
+        *errnop = ERANGE;
+        *h_errnop = TRY_AGAIN;
+        return NSS_STATUS_TRYAGAIN;
+    }
+
+    NSS_STATUS_SUCCESS
+}
+
+/// # Safety
+/// Called by glibc with the arguments described in nss(5).
+#[no_mangle]
+pub unsafe extern "C" fn _nss_vmc_gethostbyname_r(
+    name: *const c_char,
+    result: *mut hostent,
+    buffer: *mut c_char,
+    buflen: size_t,
+    errnop: *mut c_int,
+    h_errnop: *mut c_int,
+) -> NssStatus {
+    _nss_vmc_gethostbyname2_r(name, AF_INET, result, buffer, buflen, errnop, h_errnop)
+}
+
+/// # Safety
+/// Called by glibc's getaddrinfo with the arguments described in nss(5).
+#[no_mangle]
+pub unsafe extern "C" fn _nss_vmc_gethostbyname4_r(
+    name: *const c_char,
+    pat: *mut *mut GaihAddrtuple,
+    buffer: *mut c_char,
+    buflen: size_t,
+    errnop: *mut c_int,
+    h_errnop: *mut c_int,
+    ttlp: *mut i32,
+) -> NssStatus {
+    let mi = match resolve(name) {
+        Resolved::Found(mi) => mi,
+        Resolved::Status(status, h_errno) => {
+            *h_errnop = h_errno;
+            return status;
+        }
+    };
+
+    let addrs = host_addrs(&mi, AF_UNSPEC);
+    if addrs.is_empty() {
+        *h_errnop = HOST_NOT_FOUND;
+        return NSS_STATUS_NOTFOUND;
+    }
+
+    let mut buf = Buffer {
+        ptr: buffer as *mut u8,
+        len: buflen,
+        offset: 0,
+    };
+    let Some(h_name) = buf.push_str(CStr::from_ptr(name)) else {
+        *errnop = ERANGE;
+        *h_errnop = TRY_AGAIN;
+        return NSS_STATUS_TRYAGAIN;
+    };
+
+    let mut prev: *mut GaihAddrtuple = ptr::null_mut();
+    for addr in addrs.iter() {
+        let Some(tuple) = buf.alloc(size_of::<GaihAddrtuple>(), align_of::<GaihAddrtuple>()) else {
+            *errnop = ERANGE;
+            *h_errnop = TRY_AGAIN;
+            return NSS_STATUS_TRYAGAIN;
+        };
+        let tuple = tuple as *mut GaihAddrtuple;
+
+        let mut raw = [0u32; 4];
+        ptr::copy_nonoverlapping(addr.bytes.as_ptr(), raw.as_mut_ptr() as *mut u8, 16);
+        tuple.write(GaihAddrtuple {
+            next: ptr::null_mut(),
+            name: h_name,
+            family: addr.family,
+            addr: raw,
+            scopeid: addr.scope_id,
+        });
+
+        if prev.is_null() {
+            *pat = tuple;
+        } else {
+            (*prev).next = tuple;
+        }
+        prev = tuple;
+    }
+
+    if !ttlp.is_null() {
+        *ttlp = 0;
+    }
+
+    NSS_STATUS_SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipv6_scope_is_resolved_by_interface_name() {
+        assert_eq!(parse_ipv6("fd00::1"), Some(("fd00::1".parse().unwrap(), 0)));
+        assert_eq!(
+            parse_ipv6("fe80::1%lo"),
+            Some(("fe80::1".parse().unwrap(), unsafe {
+                libc::if_nametoindex(c"lo".as_ptr())
+            }))
+        );
+        // an unknown interface leaves the scope unset
+        assert_eq!(
+            parse_ipv6("fe80::1%no-such-if0"),
+            Some(("fe80::1".parse().unwrap(), 0))
+        );
+        assert_eq!(parse_ipv6("192.168.122.10"), None);
+        assert_eq!(parse_ipv6("%lo"), None);
+    }
+
+    #[test]
+    fn buffer_is_aligned_and_bounded() {
+        let mut storage = [0u64; 4];
+        let mut buf = Buffer {
+            ptr: storage.as_mut_ptr() as *mut u8,
+            len: 32,
+            offset: 0,
+        };
+
+        let byte = buf.alloc(1, 1).unwrap();
+        let word = buf.alloc(8, 8).unwrap();
+        assert_eq!(word as usize % 8, 0);
+        assert_eq!(word as usize - byte as usize, 8);
+
+        assert!(buf.alloc(16, 8).is_some());
+        // a failed allocation leaves the buffer as it is
+        assert!(buf.alloc(1, 8).is_none());
+        assert_eq!(buf.offset, 32);
+    }
+
+    #[test]
+    fn hostent_is_refused_by_a_short_buffer() {
+        let addrs = [HostAddr::v4("192.168.122.10".parse().unwrap())];
+        let name = c"alpha";
+        let mut result: hostent = unsafe { std::mem::zeroed() };
+        let mut storage = [0u64; 8];
+
+        let mut short = Buffer {
+            ptr: storage.as_mut_ptr() as *mut u8,
+            len: 16,
+            offset: 0,
+        };
+        assert!(fill_hostent(name, AF_INET, &addrs, &mut result, &mut short).is_none());
+
+        let mut enough = Buffer {
+            ptr: storage.as_mut_ptr() as *mut u8,
+            len: 64,
+            offset: 0,
+        };
+        assert!(fill_hostent(name, AF_INET, &addrs, &mut result, &mut enough).is_some());
+        unsafe {
+            assert_eq!(CStr::from_ptr(result.h_name), name);
+            assert_eq!(
+                std::slice::from_raw_parts(*result.h_addr_list as *const u8, 4),
+                [192, 168, 122, 10]
+            );
+            assert!((*result.h_addr_list.add(1)).is_null());
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use vmc_common::{
+    protocol::{try_server_negotiation, NSRequest, NSResponse, Request, Response},
+    types::{MachineInfo, SerializedDataContainer},
+    SERVER_HOST, SERVER_PORT,
+};
+
+// every process resolving a hostname pays these, so keep them short
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(300);
+const IO_TIMEOUT: Duration = Duration::from_millis(500);
+
+const POSITIVE_TTL: Duration = Duration::from_secs(30);
+const NEGATIVE_TTL: Duration = Duration::from_secs(10);
+// once the server turned out to be unreachable, don't let every lookup wait for the timeout
+const UNREACHABLE_TTL: Duration = Duration::from_secs(5);
+
+pub enum Lookup {
+    Found(MachineInfo),
+    NotFound,
+    Unavailable,
+}
+
+struct Cache {
+    entries: HashMap<String, (Instant, Option<MachineInfo>)>,
+    unreachable_since: Option<Instant>,
+}
+
+static CACHE: Mutex<Option<Cache>> = Mutex::new(None);
+
+fn query_server(hostname: &str) -> std::io::Result<Option<MachineInfo>> {
+    let addr: SocketAddr = format!("{SERVER_HOST}:{SERVER_PORT}")
+        .parse()
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+    let mut server = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+    server.set_read_timeout(Some(IO_TIMEOUT))?;
+    server.set_write_timeout(Some(IO_TIMEOUT))?;
+
+    if !try_server_negotiation(&mut server)? {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::ConnectionRefused,
+            "protocol version mismatched",
+        ));
+    }
+
+    server.write_all(
+        &SerializedDataContainer::from_serializable_data(&Request::NameService(
+            NSRequest::QueryIp(hostname.to_string()),
+        ))
+        .unwrap()
+        .to_one_vec(),
+    )?;
+
+    let sdc = SerializedDataContainer::from_reader(&mut server)?;
+    match sdc.to_serializable_data::<Response>() {
+        Some(Response::NameService(NSResponse::Ip(ret))) => Ok(ret),
+        _ => Err(std::io::Error::from(std::io::ErrorKind::InvalidData)),
+    }
+}
+
+impl Cache {
+    fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            unreachable_since: None,
+        }
+    }
+
+    fn cached(&self, hostname: &str, now: Instant) -> Option<Lookup> {
+        if let Some((cached_at, ret)) = self.entries.get(hostname) {
+            let ttl = if ret.is_some() {
+                POSITIVE_TTL
+            } else {
+                NEGATIVE_TTL
+            };
+            if now.duration_since(*cached_at) < ttl {
+                return Some(match ret {
+                    Some(mi) => Lookup::Found(mi.clone()),
+                    None => Lookup::NotFound,
+                });
+            }
+        }
+
+        self.unreachable_since
+            .filter(|since| now.duration_since(*since) < UNREACHABLE_TTL)
+            .map(|_| Lookup::Unavailable)
+    }
+
+    fn store(
+        &mut self,
+        hostname: &str,
+        queried: std::io::Result<Option<MachineInfo>>,
+        now: Instant,
+    ) -> Lookup {
+        match queried {
+            Ok(ret) => {
+                self.unreachable_since = None;
+                self.entries
+                    .insert(hostname.to_string(), (now, ret.clone()));
+
+                match ret {
+                    Some(mi) => Lookup::Found(mi),
+                    None => Lookup::NotFound,
+                }
+            }
+            Err(_) => {
+                self.unreachable_since = Some(now);
+                Lookup::Unavailable
+            }
+        }
+    }
+}
+
+// the lock is never held across a query, so lookups of other threads aren't held back by it
+fn with_cache<T>(f: impl FnOnce(&mut Cache) -> T) -> T {
+    let mut cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    f(cache.get_or_insert_with(Cache::new))
+}
+
+pub fn lookup(hostname: &str) -> Lookup {
+    if let Some(cached) = with_cache(|cache| cache.cached(hostname, Instant::now())) {
+        return cached;
+    }
+
+    let queried = query_server(hostname);
+    with_cache(|cache| cache.store(hostname, queried, Instant::now()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn machine(hostname: &str) -> MachineInfo {
+        MachineInfo {
+            hostname: hostname.to_string(),
+            ipv4_addr: "192.168.122.10".to_string(),
+            ipv6_addr: None,
+        }
+    }
+
+    fn unreachable() -> std::io::Result<Option<MachineInfo>> {
+        Err(std::io::ErrorKind::ConnectionRefused.into())
+    }
+
+    #[test]
+    fn found_hosts_are_cached_for_positive_ttl() {
+        let mut cache = Cache::new();
+        let t = Instant::now();
+
+        assert!(matches!(
+            cache.store("alpha", Ok(Some(machine("alpha"))), t),
+            Lookup::Found(_)
+        ));
+        assert!(matches!(
+            cache.cached("alpha", t + POSITIVE_TTL - Duration::from_millis(1)),
+            Some(Lookup::Found(mi)) if mi.hostname == "alpha"
+        ));
+        assert!(cache.cached("alpha", t + POSITIVE_TTL).is_none());
+        assert!(cache.cached("beta", t).is_none());
+    }
+
+    #[test]
+    fn unknown_hosts_are_cached_for_negative_ttl() {
+        let mut cache = Cache::new();
+        let t = Instant::now();
+
+        assert!(matches!(
+            cache.store("alpha", Ok(None), t),
+            Lookup::NotFound
+        ));
+        assert!(matches!(
+            cache.cached("alpha", t + NEGATIVE_TTL - Duration::from_millis(1)),
+            Some(Lookup::NotFound)
+        ));
+        assert!(cache.cached("alpha", t + NEGATIVE_TTL).is_none());
+    }
+
+    #[test]
+    fn unreachable_server_is_backed_off() {
+        let mut cache = Cache::new();
+        let t = Instant::now();
+
+        assert!(matches!(
+            cache.store("alpha", unreachable(), t),
+            Lookup::Unavailable
+        ));
+        // every host is given up, not only the one queried
+        assert!(matches!(
+            cache.cached("beta", t + UNREACHABLE_TTL - Duration::from_millis(1)),
+            Some(Lookup::Unavailable)
+        ));
+        assert!(cache.cached("beta", t + UNREACHABLE_TTL).is_none());
+
+        // entries cached before the server was lost are still answered
+        cache.store("gamma", Ok(Some(machine("gamma"))), t);
+        cache.store("alpha", unreachable(), t);
+        assert!(matches!(cache.cached("gamma", t), Some(Lookup::Found(_))));
+
+        cache.store("beta", Ok(None), t + UNREACHABLE_TTL);
+        assert!(cache.unreachable_since.is_none());
+    }
+}
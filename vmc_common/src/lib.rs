@@ -1,4 +1,11 @@
+pub mod mux;
 pub mod protocol;
+pub mod relay;
 pub mod types;
 
 include!("SERVER_CONFIG.rs");
+
+#[cfg(not(target_os = "windows"))]
+pub static PORT_FORWARD_FILE_PATH: &str = "/etc/vmc_port_forward.json";
+#[cfg(target_os = "windows")]
+pub static PORT_FORWARD_FILE_PATH: &str = "C:\\etc\\vmc_port_forward.json";
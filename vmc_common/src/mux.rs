@@ -0,0 +1,442 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::protocol::{ChannelTarget, TunnelFrame};
+use crate::relay::LocalStream;
+use crate::types::SerializedDataContainer;
+
+/*
+ * a sender may have at most CHANNEL_WINDOW bytes of a channel in flight, and the receiver
+ * returns credits as it writes them out, so a slow reader stalls only its own channel and
+ * the frames of the session are always read
+ */
+const CHANNEL_WINDOW: u32 = 256 * 1024;
+const MAX_DATA_LEN: usize = 16 * 1024;
+
+enum Inbound {
+    Data(Vec<u8>),
+    Eof,
+}
+
+// the bytes the peer is ready to receive, None after the channel is closed
+struct Window {
+    credit: Mutex<Option<u32>>,
+    changed: Condvar,
+}
+
+impl Window {
+    fn new() -> Self {
+        Self {
+            credit: Mutex::new(Some(CHANNEL_WINDOW)),
+            changed: Condvar::new(),
+        }
+    }
+
+    // Blocks until some credit is available, and takes up to max bytes of it.
+    fn reserve(&self, max: usize) -> Option<usize> {
+        let mut credit = self.credit.lock().unwrap();
+        loop {
+            match *credit {
+                None => return None,
+                Some(0) => credit = self.changed.wait(credit).unwrap(),
+                Some(available) => {
+                    let n = (available as usize).min(max);
+                    *credit = Some(available - n as u32);
+                    return Some(n);
+                }
+            }
+        }
+    }
+
+    fn give(&self, bytes: u32) {
+        if let Some(credit) = self.credit.lock().unwrap().as_mut() {
+            *credit = credit.saturating_add(bytes);
+        }
+        self.changed.notify_all();
+    }
+
+    fn close(&self) {
+        *self.credit.lock().unwrap() = None;
+        self.changed.notify_all();
+    }
+}
+
+struct ChannelEntry {
+    window: Arc<Window>,
+    // dropped on close, which ends the receiver
+    inbound: Sender<Inbound>,
+    // only while waiting for Opened
+    opened: Option<Sender<Result<(), String>>>,
+}
+
+struct Shared {
+    writer: Mutex<TcpStream>,
+    channels: Mutex<HashMap<u32, ChannelEntry>>,
+    next_id: AtomicU32,
+    peer_addr: Option<SocketAddr>,
+}
+
+// multiplexes streams as channels over a single connection
+#[derive(Clone)]
+pub struct Mux {
+    shared: Arc<Shared>,
+}
+
+impl fmt::Debug for Mux {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mux")
+            .field("peer_addr", &self.shared.peer_addr)
+            .finish()
+    }
+}
+
+impl Mux {
+    pub fn new(stream: &TcpStream) -> io::Result<Self> {
+        Ok(Self {
+            shared: Arc::new(Shared {
+                writer: Mutex::new(stream.try_clone()?),
+                channels: Mutex::new(HashMap::new()),
+                next_id: AtomicU32::new(1),
+                peer_addr: stream.peer_addr().ok(),
+            }),
+        })
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.shared.peer_addr
+    }
+
+    pub fn is_same(&self, other: &Mux) -> bool {
+        Arc::ptr_eq(&self.shared, &other.shared)
+    }
+
+    fn send(&self, frame: &TunnelFrame) -> io::Result<()> {
+        let sdc = SerializedDataContainer::from_serializable_data(frame).unwrap();
+        self.shared
+            .writer
+            .lock()
+            .unwrap()
+            .write_all(&sdc.to_one_vec())
+    }
+
+    // None if the id is already taken, the entry of a live channel is never replaced
+    fn register(
+        &self,
+        id: u32,
+        opened: Option<Sender<Result<(), String>>>,
+    ) -> Option<(Arc<Window>, Receiver<Inbound>)> {
+        let window = Arc::new(Window::new());
+        let (inbound, inbound_recv) = channel();
+
+        match self.shared.channels.lock().unwrap().entry(id) {
+            Entry::Occupied(_) => return None,
+            Entry::Vacant(e) => e.insert(ChannelEntry {
+                window: window.clone(),
+                inbound,
+                opened,
+            }),
+        };
+
+        Some((window, inbound_recv))
+    }
+
+    // Returns false if the channel was already closed.
+    fn unregister(&self, id: u32) -> bool {
+        let Some(entry) = self.shared.channels.lock().unwrap().remove(&id) else {
+            return false;
+        };
+        entry.window.close();
+
+        true
+    }
+
+    // Closes both ends of the channel, the peer is told only once.
+    fn close(&self, id: u32) {
+        if self.unregister(id) {
+            let _ = self.send(&TunnelFrame::Close { channel: id });
+        }
+    }
+
+    // Asks the peer to connect to the target, and waits for the result.
+    pub fn open(&self, target: ChannelTarget, timeout: Duration) -> io::Result<Channel> {
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+        let (opened, opened_recv) = channel();
+        let Some((window, inbound)) = self.register(id, Some(opened)) else {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("channel {id} is already opened by the peer"),
+            ));
+        };
+
+        let result = self
+            .send(&TunnelFrame::Open {
+                channel: id,
+                target,
+            })
+            .and_then(|_| match opened_recv.recv_timeout(timeout) {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(e)) => Err(io::Error::new(io::ErrorKind::ConnectionRefused, e)),
+                Err(RecvTimeoutError::Timeout) => {
+                    // the peer may still open it later
+                    let _ = self.send(&TunnelFrame::Close { channel: id });
+                    Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "opening a channel timed out",
+                    ))
+                }
+                Err(RecvTimeoutError::Disconnected) => Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "the tunnel session is closed",
+                )),
+            });
+        if let Err(e) = result {
+            self.unregister(id);
+            return Err(e);
+        }
+
+        Ok(Channel {
+            id,
+            mux: self.clone(),
+            window,
+            inbound,
+        })
+    }
+
+    // Reads frames until the session is closed, on_open is called for each channel opened by the peer.
+    pub fn serve<F>(&self, mut reader: TcpStream, on_open: F) -> io::Result<()>
+    where
+        F: Fn(Channel, ChannelTarget),
+    {
+        let result = loop {
+            let frame = match SerializedDataContainer::from_reader(&mut reader) {
+                Ok(sdc) => sdc.to_serializable_data::<TunnelFrame>(),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break Ok(()),
+                Err(e) => break Err(e),
+            };
+            let Some(frame) = frame else {
+                break Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "failed to parse a tunnel frame",
+                ));
+            };
+
+            self.dispatch(frame, &on_open);
+        };
+
+        // every channel is closed with the session
+        let _ = reader.shutdown(Shutdown::Both);
+        for (_, entry) in self.shared.channels.lock().unwrap().drain() {
+            entry.window.close();
+        }
+
+        result
+    }
+
+    fn dispatch<F>(&self, frame: TunnelFrame, on_open: &F)
+    where
+        F: Fn(Channel, ChannelTarget),
+    {
+        match frame {
+            TunnelFrame::Open { channel, target } => {
+                let Some((window, inbound)) = self.register(channel, None) else {
+                    let _ = self.send(&TunnelFrame::Opened {
+                        channel,
+                        result: Err(format!("channel {channel} is already opened")),
+                    });
+                    return;
+                };
+                on_open(
+                    Channel {
+                        id: channel,
+                        mux: self.clone(),
+                        window,
+                        inbound,
+                    },
+                    target,
+                );
+            }
+            TunnelFrame::Opened { channel, result } => {
+                let mut channels = self.shared.channels.lock().unwrap();
+                if let Some(opened) = channels.get_mut(&channel).and_then(|e| e.opened.take()) {
+                    let _ = opened.send(result);
+                }
+            }
+            TunnelFrame::Data { channel, data } => {
+                if let Some(entry) = self.shared.channels.lock().unwrap().get(&channel) {
+                    let _ = entry.inbound.send(Inbound::Data(data));
+                }
+            }
+            TunnelFrame::Eof { channel } => {
+                if let Some(entry) = self.shared.channels.lock().unwrap().get(&channel) {
+                    let _ = entry.inbound.send(Inbound::Eof);
+                }
+            }
+            TunnelFrame::Close { channel } => {
+                self.unregister(channel);
+            }
+            TunnelFrame::Credit { channel, bytes } => {
+                if let Some(entry) = self.shared.channels.lock().unwrap().get(&channel) {
+                    entry.window.give(bytes);
+                }
+            }
+        }
+    }
+}
+
+// closed when dropped, unless it is already closed by either end
+pub struct Channel {
+    id: u32,
+    mux: Mux,
+    window: Arc<Window>,
+    inbound: Receiver<Inbound>,
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        self.mux.close(self.id);
+    }
+}
+
+// closes a channel which is moved into a relay
+pub struct ChannelCloser {
+    id: u32,
+    mux: Mux,
+}
+
+impl ChannelCloser {
+    pub fn close(&self) {
+        self.mux.close(self.id);
+    }
+}
+
+// On EOF the peer is half-closed and the other direction is left running.
+// An error tears down the whole channel.
+fn send_local<L, F>(
+    mux: &Mux,
+    id: u32,
+    window: &Window,
+    mut local: L,
+    on_sent: F,
+) -> io::Result<u64>
+where
+    L: LocalStream,
+    F: Fn(&[u8]),
+{
+    let mut buf = vec![0; MAX_DATA_LEN];
+    let mut sent = 0;
+
+    loop {
+        // closed by the peer or with the session
+        let Some(reserved) = window.reserve(MAX_DATA_LEN) else {
+            let _ = local.shutdown(Shutdown::Both);
+            return Ok(sent);
+        };
+
+        let n = match local.read(&mut buf[..reserved]) {
+            Ok(n) => n,
+            Err(e) => {
+                mux.close(id);
+                return Err(e);
+            }
+        };
+        window.give((reserved - n) as u32);
+
+        if n == 0 {
+            mux.send(&TunnelFrame::Eof { channel: id })?;
+            return Ok(sent);
+        }
+
+        mux.send(&TunnelFrame::Data {
+            channel: id,
+            data: buf[..n].to_vec(),
+        })?;
+        on_sent(&buf[..n]);
+        sent += n as u64;
+    }
+}
+
+impl Channel {
+    pub fn accept(&self) -> io::Result<()> {
+        self.mux.send(&TunnelFrame::Opened {
+            channel: self.id,
+            result: Ok(()),
+        })
+    }
+
+    pub fn closer(&self) -> ChannelCloser {
+        ChannelCloser {
+            id: self.id,
+            mux: self.mux.clone(),
+        }
+    }
+
+    pub fn reject(self, reason: String) {
+        let _ = self.mux.send(&TunnelFrame::Opened {
+            channel: self.id,
+            result: Err(reason),
+        });
+        self.mux.unregister(self.id);
+    }
+
+    fn receive_into<L, F>(&self, mut local: L, on_received: F) -> io::Result<u64>
+    where
+        L: LocalStream,
+        F: Fn(&[u8]),
+    {
+        let mut received = 0;
+
+        loop {
+            match self.inbound.recv() {
+                Ok(Inbound::Data(data)) => {
+                    if let Err(e) = local.write_all(&data) {
+                        self.mux.close(self.id);
+                        let _ = local.shutdown(Shutdown::Both);
+                        return Err(e);
+                    }
+                    let _ = self.mux.send(&TunnelFrame::Credit {
+                        channel: self.id,
+                        bytes: data.len() as u32,
+                    });
+                    on_received(&data);
+                    received += data.len() as u64;
+                }
+                Ok(Inbound::Eof) => {
+                    let _ = local.shutdown(Shutdown::Write);
+                    return Ok(received);
+                }
+                // closed by the peer or with the session
+                Err(_) => {
+                    let _ = local.shutdown(Shutdown::Both);
+                    return Ok(received);
+                }
+            }
+        }
+    }
+
+    // Copies both directions until both of them are closed, returns (bytes sent, bytes received).
+    // The callbacks are given the data of each direction as it is relayed.
+    pub fn relay<L, S, R>(self, local: L, on_sent: S, on_received: R) -> io::Result<(u64, u64)>
+    where
+        L: LocalStream,
+        S: Fn(&[u8]) + Send + 'static,
+        R: Fn(&[u8]),
+    {
+        let local_reader = local.try_clone()?;
+        let (mux, window, id) = (self.mux.clone(), self.window.clone(), self.id);
+        let sender = thread::spawn(move || send_local(&mux, id, &window, local_reader, on_sent));
+
+        let received = self.receive_into(local, on_received);
+        let sent = sender.join().expect("channel sender panicked");
+        self.mux.unregister(self.id);
+
+        Ok((sent?, received?))
+    }
+}
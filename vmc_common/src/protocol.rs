@@ -1,20 +1,44 @@
-use std::{io::Write, net::TcpStream};
+use std::{fmt, io::Write, net::TcpStream};
 
-use crate::types::{MachineInfo, PortforwardList, SerializedDataContainer};
+use crate::types::{
+    CaptureSpec, MachineInfo, MachineStats, PortforwardConnection, PortforwardEntry,
+    PortforwardList, PortforwardProtocol, PortforwardSpec, PortforwardStatus,
+    SerializedDataContainer,
+};
 use ring::digest::{Context, SHA256};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum NSRequest {
-    Heartbeat(MachineInfo, PortforwardList),
+    Heartbeat(MachineInfo, PortforwardList, Option<MachineStats>),
     QueryIp(String),
     GetMachineList,
+    GetStats(String),
+    GetForwardList,
+    // take effect immediately and are kept across heartbeats until the machine expires
+    AddForward(String, PortforwardSpec),
+    RemoveForward(String, PortforwardProtocol, u16),
+    GetConnections,
+    KillConnection(u64),
+    StartCapture(CaptureSpec),
+    StopCapture(PortforwardProtocol, u16),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum NSResponse {
     Ip(Option<MachineInfo>),
     MachineList(Vec<MachineInfo>),
+    Stats(Option<Vec<MachineStats>>),
+    HeartbeatAck(PortforwardStatus),
+    ForwardList(Vec<PortforwardEntry>),
+    // None if the machine has not sent a heartbeat yet
+    ForwardUpdated(Option<PortforwardStatus>),
+    Connections(Vec<PortforwardConnection>),
+    // false if no such a connection is alive
+    ConnectionKilled(bool),
+    CaptureStarted(Result<(), String>),
+    // the bytes written, None if the rule was not captured
+    CaptureStopped(Option<u64>),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -45,6 +69,78 @@ pub enum NTFRequest {
     Notification(Option<String>, String),
 }
 
+/*
+ *
+ * guest client ---> guest listener ---[vmc connection]---> vmc server ---> host service
+ *
+ * after a successful Connect the vmc connection carries raw bytes of the tunneled connection
+ */
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TunnelRequest {
+    Connect(String),
+    // turns the connection into the tunnel session of the machine, which carries TunnelFrames
+    Attach(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TunnelResponse {
+    Connect(Result<(), String>),
+}
+
+// what the guest connects a channel to
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Hash)]
+pub enum ChannelTarget {
+    Port(u16),
+    // a unix socket of the guest
+    Path(String),
+}
+
+impl fmt::Display for ChannelTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelTarget::Port(port) => write!(f, "{port}"),
+            ChannelTarget::Path(path) => write!(f, "unix:{path}"),
+        }
+    }
+}
+
+/*
+ *
+ * client ---> server ==[ channel 1 ]==> guest ---> guest port
+ * client ---> server ==[ channel 2 ]==> guest ---> guest unix socket
+ *                     (tunnel session)
+ *
+ * channels are opened by the server, see vmc_common::mux for the flow control
+ */
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TunnelFrame {
+    Open {
+        channel: u32,
+        target: ChannelTarget,
+    },
+    Opened {
+        channel: u32,
+        result: Result<(), String>,
+    },
+    Data {
+        channel: u32,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    // no more data from the sender, the other direction is left running
+    Eof {
+        channel: u32,
+    },
+    Close {
+        channel: u32,
+    },
+    // the receiver has written out the bytes, so the sender may send as many more
+    Credit {
+        channel: u32,
+        bytes: u32,
+    },
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
     Negotiation(Vec<u8>), // 256(SHA256 bits) / 8 = 32 byte
@@ -52,6 +148,7 @@ pub enum Request {
     ClipBoard(CBRequest),
     Execute(ExecRequest),
     Notification(NTFRequest),
+    Tunnel(TunnelRequest),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -60,33 +157,38 @@ pub enum Response {
     NameService(NSResponse),
     ClipBoard(CBResponse),
     Execute(ExecResponse),
+    Tunnel(TunnelResponse),
 }
 
 const PROTOCOL_SRC: &str = include_str!("protocol.rs");
+const TYPES_SRC: &str = include_str!("types.rs");
 
 pub fn calc_protocol_digest() -> Vec<u8> {
     let mut ctx = Context::new(&SHA256);
 
     ctx.update(PROTOCOL_SRC.as_bytes());
+    ctx.update(TYPES_SRC.as_bytes());
 
     ctx.finish().as_ref().to_vec()
 }
 
-pub fn server_negotiation(server: &mut TcpStream) -> bool {
+pub fn try_server_negotiation(server: &mut TcpStream) -> std::io::Result<bool> {
     let client_digest = calc_protocol_digest();
 
-    server
-        .write_all(
-            &SerializedDataContainer::from_serializable_data(&Request::Negotiation(client_digest))
-                .unwrap()
-                .to_one_vec(),
-        )
-        .unwrap();
-
-    let sdc = SerializedDataContainer::from_reader(server).unwrap();
-    if let Response::NegotiationResult(result) = sdc.to_serializable_data::<Response>().unwrap() {
-        result
+    server.write_all(
+        &SerializedDataContainer::from_serializable_data(&Request::Negotiation(client_digest))
+            .unwrap()
+            .to_one_vec(),
+    )?;
+
+    let sdc = SerializedDataContainer::from_reader(server)?;
+    if let Some(Response::NegotiationResult(result)) = sdc.to_serializable_data::<Response>() {
+        Ok(result)
     } else {
-        false
+        Ok(false)
     }
 }
+
+pub fn server_negotiation(server: &mut TcpStream) -> bool {
+    try_server_negotiation(server).unwrap()
+}
@@ -0,0 +1,138 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::thread;
+use std::time::Duration;
+
+// a blocking stream which can be read and written by separate threads
+pub trait LocalStream: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl LocalStream for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
+#[cfg(unix)]
+impl LocalStream for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+}
+
+// either of the streams, when a peer may be a tcp or a unix socket
+#[derive(Debug)]
+pub enum AnyStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl AnyStream {
+    // "unix:/path/to/socket" or "host:port"
+    pub fn connect(addr: &str, timeout: Duration) -> io::Result<Self> {
+        #[cfg(unix)]
+        if let Some(path) = addr.strip_prefix("unix:") {
+            return UnixStream::connect(path).map(AnyStream::Unix);
+        }
+
+        // every resolved address is tried in order, so an unreachable family falls back to the other
+        let mut last_err = io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{addr} is resolved to no address"),
+        );
+        for addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => return Ok(AnyStream::Tcp(stream)),
+                Err(e) => last_err = e,
+            }
+        }
+
+        Err(last_err)
+    }
+}
+
+impl Read for AnyStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            AnyStream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            AnyStream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for AnyStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            AnyStream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            AnyStream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            AnyStream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            AnyStream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl LocalStream for AnyStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            AnyStream::Tcp(stream) => stream.try_clone().map(AnyStream::Tcp),
+            #[cfg(unix)]
+            AnyStream::Unix(stream) => stream.try_clone().map(AnyStream::Unix),
+        }
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            AnyStream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            AnyStream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+// On EOF the peer is half-closed and the other direction is left running.
+fn pump<R: LocalStream, W: LocalStream>(mut reader: R, mut writer: W) -> io::Result<u64> {
+    let copied = io::copy(&mut reader, &mut writer);
+    match copied {
+        Ok(_) => {
+            let _ = writer.shutdown(Shutdown::Write);
+        }
+        // an error tears down the whole connection
+        Err(_) => {
+            let _ = reader.shutdown(Shutdown::Both);
+            let _ = writer.shutdown(Shutdown::Both);
+        }
+    }
+
+    copied
+}
+
+// Copies both directions until both of them are closed, returns (bytes a -> b, bytes b -> a).
+pub fn relay<A: LocalStream, B: LocalStream>(a: A, b: B) -> io::Result<(u64, u64)> {
+    let (a_reader, b_writer) = (a.try_clone()?, b.try_clone()?);
+    let forward = thread::spawn(move || pump(a_reader, b_writer));
+    let backward = pump(b, a);
+
+    let forward = forward.join().expect("relay thread panicked");
+    Ok((forward?, backward?))
+}
@@ -1,7 +1,7 @@
 use rmp_serde::{self, Serializer};
 use serde::{Deserialize, Serialize};
 use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::net::{IpAddr, SocketAddr, TcpStream};
 use std::thread;
 use std::{convert::TryInto, mem::size_of};
 
@@ -57,9 +57,7 @@ impl SerializedDataContainer {
                     .try_into()
                     .expect("Failed to parse size of the data container"),
             );
-            let data = v[size_of::<usize>()..size_of::<usize>() + size]
-                .try_into()
-                .expect("Failed to get data of the data container");
+            let data = v[size_of::<usize>()..size_of::<usize>() + size].to_vec();
 
             Some(Self { size, data })
         } else {
@@ -101,7 +99,7 @@ impl AutoReConnectTcpStream {
             &host_info,
             retry_interval,
             false,
-            reconnect_callback.as_ref(),
+            reconnect_callback.as_deref(),
         );
         Self {
             host_info,
@@ -120,7 +118,7 @@ impl AutoReConnectTcpStream {
         host_info: &str,
         retry_interval: std::time::Duration,
         verbose: bool,
-        reconnect_callback: Option<&Box<dyn Fn(TcpStream)>>,
+        reconnect_callback: Option<&dyn Fn(TcpStream)>,
     ) -> TcpStream {
         loop {
             if verbose {
@@ -148,7 +146,7 @@ impl AutoReConnectTcpStream {
                     &self.host_info,
                     self.retry_interval,
                     self.verbose,
-                    self.reconnect_callback.as_ref(),
+                    self.reconnect_callback.as_deref(),
                 )
             } else {
                 break;
@@ -158,29 +156,154 @@ impl AutoReConnectTcpStream {
     }
 }
 
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Hash, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PortforwardProtocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+// the family tried first, the other one is the fallback
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum IpPreference {
+    #[default]
+    V4,
+    V6,
+}
+
+// the header of HAProxy PROXY protocol sent to the guest before the data of a connection
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+fn default_port_count() -> u16 {
+    1
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PoolBalance {
+    #[default]
+    RoundRobin,
+    LeastConnections,
+}
+
+fn default_health_check_secs() -> u64 {
+    10
+}
+
+// guests declaring the same host port with a pool share it, the first of them gives the settings
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct PoolSpec {
+    #[serde(default)]
+    pub balance: PoolBalance,
+    // a client is sent to the member it was sent to before, by its ip address
+    #[serde(default)]
+    pub sticky: bool,
+    // members which refuse a connect are skipped until they accept one again, 0 disables it
+    #[serde(default = "default_health_check_secs")]
+    pub health_check_secs: u64,
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub struct PortforwardSpec {
+    // 0 lets the server allocate a free host port
     pub host_port: u16,
     pub guest_port: u16,
+    // forwards the ranges host_port..host_port+port_count to guest_port..guest_port+port_count
+    #[serde(default = "default_port_count")]
+    pub port_count: u16,
+    #[serde(default)]
+    pub protocol: PortforwardProtocol,
+    // tcp only, applied to both of the client and the guest side sockets
+    #[serde(default)]
+    pub nodelay: bool,
+    #[serde(default)]
+    pub keepalive_secs: Option<u64>,
+    #[serde(default)]
+    pub ip_preference: IpPreference,
+    // listens on every address if None
+    #[serde(default)]
+    pub bind_addr: Option<IpAddr>,
+    // source CIDRs like "192.168.2.0/24", deny wins and an empty allow list allows everyone
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+    // tcp only, through the tunnel session of the guest instead of connecting to its address
+    #[serde(default)]
+    pub tunnel: bool,
+    // tcp only and not through the tunnel, the guest service has to expect the header
+    #[serde(default)]
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+    // tcp only, listens on this unix socket of the host instead of host_port, which still names the rule
+    #[serde(default)]
+    pub host_path: Option<String>,
+    // tcp only, connects to this unix socket of the guest instead of guest_port, always through the tunnel
+    #[serde(default)]
+    pub guest_path: Option<String>,
+    // tcp only and not through the tunnel
+    #[serde(default)]
+    pub pool: Option<PoolSpec>,
 }
 
 impl PortforwardSpec {
-    pub fn new(host_port: u16, guest_port: u16) -> Self {
+    pub fn new(host_port: u16, guest_port: u16, protocol: PortforwardProtocol) -> Self {
         Self {
             host_port,
             guest_port,
+            port_count: 1,
+            protocol,
+            nodelay: false,
+            keepalive_secs: None,
+            ip_preference: IpPreference::default(),
+            bind_addr: None,
+            allow: vec![],
+            deny: vec![],
+            tunnel: false,
+            proxy_protocol: None,
+            host_path: None,
+            guest_path: None,
+            pool: None,
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// a listener on the guest whose connections are tunneled to host_addr through the server
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Hash)]
+pub struct ReverseForwardSpec {
+    #[serde(default)]
+    pub guest_port: u16,
+    // resolved and connected on the host, e.g. "127.0.0.1:5432" or "unix:/var/run/docker.sock",
+    // which has to be listed in reverse_forward_targets of the server config
+    pub host_addr: String,
+    // 127.0.0.1 if None, the services of the host shouldn't be exposed to the others
+    #[serde(default)]
+    pub bind_addr: Option<IpAddr>,
+    // listens on this unix socket of the guest instead of guest_port
+    #[serde(default)]
+    pub guest_path: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct PortforwardList {
     pub forwards: Vec<PortforwardSpec>,
+    // served by the guest itself, the server only sees them in heartbeats
+    #[serde(default)]
+    pub reverse_forwards: Vec<ReverseForwardSpec>,
 }
 
 impl PortforwardList {
     pub fn new(forwards: Vec<PortforwardSpec>) -> Self {
-        Self { forwards }
+        Self {
+            forwards,
+            reverse_forwards: vec![],
+        }
     }
 
     pub fn has_elem(&self, forward: &PortforwardSpec) -> bool {
@@ -209,4 +332,136 @@ impl PortforwardList {
 
         ret
     }
+
+    // Drops the forwards to the guest port, and splits ranges which cover it.
+    pub fn remove_guest_port(&mut self, protocol: PortforwardProtocol, guest_port: u16) -> bool {
+        let mut removed = false;
+        let mut forwards = vec![];
+
+        for forward in self.forwards.drain(..) {
+            let count = forward.port_count.max(1);
+            let covers = forward.protocol == protocol
+                && (forward.guest_port..forward.guest_port.saturating_add(count))
+                    .contains(&guest_port);
+            if !covers {
+                forwards.push(forward);
+                continue;
+            }
+            removed = true;
+
+            let offset = guest_port - forward.guest_port;
+            if offset > 0 {
+                forwards.push(PortforwardSpec {
+                    port_count: offset,
+                    ..forward.clone()
+                });
+            }
+            if offset + 1 < count {
+                forwards.push(PortforwardSpec {
+                    // a host port of 0 stays 0, the rest is allocated again
+                    host_port: if forward.host_port == 0 {
+                        0
+                    } else {
+                        forward.host_port.saturating_add(offset + 1)
+                    },
+                    guest_port: guest_port + 1,
+                    port_count: count - offset - 1,
+                    ..forward
+                });
+            }
+        }
+        self.forwards = forwards;
+
+        removed
+    }
+}
+
+// a declared forward which is not active, owner is None when the host port is held by nobody
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PortforwardConflict {
+    pub forward: PortforwardSpec,
+    pub owner: Option<String>,
+}
+
+// the result of a heartbeat for the forwards of the machine
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct PortforwardStatus {
+    pub conflicts: Vec<PortforwardConflict>,
+    // forwards declared with host_port 0, with the host port allocated by the server
+    pub allocated: Vec<PortforwardSpec>,
+    // forwards declared with host_port 0 for which no free host port was left
+    pub unallocated: Vec<PortforwardSpec>,
+}
+
+// counted since the rule was created, kept across changes of its destination
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct PortforwardCounters {
+    pub connections: u64,
+    pub connect_failures: u64,
+    pub connect_timeouts: u64,
+    pub accept_errors: u64,
+    pub rejected: u64,
+    // connections torn down by an error in the middle of a transfer
+    pub transfer_errors: u64,
+    pub bytes_to_guest: u64,
+    pub bytes_from_guest: u64,
+}
+
+// a live TCP connection or UDP session of a forward
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PortforwardConnection {
+    pub id: u64,
+    // the owner of the host port, None if it was dropped after the connection was made
+    pub hostname: Option<String>,
+    pub protocol: PortforwardProtocol,
+    pub host_port: u16,
+    // None for a client of a unix socket
+    pub client_addr: Option<SocketAddr>,
+    // None while connecting to the guest, or for a unix socket of the guest
+    pub guest_addr: Option<SocketAddr>,
+    pub duration_ms: u64,
+    pub bytes_to_guest: u64,
+    pub bytes_from_guest: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureFormat {
+    // packets with synthesized IP and TCP/UDP headers, readable by wireshark
+    Pcapng,
+    // a timestamped hex dump of both directions
+    Transcript,
+}
+
+// captures the traffic of a rule into a file on the server until stopped or max_bytes are written
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct CaptureSpec {
+    pub protocol: PortforwardProtocol,
+    pub host_port: u16,
+    pub format: CaptureFormat,
+    // a file name in capture_dir of the server, which must not exist yet
+    pub path: String,
+    pub max_bytes: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PortforwardEntry {
+    pub hostname: String,
+    pub forward: PortforwardSpec,
+    pub active: bool,
+    // only for active entries
+    pub counters: Option<PortforwardCounters>,
+}
+
+// counters are cumulative since boot, so rates have to be derived from consecutive samples
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct MachineStats {
+    pub timestamp: u64,
+    pub load_avg: [f64; 3],
+    pub mem_total_kb: u64,
+    pub mem_available_kb: u64,
+    pub disk_read_bytes: u64,
+    pub disk_write_bytes: u64,
+    pub net_rx_bytes: u64,
+    pub net_tx_bytes: u64,
 }
@@ -5,9 +5,15 @@ use std::{env, process::Command, str};
 use strum::{EnumIter, IntoEnumIterator};
 use vmc_common::protocol::server_negotiation;
 use vmc_common::{
-    protocol::{CBRequest, CBResponse, ExecRequest, ExecResponse, NTFRequest, Request, Response},
-    types::{AutoReConnectTcpStream, SerializedDataContainer},
-    SERVER_HOST, SERVER_PORT,
+    protocol::{
+        CBRequest, CBResponse, ExecRequest, ExecResponse, NSRequest, NSResponse, NTFRequest,
+        Request, Response,
+    },
+    types::{
+        AutoReConnectTcpStream, PortforwardList, PortforwardProtocol, PortforwardSpec,
+        PortforwardStatus, SerializedDataContainer,
+    },
+    PORT_FORWARD_FILE_PATH, SERVER_HOST, SERVER_PORT,
 };
 
 const MOUNT_LIST_FILE: &str = ".mount_list.json";
@@ -123,6 +129,159 @@ fn is_sub_of_cifs_dir(dir: &str) -> bool {
     false
 }
 
+fn load_port_forward_list() -> PortforwardList {
+    match std::fs::read_to_string(PORT_FORWARD_FILE_PATH) {
+        Ok(s) => serde_json::from_str::<PortforwardList>(&s).expect("failed to parse forward list"),
+        Err(_) => PortforwardList::new(vec![]),
+    }
+}
+
+fn save_port_forward_list(forward_list: &PortforwardList) -> std::io::Result<()> {
+    std::fs::write(
+        PORT_FORWARD_FILE_PATH,
+        serde_json::to_string_pretty(forward_list).unwrap() + "\n",
+    )
+}
+
+fn print_forward_status(status: &PortforwardStatus) {
+    for forward in status.allocated.iter() {
+        println!(
+            "guest port {} ({:?}) is reachable at {SERVER_HOST}:{}",
+            forward.guest_port, forward.protocol, forward.host_port
+        );
+    }
+    for forward in status.unallocated.iter() {
+        eprintln!(
+            "[Warning] no free host port for guest port {} ({:?})",
+            forward.guest_port, forward.protocol
+        );
+    }
+    for conflict in status.conflicts.iter() {
+        eprintln!(
+            "[Warning] host port {} ({:?}) is not forwarded to guest port {}: {}",
+            conflict.forward.host_port,
+            conflict.forward.protocol,
+            conflict.forward.guest_port,
+            match &conflict.owner {
+                Some(owner) => format!("owned by {owner}"),
+                None => "declared by several machines".to_string(),
+            }
+        );
+    }
+}
+
+/*
+ * forward add [--udp] [--persist] <host port> <guest port> [port count]
+ * forward remove [--udp] [--persist] <guest port>
+ * forward list
+ *
+ * changes take effect on the server immediately, and --persist also writes them into
+ * PORT_FORWARD_FILE_PATH so that they survive a restart of the server or the machine.
+ */
+const FORWARD_USAGE: &str = "usage: forward add [--udp] [--persist] <host port> <guest port> [port count] | remove [--udp] [--persist] <guest port> | list";
+
+fn exit_with_forward_usage(message: &str) -> ! {
+    eprintln!("{message}");
+    eprintln!("{FORWARD_USAGE}");
+    std::process::exit(1);
+}
+
+fn parse_port(s: &str) -> u16 {
+    s.parse()
+        .unwrap_or_else(|_| exit_with_forward_usage(&format!("invalid port number: {s}")))
+}
+
+fn forward_command(server: &mut AutoReConnectTcpStream, args: &[String]) -> std::io::Result<()> {
+    let hostname = hostname::get()
+        .ok()
+        .and_then(|os_str| os_str.into_string().ok())
+        .expect("failed to get hostname");
+    let persist = args.iter().any(|arg| arg == "--persist");
+    let protocol = if args.iter().any(|arg| arg == "--udp") {
+        PortforwardProtocol::Udp
+    } else {
+        PortforwardProtocol::Tcp
+    };
+    let params: Vec<_> = args[1..]
+        .iter()
+        .filter(|arg| !arg.starts_with("--"))
+        .collect();
+
+    let req = match (args[0].as_str(), params.len()) {
+        ("add", 2 | 3) => {
+            let mut forward =
+                PortforwardSpec::new(parse_port(params[0]), parse_port(params[1]), protocol);
+            if let Some(port_count) = params.get(2) {
+                forward.port_count = parse_port(port_count);
+            }
+            if persist {
+                let mut forward_list = load_port_forward_list();
+                if !forward_list.has_elem(&forward) {
+                    forward_list.append_elem(forward.clone());
+                }
+                save_port_forward_list(&forward_list)?;
+            }
+
+            NSRequest::AddForward(hostname.clone(), forward)
+        }
+        ("remove", 1) => {
+            let guest_port = parse_port(params[0]);
+            if persist {
+                let mut forward_list = load_port_forward_list();
+                forward_list.remove_guest_port(protocol, guest_port);
+                save_port_forward_list(&forward_list)?;
+            }
+
+            NSRequest::RemoveForward(hostname.clone(), protocol, guest_port)
+        }
+        ("list", 0) => NSRequest::GetForwardList,
+        _ => exit_with_forward_usage(&format!("invalid arguments: {}", args.join(" "))),
+    };
+
+    server
+        .write_all(
+            &SerializedDataContainer::from_serializable_data(&Request::NameService(req))
+                .unwrap()
+                .to_one_vec(),
+        )
+        .unwrap();
+
+    let sdc = SerializedDataContainer::from_reader(&mut server.stream)?;
+    match sdc.to_serializable_data::<Response>().unwrap() {
+        Response::NameService(NSResponse::ForwardUpdated(Some(status))) => {
+            print_forward_status(&status);
+        }
+        Response::NameService(NSResponse::ForwardUpdated(None)) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{hostname} is not registered in server, is vmc_ip_reporter running?"),
+            ));
+        }
+        Response::NameService(NSResponse::ForwardList(entries)) => {
+            for entry in entries.iter().filter(|e| e.hostname == hostname) {
+                let forward = &entry.forward;
+                print!(
+                    "{}/{:?} -> {}",
+                    forward.host_port, forward.protocol, forward.guest_port
+                );
+                if entry.active {
+                    println!();
+                } else {
+                    println!(" (conflict, inactive)");
+                }
+            }
+        }
+        res => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unexpected response from server: {res:?}"),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
 fn main() -> std::io::Result<()> {
     let args: Vec<String> = std::env::args().collect();
 
@@ -149,6 +308,7 @@ fn main() -> std::io::Result<()> {
         ToWinPath,
         GetEnvVar,
         Notify,
+        Forward,
     }
 
     let mode = if args.len() < 2 {
@@ -190,6 +350,15 @@ fn main() -> std::io::Result<()> {
                 }
                 Mode::Notify
             }
+            "forward" => {
+                if args.len() < 3 {
+                    exit_with_forward_usage(&format!(
+                        "{} command requires a sub-command: add, remove or list",
+                        args[1]
+                    ));
+                }
+                Mode::Forward
+            }
             _ => {
                 eprintln!("Unkown command was given: {}", args[1]);
 
@@ -332,6 +501,7 @@ fn main() -> std::io::Result<()> {
 
             false
         }
+        Mode::Forward => return forward_command(&mut server, &args[2..]),
     };
 
     if !recv_required {
@@ -1,3 +1,7 @@
+mod reverse_forward;
+mod stats;
+mod tunnel;
+
 use network_interface::{Addr, NetworkInterface, NetworkInterfaceConfig};
 use std::fs::File;
 use std::io::Read;
@@ -7,15 +11,14 @@ use std::{str, thread, time};
 use vmc_common::protocol::server_negotiation;
 use vmc_common::types::PortforwardList;
 use vmc_common::{
-    protocol::{NSRequest, Request},
+    protocol::{NSRequest, NSResponse, Request, Response},
     types::{AutoReConnectTcpStream, MachineInfo, SerializedDataContainer},
-    ETH_NAME, IPV4_PREFIX_LIST, IPV6_PREFIX, SERVER_HOST, SERVER_PORT,
+    ETH_NAME, IPV4_PREFIX_LIST, IPV6_PREFIX, PORT_FORWARD_FILE_PATH, SERVER_HOST, SERVER_PORT,
 };
 
-#[cfg(not(target_os = "windows"))]
-static PORT_FORWARD_FILE_PATH: &str = "/etc/vmc_port_forward.json";
-#[cfg(target_os = "windows")]
-static PORT_FORWARD_FILE_PATH: &str = "C:\\etc\\vmc_port_forward.json";
+use crate::reverse_forward::ReverseForwarder;
+use crate::stats::collect_machine_stats;
+use crate::tunnel::start_tunnel_session;
 
 fn get_ipv4addr(eth_name: &str) -> Option<String> {
     let network_interfaces = NetworkInterface::show().unwrap();
@@ -78,6 +81,9 @@ fn get_port_forward_list() -> PortforwardList {
 }
 
 fn main() -> std::io::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let report_stats = args.iter().skip(1).any(|arg| arg == "--stats");
+
     let sleep_sec = time::Duration::from_secs(30);
     let mut server = AutoReConnectTcpStream::new(
         format!("{SERVER_HOST}:{SERVER_PORT}"),
@@ -97,19 +103,31 @@ fn main() -> std::io::Result<()> {
         ));
     }
 
+    start_tunnel_session(
+        get_hostname().expect("failed to get hostname"),
+        get_ipv4addr(ETH_NAME).and_then(|ip| ip.parse().ok()),
+    );
+
+    let mut last_allocated = vec![];
+    let mut reverse_forwarder = ReverseForwarder::default();
+
     loop {
         let (hostname, ipv4_addr, ipv6_addr) = (
             get_hostname().expect("failed to get hostname"),
             get_ipv4addr(ETH_NAME).expect("failed to get ipv4 addr"),
             get_ipv6addr(ETH_NAME),
         );
+        let forward_list = get_port_forward_list();
+        reverse_forwarder.update(&forward_list.reverse_forwards);
+
         let m = Request::NameService(NSRequest::Heartbeat(
             MachineInfo {
                 hostname,
                 ipv4_addr,
                 ipv6_addr,
             },
-            get_port_forward_list(),
+            forward_list,
+            report_stats.then(collect_machine_stats),
         ));
         let sdc = SerializedDataContainer::from_serializable_data(&m).unwrap();
 
@@ -117,6 +135,42 @@ fn main() -> std::io::Result<()> {
 
         server.write_all(&sdc.to_one_vec()).unwrap();
 
+        // a failed read is recovered by the reconnect on the next heartbeat
+        if let Some(Response::NameService(NSResponse::HeartbeatAck(status))) =
+            SerializedDataContainer::from_reader(&mut server.stream)
+                .ok()
+                .and_then(|sdc| sdc.to_serializable_data::<Response>())
+        {
+            // allocations are sticky, so they are printed only when changed
+            if status.allocated != last_allocated {
+                for forward in status.allocated.iter() {
+                    println!(
+                        "guest port {} ({:?}) is reachable at {SERVER_HOST}:{}",
+                        forward.guest_port, forward.protocol, forward.host_port
+                    );
+                }
+                last_allocated = status.allocated;
+            }
+            for forward in status.unallocated {
+                eprintln!(
+                    "[Warning] no free host port for guest port {} ({:?})",
+                    forward.guest_port, forward.protocol
+                );
+            }
+            for conflict in status.conflicts {
+                eprintln!(
+                    "[Warning] host port {} ({:?}) is not forwarded to guest port {}: {}",
+                    conflict.forward.host_port,
+                    conflict.forward.protocol,
+                    conflict.forward.guest_port,
+                    match conflict.owner {
+                        Some(owner) => format!("owned by {owner}"),
+                        None => "declared by several machines".to_string(),
+                    }
+                );
+            }
+        }
+
         thread::sleep(sleep_sec);
     }
 }
@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use vmc_common::protocol::{Request, Response, TunnelRequest, TunnelResponse};
+use vmc_common::relay::{relay, LocalStream};
+use vmc_common::types::{ReverseForwardSpec, SerializedDataContainer};
+
+use crate::tunnel::connect_server;
+
+// e.g. EMFILE, retrying immediately would just spin
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/*
+ *
+ * guest client ---> reverse listener (guest_port or guest_path) ---[new vmc connection]---> server ---> host_addr
+ *
+ * each tunneled connection has its own vmc connection, which carries raw bytes after Connect
+ */
+fn open_tunnel(host_addr: &str) -> io::Result<TcpStream> {
+    let mut server = connect_server()?;
+    server.write_all(
+        &SerializedDataContainer::from_serializable_data(&Request::Tunnel(TunnelRequest::Connect(
+            host_addr.to_string(),
+        )))
+        .unwrap()
+        .to_one_vec(),
+    )?;
+
+    match SerializedDataContainer::from_reader(&mut server)?.to_serializable_data::<Response>() {
+        Some(Response::Tunnel(TunnelResponse::Connect(Ok(())))) => Ok(server),
+        Some(Response::Tunnel(TunnelResponse::Connect(Err(e)))) => {
+            Err(io::Error::new(io::ErrorKind::ConnectionRefused, e))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected response to the tunnel request",
+        )),
+    }
+}
+
+// "port 5432" or "/run/host.sock", for messages
+fn guest_endpoint(spec: &ReverseForwardSpec) -> String {
+    match &spec.guest_path {
+        Some(path) => path.clone(),
+        None => format!("port {}", spec.guest_port),
+    }
+}
+
+fn accept_clients<S, I>(incoming: I, endpoint: String, host_addr: String, stopped: Arc<AtomicBool>)
+where
+    S: LocalStream,
+    I: Iterator<Item = io::Result<S>>,
+{
+    for client in incoming {
+        if stopped.load(Ordering::SeqCst) {
+            break;
+        }
+        let client = match client {
+            Ok(client) => client,
+            Err(e) => {
+                eprintln!("[Warning] failed to accept on {endpoint}: {e}");
+                thread::sleep(ACCEPT_ERROR_BACKOFF);
+                continue;
+            }
+        };
+
+        let host_addr = host_addr.clone();
+        thread::spawn(move || match open_tunnel(&host_addr) {
+            Ok(tunnel) => {
+                let _ = relay(client, tunnel);
+            }
+            Err(e) => eprintln!("[Warning] failed to open a tunnel to {host_addr}: {e}"),
+        });
+    }
+}
+
+enum ListenAddr {
+    Tcp(SocketAddr),
+    // the file may be replaced by another listener by the time this one is dropped
+    #[cfg(unix)]
+    Unix {
+        path: PathBuf,
+        dev: u64,
+        ino: u64,
+    },
+}
+
+struct ReverseListener {
+    addr: ListenAddr,
+    stopped: Arc<AtomicBool>,
+}
+
+impl ReverseListener {
+    #[cfg(unix)]
+    fn bind_path(path: &str) -> io::Result<(UnixListener, ListenAddr)> {
+        use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+        // a stale socket left by a previous run is replaced, a live one or anything else is left as it is
+        if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{path} is already listened on"),
+                ));
+            }
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        let meta = std::fs::symlink_metadata(path)?;
+
+        Ok((
+            listener,
+            ListenAddr::Unix {
+                path: path.into(),
+                dev: meta.dev(),
+                ino: meta.ino(),
+            },
+        ))
+    }
+
+    fn start(spec: &ReverseForwardSpec) -> io::Result<Self> {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let endpoint = guest_endpoint(spec);
+        let host_addr = spec.host_addr.clone();
+        let stopped_by_drop = stopped.clone();
+
+        #[cfg(unix)]
+        if let Some(path) = &spec.guest_path {
+            let (listener, addr) = Self::bind_path(path)?;
+            thread::spawn(move || {
+                accept_clients(listener.incoming(), endpoint, host_addr, stopped_by_drop)
+            });
+
+            return Ok(Self { addr, stopped });
+        }
+        #[cfg(not(unix))]
+        if spec.guest_path.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix sockets are not supported",
+            ));
+        }
+
+        let ip = spec.bind_addr.unwrap_or(Ipv4Addr::LOCALHOST.into());
+        let listener = TcpListener::bind((ip, spec.guest_port))?;
+        let addr = listener.local_addr()?;
+        thread::spawn(move || {
+            accept_clients(listener.incoming(), endpoint, host_addr, stopped_by_drop)
+        });
+
+        Ok(Self {
+            addr: ListenAddr::Tcp(addr),
+            stopped,
+        })
+    }
+}
+
+impl Drop for ReverseListener {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+
+        // wakes up the blocking accept, which sees the flag and closes the listener
+        match &self.addr {
+            ListenAddr::Tcp(addr) => {
+                let ip = match addr.ip() {
+                    IpAddr::V4(ip) if ip.is_unspecified() => Ipv4Addr::LOCALHOST.into(),
+                    IpAddr::V6(ip) if ip.is_unspecified() => Ipv6Addr::LOCALHOST.into(),
+                    ip => ip,
+                };
+                let _ = TcpStream::connect((ip, addr.port()));
+            }
+            #[cfg(unix)]
+            ListenAddr::Unix { path, dev, ino } => {
+                use std::os::unix::fs::MetadataExt;
+
+                let _ = UnixStream::connect(path);
+                if std::fs::symlink_metadata(path)
+                    .is_ok_and(|meta| (meta.dev(), meta.ino()) == (*dev, *ino))
+                {
+                    let _ = std::fs::remove_file(path);
+                }
+            }
+        }
+    }
+}
+
+// keeps a listener for each of the reverse forwards declared in the forward list file
+#[derive(Default)]
+pub struct ReverseForwarder {
+    listeners: HashMap<ReverseForwardSpec, ReverseListener>,
+}
+
+impl ReverseForwarder {
+    pub fn update(&mut self, specs: &[ReverseForwardSpec]) {
+        // dropped first, so that a changed spec can bind the same port again
+        self.listeners.retain(|spec, _| {
+            let declared = specs.contains(spec);
+            if !declared {
+                println!(
+                    "guest {} is no longer forwarded to {} on the host",
+                    guest_endpoint(spec),
+                    spec.host_addr
+                );
+            }
+            declared
+        });
+
+        for spec in specs {
+            if self.listeners.contains_key(spec) {
+                continue;
+            }
+
+            // retried on the next heartbeat
+            match ReverseListener::start(spec) {
+                Ok(listener) => {
+                    println!(
+                        "guest {} is forwarded to {} on the host",
+                        guest_endpoint(spec),
+                        spec.host_addr
+                    );
+                    self.listeners.insert(spec.clone(), listener);
+                }
+                Err(e) => eprintln!(
+                    "[Warning] failed to listen on guest {} for {}: {e}",
+                    guest_endpoint(spec),
+                    spec.host_addr
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn spec(guest_path: &std::path::Path) -> ReverseForwardSpec {
+        ReverseForwardSpec {
+            guest_port: 0,
+            guest_path: Some(guest_path.to_str().unwrap().to_string()),
+            bind_addr: None,
+            host_addr: "127.0.0.1:1".to_string(),
+        }
+    }
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("vmc-reverse-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn live_socket_is_not_replaced() {
+        let path = socket_path("live");
+        let _live = UnixListener::bind(&path).unwrap();
+
+        let err = ReverseListener::start(&spec(&path)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert!(UnixStream::connect(&path).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stale_socket_is_replaced() {
+        let path = socket_path("stale");
+        drop(UnixListener::bind(&path).unwrap());
+
+        let listener = ReverseListener::start(&spec(&path)).unwrap();
+        assert!(UnixStream::connect(&path).is_ok());
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn socket_of_another_listener_is_kept_on_drop() {
+        let path = socket_path("replaced");
+        let listener = ReverseListener::start(&spec(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let _other = UnixListener::bind(&path).unwrap();
+
+        drop(listener);
+        assert!(UnixStream::connect(&path).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,168 @@
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use vmc_common::types::MachineStats;
+
+const SECTOR_SIZE: u64 = 512;
+
+fn parse_load_avg(content: &str) -> Option<[f64; 3]> {
+    let mut fields = content.split_whitespace();
+
+    Some([
+        fields.next()?.parse().ok()?,
+        fields.next()?.parse().ok()?,
+        fields.next()?.parse().ok()?,
+    ])
+}
+
+fn parse_meminfo(content: &str) -> Option<(u64, u64)> {
+    let (mut total, mut available) = (None, None);
+
+    for line in content.lines() {
+        let mut fields = line.split_whitespace();
+        match fields.next() {
+            Some("MemTotal:") => total = fields.next()?.parse().ok(),
+            Some("MemAvailable:") => available = fields.next()?.parse().ok(),
+            _ => {}
+        }
+    }
+
+    Some((total?, available?))
+}
+
+fn is_whole_disk(name: &str) -> bool {
+    !name.starts_with("loop")
+        && !name.starts_with("ram")
+        && Path::new(&format!("/sys/block/{name}")).exists()
+}
+
+fn parse_diskstats(content: &str, is_whole_disk: impl Fn(&str) -> bool) -> (u64, u64) {
+    let (mut read, mut written) = (0, 0);
+
+    for line in content.lines() {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        if fields.len() < 10 {
+            continue;
+        }
+
+        // count whole disks only, partitions are included in them
+        if !is_whole_disk(fields[2]) {
+            continue;
+        }
+
+        read += fields[5].parse::<u64>().unwrap_or(0) * SECTOR_SIZE;
+        written += fields[9].parse::<u64>().unwrap_or(0) * SECTOR_SIZE;
+    }
+
+    (read, written)
+}
+
+fn parse_net_dev(content: &str) -> Option<(u64, u64)> {
+    let (mut rx, mut tx) = (0, 0);
+
+    // the first 2 lines are headers
+    for line in content.lines().skip(2) {
+        let (itf, counters) = line.split_once(':')?;
+        if itf.trim() == "lo" {
+            continue;
+        }
+
+        let fields: Vec<_> = counters.split_whitespace().collect();
+        if fields.len() < 9 {
+            continue;
+        }
+
+        rx += fields[0].parse::<u64>().unwrap_or(0);
+        tx += fields[8].parse::<u64>().unwrap_or(0);
+    }
+
+    Some((rx, tx))
+}
+
+pub fn collect_machine_stats() -> MachineStats {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let read = |path| fs::read_to_string(path).ok();
+    let load_avg = read("/proc/loadavg")
+        .and_then(|content| parse_load_avg(&content))
+        .unwrap_or_default();
+    let (mem_total_kb, mem_available_kb) = read("/proc/meminfo")
+        .and_then(|content| parse_meminfo(&content))
+        .unwrap_or_default();
+    let (disk_read_bytes, disk_write_bytes) = read("/proc/diskstats")
+        .map(|content| parse_diskstats(&content, is_whole_disk))
+        .unwrap_or_default();
+    let (net_rx_bytes, net_tx_bytes) = read("/proc/net/dev")
+        .and_then(|content| parse_net_dev(&content))
+        .unwrap_or_default();
+
+    MachineStats {
+        timestamp,
+        load_avg,
+        mem_total_kb,
+        mem_available_kb,
+        disk_read_bytes,
+        disk_write_bytes,
+        net_rx_bytes,
+        net_tx_bytes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_avg_needs_three_numbers() {
+        assert_eq!(
+            parse_load_avg("0.52 0.58 0.59 1/467 12345\n"),
+            Some([0.52, 0.58, 0.59])
+        );
+        assert_eq!(parse_load_avg("0.52 0.58"), None);
+        assert_eq!(parse_load_avg("0.52 high 0.59"), None);
+    }
+
+    #[test]
+    fn meminfo_needs_total_and_available() {
+        let content = "\
+MemTotal:        8039412 kB
+MemFree:          312456 kB
+MemAvailable:    5123456 kB
+";
+        assert_eq!(parse_meminfo(content), Some((8039412, 5123456)));
+        // kernels before 3.14 don't report MemAvailable
+        assert_eq!(parse_meminfo("MemTotal:        8039412 kB\n"), None);
+    }
+
+    #[test]
+    fn diskstats_counts_whole_disks_in_bytes() {
+        let content = "\
+ 253       0 vda 1000 0 2048 500 300 0 4096 200 0 600 700
+ 253       1 vda1 900 0 2000 400 250 0 4000 150 0 500 550
+   7       0 loop0 10 0 80 1 0 0 0 0 0 1 1
+ 253      16 vdb 1 0 broken 1 1 0 8 1 0 1 1
+ 253      32 vdc 1 0
+";
+        let (read, written) =
+            parse_diskstats(content, |name| name.starts_with("vd") && name.len() == 3);
+        // a malformed counter of vdb counts as 0, the short line of vdc is skipped
+        assert_eq!(read, 2048 * SECTOR_SIZE);
+        assert_eq!(written, (4096 + 8) * SECTOR_SIZE);
+        assert_eq!(parse_diskstats("", |_| true), (0, 0));
+    }
+
+    #[test]
+    fn net_dev_skips_loopback() {
+        let content = "\
+Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo: 5000      50    0    0    0     0          0         0     5000      50    0    0    0     0       0          0
+  eth0: 1000      10    0    0    0     0          0         0     2000      20    0    0    0     0       0          0
+  eth1: 300        3    0    0    0     0          0         0      400       4    0    0    0     0       0          0
+";
+        assert_eq!(parse_net_dev(content), Some((1300, 2400)));
+        assert_eq!(parse_net_dev("header\nheader\n"), Some((0, 0)));
+    }
+}
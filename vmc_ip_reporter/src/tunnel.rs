@@ -0,0 +1,108 @@
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpStream};
+use std::thread;
+use std::time::Duration;
+use vmc_common::mux::{Channel, Mux};
+use vmc_common::protocol::{try_server_negotiation, ChannelTarget, Request, TunnelRequest};
+use vmc_common::relay::AnyStream;
+use vmc_common::types::SerializedDataContainer;
+use vmc_common::{SERVER_HOST, SERVER_PORT};
+
+const SESSION_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+const LOCAL_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn connect_server() -> io::Result<TcpStream> {
+    let mut server = TcpStream::connect(format!("{SERVER_HOST}:{SERVER_PORT}"))?;
+    if !try_server_negotiation(&mut server)? {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            "protocol version mismatched",
+        ));
+    }
+
+    Ok(server)
+}
+
+// a service may listen only on one of the loopbacks, or only on the address of the guest
+fn connect_local(port: u16, guest_ip: Option<IpAddr>) -> io::Result<TcpStream> {
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no address to connect");
+
+    for ip in [Ipv4Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()]
+        .into_iter()
+        .chain(guest_ip)
+    {
+        match TcpStream::connect_timeout(&(ip, port).into(), LOCAL_CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = e,
+        }
+    }
+
+    Err(last_err)
+}
+
+// a unix socket of the guest, e.g. /var/run/docker.sock
+#[cfg(unix)]
+fn connect_path(path: &str) -> io::Result<AnyStream> {
+    std::os::unix::net::UnixStream::connect(path).map(AnyStream::Unix)
+}
+
+#[cfg(not(unix))]
+fn connect_path(_path: &str) -> io::Result<AnyStream> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "unix sockets are not supported",
+    ))
+}
+
+fn serve_channel(channel: Channel, target: ChannelTarget, guest_ip: Option<IpAddr>) {
+    let local = match &target {
+        ChannelTarget::Port(port) => connect_local(*port, guest_ip).map(AnyStream::Tcp),
+        ChannelTarget::Path(path) => connect_path(path),
+    };
+    let local = match local {
+        Ok(local) => local,
+        Err(e) => {
+            eprintln!("[Warning] failed to connect to guest {target} for the tunnel: {e}");
+            channel.reject(e.to_string());
+            return;
+        }
+    };
+
+    if channel.accept().is_ok() {
+        let _ = channel.relay(local, |_| {}, |_| {});
+    }
+}
+
+fn run_session(hostname: &str, guest_ip: Option<IpAddr>) -> io::Result<()> {
+    let server = connect_server()?;
+    (&server).write_all(
+        &SerializedDataContainer::from_serializable_data(&Request::Tunnel(TunnelRequest::Attach(
+            hostname.to_string(),
+        )))
+        .unwrap()
+        .to_one_vec(),
+    )?;
+    println!("tunnel session is attached to {SERVER_HOST}:{SERVER_PORT}");
+
+    let mux = Mux::new(&server)?;
+    mux.serve(server, |channel, target| {
+        thread::spawn(move || serve_channel(channel, target, guest_ip));
+    })
+}
+
+/*
+ *
+ * host client ---> server (host_port) ---[channel]---> tunnel session ---> guest_port or guest_path
+ *
+ * forwards with "tunnel" reach the guest through this session, which is always kept attached
+ */
+pub fn start_tunnel_session(hostname: String, guest_ip: Option<IpAddr>) {
+    thread::spawn(move || loop {
+        match run_session(&hostname, guest_ip) {
+            Ok(()) => eprintln!("[Warning] tunnel session is closed by the server"),
+            Err(e) => eprintln!("[Warning] tunnel session is lost: {e}"),
+        }
+
+        thread::sleep(SESSION_RETRY_INTERVAL);
+    });
+}
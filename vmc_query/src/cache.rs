@@ -0,0 +1,199 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use vmc_common::types::MachineInfo;
+
+const CACHE_FILE: &str = "vmc_query_cache.json";
+
+const POSITIVE_TTL_SECS: u64 = 60;
+const NEGATIVE_TTL_SECS: u64 = 10;
+// stale entries are only kept as an offline fallback, so old and excess ones are dropped on save
+const MAX_AGE_SECS: u64 = 7 * 24 * 60 * 60;
+const MAX_ENTRIES: usize = 256;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CacheEntry {
+    pub fetched_at: u64,
+    pub machine: Option<MachineInfo>,
+}
+
+impl CacheEntry {
+    pub fn age(&self) -> u64 {
+        now().saturating_sub(self.fetched_at)
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        let ttl = if self.machine.is_some() {
+            POSITIVE_TTL_SECS
+        } else {
+            NEGATIVE_TTL_SECS
+        };
+
+        self.age() < ttl
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct QueryCache {
+    entries: HashMap<String, CacheEntry>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn cache_dir() -> Option<PathBuf> {
+    if let Ok(dir) = env::var("XDG_CACHE_HOME") {
+        return Some(PathBuf::from(dir));
+    }
+    #[cfg(target_os = "windows")]
+    if let Ok(dir) = env::var("LOCALAPPDATA") {
+        return Some(PathBuf::from(dir));
+    }
+
+    env::var("HOME")
+        .ok()
+        .map(|home| PathBuf::from(home).join(".cache"))
+}
+
+// concurrent queries may save at the same time, so the cache is replaced as a whole rather than rewritten
+fn write_atomically(path: &Path, content: &str) -> std::io::Result<()> {
+    let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+    fs::write(&tmp, content)
+        .and_then(|_| fs::rename(&tmp, path))
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&tmp);
+        })
+}
+
+impl QueryCache {
+    pub fn load() -> Self {
+        cache_dir()
+            .and_then(|dir| fs::read_to_string(dir.join(CACHE_FILE)).ok())
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    // the cache is only an optimization, failing to write it is not an error
+    pub fn save(&mut self) {
+        self.prune();
+        if let Some(dir) = cache_dir() {
+            let _ = fs::create_dir_all(&dir).and_then(|_| {
+                write_atomically(
+                    &dir.join(CACHE_FILE),
+                    &serde_json::to_string(self).expect("failed to serialize query cache"),
+                )
+            });
+        }
+    }
+
+    fn prune(&mut self) {
+        self.entries.retain(|_, entry| entry.age() < MAX_AGE_SECS);
+
+        if self.entries.len() > MAX_ENTRIES {
+            let mut hostnames: Vec<String> = self.entries.keys().cloned().collect();
+            hostnames.sort_by_key(|hostname| std::cmp::Reverse(self.entries[hostname].fetched_at));
+            for hostname in &hostnames[MAX_ENTRIES..] {
+                self.entries.remove(hostname);
+            }
+        }
+    }
+
+    pub fn get(&self, hostname: &str) -> Option<&CacheEntry> {
+        self.entries.get(hostname)
+    }
+
+    pub fn insert(&mut self, hostname: &str, machine: Option<MachineInfo>) {
+        self.entries.insert(
+            hostname.to_string(),
+            CacheEntry {
+                fetched_at: now(),
+                machine,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn machine(hostname: &str) -> MachineInfo {
+        MachineInfo {
+            hostname: hostname.to_string(),
+            ipv4_addr: "192.168.122.10".to_string(),
+            ipv6_addr: None,
+        }
+    }
+
+    // the clock may tick between creating and checking an entry, so fresh ones are checked a second early
+    fn entry(age: u64, machine: Option<MachineInfo>) -> CacheEntry {
+        CacheEntry {
+            fetched_at: now() - age,
+            machine,
+        }
+    }
+
+    #[test]
+    fn found_hosts_expire_after_positive_ttl() {
+        assert!(entry(0, Some(machine("alpha"))).is_fresh());
+        assert!(entry(POSITIVE_TTL_SECS - 2, Some(machine("alpha"))).is_fresh());
+        assert!(!entry(POSITIVE_TTL_SECS, Some(machine("alpha"))).is_fresh());
+    }
+
+    #[test]
+    fn unknown_hosts_expire_after_negative_ttl() {
+        let mut cache = QueryCache::default();
+        cache.insert("alpha", None);
+        assert!(cache.get("alpha").unwrap().is_fresh());
+        assert!(cache.get("alpha").unwrap().machine.is_none());
+
+        assert!(entry(NEGATIVE_TTL_SECS - 2, None).is_fresh());
+        assert!(!entry(NEGATIVE_TTL_SECS, None).is_fresh());
+    }
+
+    #[test]
+    fn prune_drops_old_and_least_recent_entries() {
+        let mut cache = QueryCache::default();
+        cache
+            .entries
+            .insert("old".to_string(), entry(MAX_AGE_SECS, None));
+        let t = now();
+        for i in 0..MAX_ENTRIES + 2 {
+            cache.entries.insert(
+                format!("host{i}"),
+                CacheEntry {
+                    fetched_at: t - i as u64,
+                    machine: Some(machine(&format!("host{i}"))),
+                },
+            );
+        }
+
+        cache.prune();
+        assert_eq!(cache.entries.len(), MAX_ENTRIES);
+        assert!(cache.get("old").is_none());
+        assert!(cache.get("host0").is_some());
+        assert!(cache.get(&format!("host{}", MAX_ENTRIES - 1)).is_some());
+        assert!(cache.get(&format!("host{MAX_ENTRIES}")).is_none());
+    }
+
+    #[test]
+    fn cache_file_is_replaced_without_leaving_temp_files() {
+        let dir = env::temp_dir().join(format!("vmc-query-cache-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(CACHE_FILE);
+
+        write_atomically(&path, "first").unwrap();
+        write_atomically(&path, "second").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,11 +1,72 @@
+mod cache;
+
 use std::io::prelude::*;
-use std::net::TcpStream;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
 use vmc_common::{
-    protocol::{server_negotiation, NSRequest, NSResponse, Request, Response},
-    types::SerializedDataContainer,
+    protocol::{try_server_negotiation, NSRequest, NSResponse, Request, Response},
+    types::{
+        CaptureFormat, CaptureSpec, MachineInfo, MachineStats, PortforwardProtocol,
+        SerializedDataContainer,
+    },
     SERVER_HOST, SERVER_PORT,
 };
 
+use crate::cache::QueryCache;
+
+#[derive(PartialEq)]
+enum Mode {
+    QueryIPv4,
+    QueryIPv6,
+    QueryIpv6OrV4,
+    List,
+    Stats,
+    Forwards,
+    Connections,
+    KillConnection(u64),
+    StartCapture(CaptureSpec),
+    StopCapture(PortforwardProtocol, u16),
+}
+
+// a capture stops itself when it reaches this size unless another is given
+const DEFAULT_CAPTURE_MAX_BYTES: u64 = 16 * 1024 * 1024;
+
+// 8080 or 8080/udp
+fn parse_port(arg: &str) -> (PortforwardProtocol, u16) {
+    let (port, protocol) = match arg.split_once('/') {
+        Some((port, "tcp")) => (port, PortforwardProtocol::Tcp),
+        Some((port, "udp")) => (port, PortforwardProtocol::Udp),
+        Some((_, protocol)) => panic!("Unkown protocol was given: {protocol}"),
+        None => (arg, PortforwardProtocol::Tcp),
+    };
+
+    (protocol, port.parse().expect("invalid host port"))
+}
+
+// forwards --capture <port>[/udp] <file name> [max bytes], a name ending with .pcapng is written as pcapng
+// into capture_dir of the server
+fn parse_capture(args: &[String]) -> CaptureSpec {
+    let (protocol, host_port) = parse_port(args.first().expect("--capture requires a host port"));
+    let path = args.get(1).expect("--capture requires a file name").clone();
+    let format = if path.ends_with(".pcapng") {
+        CaptureFormat::Pcapng
+    } else {
+        CaptureFormat::Transcript
+    };
+    let max_bytes = args
+        .get(2)
+        .map(|max_bytes| max_bytes.parse().expect("invalid max bytes"))
+        .unwrap_or(DEFAULT_CAPTURE_MAX_BYTES);
+
+    CaptureSpec {
+        protocol,
+        host_port,
+        format,
+        path,
+        max_bytes,
+    }
+}
+
 fn normalize_ipv6(ipv6_addr: &str) -> String {
     #[cfg(target_os = "windows")]
     {
@@ -25,8 +86,176 @@ fn normalize_ipv6(ipv6_addr: &str) -> String {
     }
 }
 
+const SPARK_CHARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+fn sparkline(values: &[f64]) -> String {
+    let max = values.iter().cloned().fold(0.0, f64::max);
+    let min = values.iter().cloned().fold(max, f64::min);
+    let range = max - min;
+
+    values
+        .iter()
+        .map(|v| {
+            if range <= 0.0 {
+                SPARK_CHARS[0]
+            } else {
+                let idx = ((v - min) / range * (SPARK_CHARS.len() - 1) as f64).round() as usize;
+                SPARK_CHARS[idx.min(SPARK_CHARS.len() - 1)]
+            }
+        })
+        .collect()
+}
+
+// per second rates between consecutive samples of a cumulative counter
+fn rates(history: &[MachineStats], counter: fn(&MachineStats) -> u64) -> Vec<f64> {
+    history
+        .windows(2)
+        .map(|w| {
+            let elapsed = w[1].timestamp.saturating_sub(w[0].timestamp).max(1);
+            counter(&w[1]).saturating_sub(counter(&w[0])) as f64 / elapsed as f64
+        })
+        .collect()
+}
+
+fn human_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+fn print_stats(hostname: &str, history: &[MachineStats]) {
+    let Some(current) = history.last() else {
+        println!("{hostname} has not reported any stats yet");
+        return;
+    };
+
+    let mem_used_ratio = |s: &MachineStats| {
+        if s.mem_total_kb == 0 {
+            0.0
+        } else {
+            (s.mem_total_kb - s.mem_available_kb.min(s.mem_total_kb)) as f64 / s.mem_total_kb as f64
+        }
+    };
+    let last_rate = |rates: &[f64]| rates.last().cloned().unwrap_or(0.0);
+
+    let loads: Vec<_> = history.iter().map(|s| s.load_avg[0]).collect();
+    let mems: Vec<_> = history.iter().map(mem_used_ratio).collect();
+    let disk_reads = rates(history, |s| s.disk_read_bytes);
+    let disk_writes = rates(history, |s| s.disk_write_bytes);
+    let net_rxs = rates(history, |s| s.net_rx_bytes);
+    let net_txs = rates(history, |s| s.net_tx_bytes);
+
+    println!("stats of {hostname} ({} samples)", history.len());
+    println!(
+        " load       : {:.2} {:.2} {:.2}  {}",
+        current.load_avg[0],
+        current.load_avg[1],
+        current.load_avg[2],
+        sparkline(&loads)
+    );
+    println!(
+        " memory     : {:.1}% of {}  {}",
+        mem_used_ratio(current) * 100.0,
+        human_bytes(current.mem_total_kb as f64 * 1024.0),
+        sparkline(&mems)
+    );
+    println!(
+        " disk read  : {}/s  {}",
+        human_bytes(last_rate(&disk_reads)),
+        sparkline(&disk_reads)
+    );
+    println!(
+        " disk write : {}/s  {}",
+        human_bytes(last_rate(&disk_writes)),
+        sparkline(&disk_writes)
+    );
+    println!(
+        " net rx     : {}/s  {}",
+        human_bytes(last_rate(&net_rxs)),
+        sparkline(&net_rxs)
+    );
+    println!(
+        " net tx     : {}/s  {}",
+        human_bytes(last_rate(&net_txs)),
+        sparkline(&net_txs)
+    );
+}
+
+fn print_machine_info(mode: &Mode, ret: Option<MachineInfo>) -> std::io::Result<()> {
+    if let Some(mi) = ret {
+        match mode {
+            Mode::QueryIPv4 => println!("{}", mi.ipv4_addr),
+            Mode::QueryIPv6 => println!(
+                "{}",
+                mi.ipv6_addr
+                    .map(|e| normalize_ipv6(&e))
+                    .expect("your queried host does not have an ipv6 addr.")
+            ),
+            Mode::QueryIpv6OrV4 => {
+                if let Some(ipv6_addr) = mi.ipv6_addr {
+                    let ipv6_addr = normalize_ipv6(&ipv6_addr);
+                    println!("{ipv6_addr}");
+                } else {
+                    println!("{}", mi.ipv4_addr);
+                }
+            }
+            _ => panic!("never reach here"),
+        }
+
+        Ok(())
+    } else {
+        eprintln!("your queried hostname is not registered in server");
+
+        Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "No such a hostname",
+        ))
+    }
+}
+
+// short enough for a shell prompt to fall back to the cache without a noticeable stall
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+// a server which accepts but never answers would hang the query, and the cache fallback with it
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn connect_server() -> std::io::Result<TcpStream> {
+    let addr = format!("{SERVER_HOST}:{SERVER_PORT}")
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::AddrNotAvailable))?;
+    let mut server = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+    server.set_read_timeout(Some(READ_TIMEOUT))?;
+
+    if !try_server_negotiation(&mut server)? {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::ConnectionRefused,
+            "protocol version mismatched",
+        ));
+    }
+
+    Ok(server)
+}
+
+fn unexpected_response(response: String) -> std::io::Error {
+    eprintln!("unexpected response from the server: {response}");
+
+    std::io::Error::new(std::io::ErrorKind::InvalidData, "Unexpected response")
+}
+
 fn main() -> std::io::Result<()> {
-    let args: Vec<String> = std::env::args().collect();
+    let mut args: Vec<String> = std::env::args().collect();
+
+    // --no-cache: neither read nor write the cache, --refresh: ask the server and update the cache
+    let no_cache = args.iter().any(|arg| arg == "--no-cache");
+    let refresh = args.iter().any(|arg| arg == "--refresh");
+    args.retain(|arg| arg != "--no-cache" && arg != "--refresh");
 
     if args.len() < 2 {
         return Err(std::io::Error::new(
@@ -35,33 +264,65 @@ fn main() -> std::io::Result<()> {
         ));
     }
 
-    #[derive(PartialEq)]
-    enum Mode {
-        QueryIPv4,
-        QueryIPv6,
-        QueryIpv6OrV4,
-        List,
-    }
-
     let mode = match args[1].as_str() {
         "list" => Mode::List,
         "ip" => Mode::QueryIpv6OrV4,
         "ipv4" => Mode::QueryIPv4,
         "ipv6" => Mode::QueryIPv6,
+        "stats" => Mode::Stats,
+        "forwards" => match args.get(2).map(|arg| arg.as_str()) {
+            None => Mode::Forwards,
+            Some("--connections") => Mode::Connections,
+            Some("--kill") => Mode::KillConnection(
+                args.get(3)
+                    .and_then(|id| id.parse().ok())
+                    .expect("--kill requires the id of a connection"),
+            ),
+            Some("--capture") => Mode::StartCapture(parse_capture(&args[3..])),
+            Some("--stop-capture") => {
+                let (protocol, host_port) =
+                    parse_port(args.get(3).expect("--stop-capture requires a host port"));
+                Mode::StopCapture(protocol, host_port)
+            }
+            Some(arg) => panic!("Unkown option was given: {arg}"),
+        },
         _ => {
             panic!("Unkown command was given: {}", args[1]);
         }
     };
 
-    let mut server = TcpStream::connect(format!("{SERVER_HOST}:{SERVER_PORT}"))?;
+    let use_cache = !no_cache
+        && matches!(
+            mode,
+            Mode::QueryIPv4 | Mode::QueryIPv6 | Mode::QueryIpv6OrV4
+        );
+    let mut cache = if use_cache {
+        QueryCache::load()
+    } else {
+        QueryCache::default()
+    };
 
-    if !server_negotiation(&mut server) {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::ConnectionRefused,
-            "protocol version mismatched",
-        ));
+    if use_cache && !refresh {
+        if let Some(entry) = cache.get(&args[2]).filter(|entry| entry.is_fresh()) {
+            return print_machine_info(&mode, entry.machine.clone());
+        }
     }
 
+    let mut server = match connect_server() {
+        Ok(server) => server,
+        Err(e) => {
+            if let Some(entry) = use_cache.then(|| cache.get(&args[2])).flatten() {
+                eprintln!(
+                    "[Warning] failed to connect to server ({e}), using a cached result from {} secs ago",
+                    entry.age()
+                );
+                return print_machine_info(&mode, entry.machine.clone());
+            }
+
+            return Err(e);
+        }
+    };
+
     match mode {
         Mode::QueryIPv4 | Mode::QueryIPv6 | Mode::QueryIpv6OrV4 => {
             let q_hostname = args[2].clone();
@@ -76,6 +337,19 @@ fn main() -> std::io::Result<()> {
                 )
                 .unwrap();
         }
+        Mode::Stats => {
+            let q_hostname = args[2].clone();
+
+            server
+                .write_all(
+                    &SerializedDataContainer::from_serializable_data(&Request::NameService(
+                        NSRequest::GetStats(q_hostname),
+                    ))
+                    .unwrap()
+                    .to_one_vec(),
+                )
+                .unwrap();
+        }
         Mode::List => {
             server
                 .write_all(
@@ -87,31 +361,88 @@ fn main() -> std::io::Result<()> {
                 )
                 .unwrap();
         }
+        Mode::Forwards => {
+            server
+                .write_all(
+                    &SerializedDataContainer::from_serializable_data(&Request::NameService(
+                        NSRequest::GetForwardList,
+                    ))
+                    .unwrap()
+                    .to_one_vec(),
+                )
+                .unwrap();
+        }
+        Mode::Connections => {
+            server
+                .write_all(
+                    &SerializedDataContainer::from_serializable_data(&Request::NameService(
+                        NSRequest::GetConnections,
+                    ))
+                    .unwrap()
+                    .to_one_vec(),
+                )
+                .unwrap();
+        }
+        Mode::KillConnection(id) => {
+            server
+                .write_all(
+                    &SerializedDataContainer::from_serializable_data(&Request::NameService(
+                        NSRequest::KillConnection(id),
+                    ))
+                    .unwrap()
+                    .to_one_vec(),
+                )
+                .unwrap();
+        }
+        Mode::StartCapture(ref capture) => {
+            server
+                .write_all(
+                    &SerializedDataContainer::from_serializable_data(&Request::NameService(
+                        NSRequest::StartCapture(capture.clone()),
+                    ))
+                    .unwrap()
+                    .to_one_vec(),
+                )
+                .unwrap();
+        }
+        Mode::StopCapture(protocol, host_port) => {
+            server
+                .write_all(
+                    &SerializedDataContainer::from_serializable_data(&Request::NameService(
+                        NSRequest::StopCapture(protocol, host_port),
+                    ))
+                    .unwrap()
+                    .to_one_vec(),
+                )
+                .unwrap();
+        }
     };
 
     let sdc = SerializedDataContainer::from_reader(&mut server).unwrap();
     match sdc.to_serializable_data::<Response>().unwrap() {
         Response::NameService(ns_res) => match ns_res {
             NSResponse::Ip(ret) => {
-                if let Some(mi) = ret {
-                    match mode {
-                        Mode::QueryIPv4 => println!("{}", mi.ipv4_addr),
-                        Mode::QueryIPv6 => println!(
-                            "{}",
-                            mi.ipv6_addr
-                                .map(|e| normalize_ipv6(&e))
-                                .expect("your queried host does not have an ipv6 addr.")
-                        ),
-                        Mode::QueryIpv6OrV4 => {
-                            if let Some(ipv6_addr) = mi.ipv6_addr {
-                                let ipv6_addr = normalize_ipv6(&ipv6_addr);
-                                println!("{ipv6_addr}");
-                            } else {
-                                println!("{}", mi.ipv4_addr);
-                            }
-                        }
-                        _ => panic!("never reach here"),
+                if use_cache {
+                    cache.insert(&args[2], ret.clone());
+                    cache.save();
+                }
+
+                print_machine_info(&mode, ret)?;
+            }
+            NSResponse::MachineList(machines) => {
+                println!("machine list");
+                for machine in machines.iter() {
+                    print!("{} : {}", machine.hostname, machine.ipv4_addr);
+                    if let Some(ipv6_addr) = &machine.ipv6_addr {
+                        println!(" ( {ipv6_addr} )");
+                    } else {
+                        println!();
                     }
+                }
+            }
+            NSResponse::Stats(ret) => {
+                if let Some(history) = ret {
+                    print_stats(&args[2], &history);
                 } else {
                     eprintln!("your queried hostname is not registered in server");
 
@@ -121,20 +452,137 @@ fn main() -> std::io::Result<()> {
                     ));
                 }
             }
-            NSResponse::MachineList(machines) => {
-                println!("machine list");
-                for machine in machines.iter() {
-                    print!("{} : {}", machine.hostname, machine.ipv4_addr);
-                    if let Some(ipv6_addr) = &machine.ipv6_addr {
-                        println!(" ( {ipv6_addr} )");
+            NSResponse::ForwardList(entries) => {
+                println!("forward list");
+                for entry in entries.iter() {
+                    let forward = &entry.forward;
+                    print!(
+                        "{}/{:?} -> {}:{}",
+                        forward.host_port, forward.protocol, entry.hostname, forward.guest_port
+                    );
+                    if entry.active && forward.pool.is_some() {
+                        print!(" (pool)");
+                    }
+                    if !entry.active {
+                        println!(" (conflict, inactive)");
+                    } else if let Some(counters) = &entry.counters {
+                        println!(
+                            " [connections: {}, connect failures: {}, connect timeouts: {}, accept errors: {}, rejected: {}, transfer errors: {}, to guest: {}, from guest: {}]",
+                            counters.connections,
+                            counters.connect_failures,
+                            counters.connect_timeouts,
+                            counters.accept_errors,
+                            counters.rejected,
+                            counters.transfer_errors,
+                            human_bytes(counters.bytes_to_guest as f64),
+                            human_bytes(counters.bytes_from_guest as f64)
+                        );
                     } else {
                         println!();
                     }
                 }
             }
+            NSResponse::Connections(connections) => {
+                println!("connection list");
+                for connection in connections.iter() {
+                    println!(
+                        "#{} {}/{:?} {} -> {}:{} [{:.1} secs, to guest: {}, from guest: {}]",
+                        connection.id,
+                        connection.host_port,
+                        connection.protocol,
+                        connection
+                            .client_addr
+                            .map(|addr| addr.to_string())
+                            .unwrap_or("unix".to_string()),
+                        connection.hostname.as_deref().unwrap_or("-"),
+                        connection
+                            .guest_addr
+                            .map(|addr| addr.to_string())
+                            .unwrap_or("(connecting)".to_string()),
+                        connection.duration_ms as f64 / 1000.0,
+                        human_bytes(connection.bytes_to_guest as f64),
+                        human_bytes(connection.bytes_from_guest as f64)
+                    );
+                }
+            }
+            NSResponse::ConnectionKilled(killed) => {
+                if !killed {
+                    eprintln!("no such a connection is alive");
+
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        "No such a connection",
+                    ));
+                }
+            }
+            NSResponse::CaptureStarted(started) => {
+                if let Err(e) = started {
+                    eprintln!("{e}");
+
+                    return Err(std::io::Error::other(e));
+                }
+            }
+            NSResponse::CaptureStopped(written) => {
+                if let Some(written) = written {
+                    println!("{} written", human_bytes(written as f64));
+                } else {
+                    eprintln!("the rule was not captured");
+
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        "No such a capture",
+                    ));
+                }
+            }
+            ns_res => return Err(unexpected_response(format!("{ns_res:?}"))),
         },
-        _ => todo!(),
+        res => return Err(unexpected_response(format!("{res:?}"))),
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(timestamp: u64, net_rx_bytes: u64) -> MachineStats {
+        MachineStats {
+            timestamp,
+            net_rx_bytes,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rates_need_two_samples() {
+        assert!(rates(&[], |s| s.net_rx_bytes).is_empty());
+        assert!(rates(&[sample(100, 1000)], |s| s.net_rx_bytes).is_empty());
+        assert_eq!(
+            rates(&[sample(100, 1000), sample(110, 3000)], |s| s.net_rx_bytes),
+            vec![200.0]
+        );
+    }
+
+    #[test]
+    fn reset_counter_is_not_a_negative_rate() {
+        // e.g. the guest rebooted between the samples
+        let history = [sample(100, 5000), sample(110, 100), sample(120, 1100)];
+        assert_eq!(rates(&history, |s| s.net_rx_bytes), vec![0.0, 100.0]);
+    }
+
+    #[test]
+    fn samples_of_the_same_second_are_not_divided_by_zero() {
+        let history = [sample(100, 1000), sample(100, 1500), sample(90, 2000)];
+        assert_eq!(rates(&history, |s| s.net_rx_bytes), vec![500.0, 500.0]);
+    }
+
+    #[test]
+    fn sparkline_is_scaled_to_the_range() {
+        assert_eq!(sparkline(&[]), "");
+        assert_eq!(sparkline(&[42.0]), "▁");
+        assert_eq!(sparkline(&[3.0, 3.0, 3.0]), "▁▁▁");
+        assert_eq!(sparkline(&[0.0, 7.0, 3.5]), "▁█▅");
+        assert_eq!(sparkline(&[10.0, 17.0]), "▁█");
+    }
+}
@@ -0,0 +1,147 @@
+/*
+ * Throughput of forwarded TCP connections on the loopback.
+ *
+ *   $ cargo bench -p vmc_server
+ *
+ * every connection sends BYTES_PER_CONN bytes to a sink server through the port forwarder,
+ * and the sink replies a byte after receiving all of them.
+ */
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::unbounded_channel;
+use vmc_common::types::PortforwardProtocol;
+use vmc_server::port_forward::{
+    start_port_forward_service, PortforwardOptions, PortforwardRequest,
+};
+
+const BYTES_PER_CONN: usize = 256 * 1024 * 1024;
+const WRITE_CHUNK: usize = 64 * 1024;
+
+fn spawn_sink_server() -> u16 {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                let mut buf = vec![0; WRITE_CHUNK];
+                let mut received = 0;
+                while received < BYTES_PER_CONN {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => received += n,
+                    }
+                }
+                let _ = stream.write_all(&[1]);
+            });
+        }
+    });
+
+    port
+}
+
+fn unused_port() -> u16 {
+    TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn spawn_forwarder(options: PortforwardOptions, dst_port: u16) -> u16 {
+    let src_port = unused_port();
+    let (pf_req, pf_recv) = unbounded_channel();
+    start_port_forward_service(options, pf_recv);
+
+    pf_req
+        .send(PortforwardRequest::UpdateRoutingRule {
+            protocol: PortforwardProtocol::Tcp,
+            src_port,
+            dst_addrs: vec![SocketAddr::from((Ipv4Addr::LOCALHOST, dst_port))],
+            bind_addr: None,
+            access: Default::default(),
+            socket_options: Default::default(),
+            tunnel: None,
+            proxy_protocol: None,
+            host_path: None,
+            pool: None,
+        })
+        .unwrap();
+
+    // keeps the service alive
+    std::mem::forget(pf_req);
+
+    while TcpStream::connect((Ipv4Addr::LOCALHOST, src_port)).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    src_port
+}
+
+fn measure(port: u16, conns: usize) -> f64 {
+    let start = Instant::now();
+
+    let handles: Vec<_> = (0..conns)
+        .map(|_| {
+            thread::spawn(move || {
+                let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+                let buf = vec![0; WRITE_CHUNK];
+                for _ in 0..BYTES_PER_CONN / WRITE_CHUNK {
+                    stream.write_all(&buf).unwrap();
+                }
+                stream.read_exact(&mut [0]).unwrap();
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    (BYTES_PER_CONN * conns) as f64 / (1024.0 * 1024.0) / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let sink_port = spawn_sink_server();
+
+    let mut targets = vec![
+        ("direct", sink_port),
+        (
+            "forward (buf 1 KiB)",
+            spawn_forwarder(
+                PortforwardOptions {
+                    buf_size: 1024,
+                    ..Default::default()
+                },
+                sink_port,
+            ),
+        ),
+        (
+            "forward",
+            spawn_forwarder(PortforwardOptions::default(), sink_port),
+        ),
+    ];
+    if cfg!(target_os = "linux") {
+        targets.push((
+            "forward (splice)",
+            spawn_forwarder(
+                PortforwardOptions {
+                    use_splice: true,
+                    ..Default::default()
+                },
+                sink_port,
+            ),
+        ));
+    }
+
+    for conns in [1, 16] {
+        for (name, port) in targets.iter() {
+            println!(
+                "{name:<20} {conns:>2} conns: {:>8.1} MiB/s",
+                measure(*port, conns)
+            );
+        }
+    }
+}
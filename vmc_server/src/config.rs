@@ -0,0 +1,167 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::forward_table::ConflictPolicy;
+use crate::hooks::HookSpec;
+use crate::lease::LeaseSource;
+use crate::port_forward::{AccessList, PortforwardOptions, DEFAULT_BUF_SIZE};
+use crate::proxy::{ProxyOptions, ReverseProxyOptions};
+
+#[cfg(not(target_os = "windows"))]
+static SERVER_CONFIG_FILE_PATH: &str = "/etc/vmc_server.json";
+#[cfg(target_os = "windows")]
+static SERVER_CONFIG_FILE_PATH: &str = "C:\\etc\\vmc_server.json";
+
+static SERVER_CONFIG_ENV_VAR: &str = "VMC_SERVER_CONFIG";
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub hooks: Vec<HookSpec>,
+    pub stale_after_secs: u64,
+    pub expire_after_secs: u64,
+    pub stats_history_len: usize,
+    pub lease_sources: Vec<LeaseSource>,
+    pub lease_poll_interval_secs: u64,
+    pub forward_buf_size: usize,
+    pub forward_splice: bool,
+    pub forward_drain_on_remove: bool,
+    // connections of a changed rule are left to finish for this long, closed at once if None
+    pub forward_drain_timeout_ms: Option<u64>,
+    pub forward_connect_timeout_ms: u64,
+    pub forward_connect_retries: u32,
+    pub forward_connect_retry_interval_ms: u64,
+    // the interface of this machine facing the guests, the scope of link-local guest addrs
+    pub forward_ipv6_interface: Option<String>,
+    pub forward_conflict_policy: ConflictPolicy,
+    // hostname -> priority, used by the priority conflict policy, 0 if absent
+    pub forward_priorities: HashMap<String, i32>,
+    // host ports allocated to forwards declared with host_port 0, inclusive
+    pub forward_auto_port_range: (u16, u16),
+    // host_path of forwards is a file name in this directory, which has to belong to the server,
+    // forwards with host_path are refused if None
+    pub forward_socket_dir: Option<PathBuf>,
+    // captures are written to new files in this directory, named by the client, refused if None
+    pub capture_dir: Option<PathBuf>,
+    // SOCKS5 and HTTP CONNECT proxy into the guests, disabled if None
+    pub proxy_port: Option<u16>,
+    // None is 127.0.0.1, since the proxy reaches every port of the guests
+    pub proxy_bind_addr: Option<IpAddr>,
+    // source CIDRs of the clients, the same as the access lists of forwards
+    pub proxy_allow: Vec<String>,
+    pub proxy_deny: Vec<String>,
+    // HTTP reverse proxy routing "<hostname>.<suffix>" to the guest, disabled if None
+    pub http_proxy_port: Option<u16>,
+    // None is 127.0.0.1, the same as the proxy
+    pub http_proxy_bind_addr: Option<IpAddr>,
+    pub http_proxy_allow: Vec<String>,
+    pub http_proxy_deny: Vec<String>,
+    pub http_proxy_suffixes: Vec<String>,
+    pub http_proxy_guest_port: u16,
+    // hostname -> the port of the guest, http_proxy_guest_port if absent
+    pub http_proxy_guest_ports: HashMap<String, u16>,
+    // host targets guests may reverse forward to, exactly as named by the guest,
+    // like "127.0.0.1:5432" or "unix:/run/app.sock", everything else is refused
+    pub reverse_forward_targets: Vec<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            hooks: vec![],
+            // vmc_ip_reporter sends a heartbeat every 30 secs
+            stale_after_secs: 90,
+            expire_after_secs: 600,
+            // an hour of heartbeats
+            stats_history_len: 120,
+            lease_sources: vec![],
+            lease_poll_interval_secs: 10,
+            forward_buf_size: DEFAULT_BUF_SIZE,
+            forward_splice: false,
+            forward_drain_on_remove: false,
+            forward_drain_timeout_ms: None,
+            forward_connect_timeout_ms: 5000,
+            forward_connect_retries: 2,
+            forward_connect_retry_interval_ms: 500,
+            forward_ipv6_interface: None,
+            forward_conflict_policy: ConflictPolicy::default(),
+            forward_priorities: HashMap::new(),
+            forward_auto_port_range: (20000, 29999),
+            forward_socket_dir: None,
+            capture_dir: None,
+            proxy_port: None,
+            proxy_bind_addr: None,
+            proxy_allow: vec![],
+            proxy_deny: vec![],
+            http_proxy_port: None,
+            http_proxy_bind_addr: None,
+            http_proxy_allow: vec![],
+            http_proxy_deny: vec![],
+            http_proxy_suffixes: vec!["localhost".to_string()],
+            http_proxy_guest_port: 80,
+            http_proxy_guest_ports: HashMap::new(),
+            reverse_forward_targets: vec![],
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn portforward_options(&self) -> PortforwardOptions {
+        PortforwardOptions {
+            buf_size: self.forward_buf_size,
+            use_splice: self.forward_splice,
+            drain_on_remove: self.forward_drain_on_remove,
+            drain_timeout: self.forward_drain_timeout_ms.map(Duration::from_millis),
+            connect_timeout: Duration::from_millis(self.forward_connect_timeout_ms),
+            connect_retries: self.forward_connect_retries,
+            connect_retry_interval: Duration::from_millis(self.forward_connect_retry_interval_ms),
+        }
+    }
+
+    pub fn proxy_options(&self) -> Option<ProxyOptions> {
+        Some(ProxyOptions {
+            bind_addr: self.proxy_bind_addr.unwrap_or(Ipv4Addr::LOCALHOST.into()),
+            port: self.proxy_port?,
+            access: AccessList::parse(&self.proxy_allow, &self.proxy_deny)
+                .expect("failed to parse the access list of proxy"),
+            connect_timeout: Duration::from_millis(self.forward_connect_timeout_ms),
+            ipv6_interface: self.forward_ipv6_interface.clone(),
+        })
+    }
+
+    pub fn reverse_proxy_options(&self) -> Option<ReverseProxyOptions> {
+        Some(ReverseProxyOptions {
+            bind_addr: self
+                .http_proxy_bind_addr
+                .unwrap_or(Ipv4Addr::LOCALHOST.into()),
+            port: self.http_proxy_port?,
+            access: AccessList::parse(&self.http_proxy_allow, &self.http_proxy_deny)
+                .expect("failed to parse the access list of http proxy"),
+            connect_timeout: Duration::from_millis(self.forward_connect_timeout_ms),
+            ipv6_interface: self.forward_ipv6_interface.clone(),
+            suffixes: self.http_proxy_suffixes.clone(),
+            guest_port: self.http_proxy_guest_port,
+            guest_ports: self.http_proxy_guest_ports.clone(),
+        })
+    }
+}
+
+pub fn load_server_config() -> ServerConfig {
+    let file_path =
+        env::var(SERVER_CONFIG_ENV_VAR).unwrap_or_else(|_| SERVER_CONFIG_FILE_PATH.to_string());
+    let path = Path::new(&file_path);
+
+    if let Ok(mut file) = File::open(path) {
+        let mut s = String::new();
+        file.read_to_string(&mut s).unwrap();
+        serde_json::from_str::<ServerConfig>(&s).expect("failed to parse server config")
+    } else {
+        ServerConfig::default()
+    }
+}
@@ -0,0 +1,771 @@
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, UdpSocket};
+use std::ops::RangeInclusive;
+use vmc_common::types::{
+    PortforwardConflict, PortforwardEntry, PortforwardList, PortforwardProtocol, PortforwardSpec,
+    PortforwardStatus,
+};
+
+use crate::port_forward::GuestAddrs;
+
+type HostPort = (PortforwardProtocol, u16);
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    // the machine which declared the host port first keeps it until it drops the forward
+    #[default]
+    FirstWins,
+    // a host port declared by several machines is forwarded to none of them
+    Reject,
+    // the machine with the highest configured priority wins, ties are broken by first-wins
+    Priority,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct PoolMember {
+    pub hostname: String,
+    pub guest_port: u16,
+    pub dst: GuestAddrs,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ForwardAction {
+    Update {
+        hostname: String,
+        forward: Box<PortforwardSpec>,
+        dst: GuestAddrs,
+        // every member including the owner, empty unless the forward of the owner has a pool
+        pool: Vec<PoolMember>,
+    },
+    Remove {
+        protocol: PortforwardProtocol,
+        src_port: u16,
+    },
+}
+
+// forwards added or removed at runtime, applied over the forward list of every heartbeat
+#[derive(Debug, Default)]
+struct ForwardOverrides {
+    added: PortforwardList,
+    removed: HashSet<(PortforwardProtocol, u16)>,
+}
+
+impl ForwardOverrides {
+    fn apply(&self, mut forward_list: PortforwardList) -> PortforwardList {
+        forward_list.merge_elem(&self.added);
+        for (protocol, guest_port) in self.removed.iter() {
+            forward_list.remove_guest_port(*protocol, *guest_port);
+        }
+
+        forward_list
+    }
+}
+
+#[derive(Debug)]
+struct MachineForwards {
+    addrs: GuestAddrs,
+    // as given by the last heartbeat
+    declared: PortforwardList,
+    // ranges are expanded and the allocated host ports are filled
+    forward_list: PortforwardList,
+    allocated: Vec<PortforwardSpec>,
+    unallocated: Vec<PortforwardSpec>,
+}
+
+impl MachineForwards {
+    fn get(&self, host_port: &HostPort) -> Option<&PortforwardSpec> {
+        self.forward_list
+            .forwards
+            .iter()
+            .find(|f| (f.protocol, f.host_port) == *host_port)
+    }
+}
+
+// the forwards declared by each machine in its last heartbeat, and the owner of each host port
+#[derive(Debug)]
+pub struct ForwardTable {
+    machines: HashMap<String, MachineForwards>,
+    // in the order of their first declaration
+    claimants: HashMap<HostPort, Vec<String>>,
+    owners: HashMap<HostPort, String>,
+    policy: ConflictPolicy,
+    priorities: HashMap<String, i32>,
+    // (hostname, protocol, guest port) -> host port, kept while the machine declares the forward
+    allocations: HashMap<(String, PortforwardProtocol, u16), u16>,
+    auto_ports: RangeInclusive<u16>,
+    overrides: HashMap<String, ForwardOverrides>,
+}
+
+// the port may still be taken by the time the forwarder binds it, which fails with a warning
+fn is_bindable(protocol: PortforwardProtocol, bind_addr: Option<IpAddr>, port: u16) -> bool {
+    let ip = bind_addr.unwrap_or(Ipv4Addr::UNSPECIFIED.into());
+
+    match protocol {
+        PortforwardProtocol::Tcp => TcpListener::bind((ip, port)).is_ok(),
+        PortforwardProtocol::Udp => UdpSocket::bind((ip, port)).is_ok(),
+    }
+}
+
+fn host_ports(forward_list: &PortforwardList) -> HashSet<HostPort> {
+    forward_list
+        .forwards
+        .iter()
+        .map(|f| (f.protocol, f.host_port))
+        .collect()
+}
+
+impl ForwardTable {
+    pub fn new(
+        policy: ConflictPolicy,
+        priorities: HashMap<String, i32>,
+        auto_ports: RangeInclusive<u16>,
+    ) -> Self {
+        Self {
+            machines: HashMap::new(),
+            claimants: HashMap::new(),
+            owners: HashMap::new(),
+            policy,
+            priorities,
+            allocations: HashMap::new(),
+            auto_ports,
+            overrides: HashMap::new(),
+        }
+    }
+
+    fn allocate(&mut self, hostname: &str, forward: &PortforwardSpec) -> Option<u16> {
+        let key = (hostname.to_string(), forward.protocol, forward.guest_port);
+        if let Some(host_port) = self.allocations.get(&key) {
+            return Some(*host_port);
+        }
+
+        let host_port = self.auto_ports.clone().find(|port| {
+            let host_port = (forward.protocol, *port);
+            !self.claimants.contains_key(&host_port)
+                && !self
+                    .allocations
+                    .iter()
+                    .any(|((_, protocol, _), p)| (*protocol, *p) == host_port)
+                && is_bindable(forward.protocol, forward.bind_addr, *port)
+        })?;
+        self.allocations.insert(key, host_port);
+
+        Some(host_port)
+    }
+
+    // Expands ranges into single ports and fills allocated host ports.
+    fn expand(&mut self, hostname: &str, forward_list: PortforwardList) -> MachineForwards {
+        let mut forwards = vec![];
+        let mut allocated = vec![];
+        let mut unallocated = vec![];
+        let mut requested = HashSet::new();
+
+        for spec in forward_list.forwards {
+            for i in 0..spec.port_count.max(1) {
+                let Some(guest_port) = spec.guest_port.checked_add(i) else {
+                    break;
+                };
+                let mut forward = PortforwardSpec {
+                    guest_port,
+                    port_count: 1,
+                    ..spec.clone()
+                };
+
+                if spec.host_port == 0 {
+                    requested.insert((hostname.to_string(), forward.protocol, guest_port));
+                    let Some(host_port) = self.allocate(hostname, &forward) else {
+                        unallocated.push(forward);
+                        continue;
+                    };
+                    forward.host_port = host_port;
+                    allocated.push(forward.clone());
+                } else {
+                    let Some(host_port) = spec.host_port.checked_add(i) else {
+                        break;
+                    };
+                    forward.host_port = host_port;
+                }
+
+                forwards.push(forward);
+            }
+        }
+
+        self.allocations
+            .retain(|key, _| key.0 != hostname || requested.contains(key));
+
+        MachineForwards {
+            addrs: GuestAddrs::default(),
+            declared: PortforwardList::default(),
+            forward_list: PortforwardList::new(forwards),
+            allocated,
+            unallocated,
+        }
+    }
+
+    fn is_pooled(&self, hostname: &str, host_port: &HostPort) -> bool {
+        self.machines
+            .get(hostname)
+            .and_then(|machine| machine.get(host_port))
+            .is_some_and(|forward| forward.pool.is_some())
+    }
+
+    // the claimants sharing the host port with its owner, in the order of their claims
+    fn pool_members(&self, host_port: &HostPort) -> Vec<&String> {
+        let Some(owner) = self.owners.get(host_port) else {
+            return vec![];
+        };
+        if !self.is_pooled(owner, host_port) {
+            return vec![];
+        }
+
+        self.claimants[host_port]
+            .iter()
+            .filter(|hostname| self.is_pooled(hostname, host_port))
+            .collect()
+    }
+
+    fn resolve_owner(&self, host_port: &HostPort) -> Option<String> {
+        let claimants = self.claimants.get(host_port)?;
+
+        // a pool is shared instead of being contended, and the first member gives its settings
+        if claimants
+            .iter()
+            .all(|hostname| self.is_pooled(hostname, host_port))
+        {
+            return claimants.first().cloned();
+        }
+
+        match self.policy {
+            ConflictPolicy::FirstWins => claimants.first().cloned(),
+            ConflictPolicy::Reject => (claimants.len() == 1).then(|| claimants[0].clone()),
+            ConflictPolicy::Priority => claimants
+                .iter()
+                .enumerate()
+                // max_by_key returns the last max, so earlier claims are ranked higher
+                .max_by_key(|(i, hostname)| {
+                    (
+                        self.priorities.get(*hostname).cloned().unwrap_or(0),
+                        std::cmp::Reverse(*i),
+                    )
+                })
+                .map(|(_, hostname)| hostname.clone()),
+        }
+    }
+
+    // Re-elects the owners of the given host ports, and returns how the routing rules should follow.
+    fn reassign(&mut self, host_ports: HashSet<HostPort>, updated: &str) -> Vec<ForwardAction> {
+        let mut actions = vec![];
+
+        for host_port in host_ports {
+            let new_owner = self.resolve_owner(&host_port);
+            let old_owner = match &new_owner {
+                Some(owner) => self.owners.insert(host_port, owner.clone()),
+                None => self.owners.remove(&host_port),
+            };
+
+            let pool: Vec<_> = self
+                .pool_members(&host_port)
+                .into_iter()
+                .map(|hostname| {
+                    let machine = &self.machines[hostname];
+                    PoolMember {
+                        hostname: hostname.clone(),
+                        guest_port: machine.get(&host_port).unwrap().guest_port,
+                        dst: machine.addrs,
+                    }
+                })
+                .collect();
+
+            match new_owner {
+                // re-sent on every heartbeat of the owner to follow its address, or of any member of the pool
+                Some(owner)
+                    if Some(&owner) != old_owner.as_ref()
+                        || owner == updated
+                        || !pool.is_empty() =>
+                {
+                    let machine = &self.machines[&owner];
+                    actions.push(ForwardAction::Update {
+                        hostname: owner.clone(),
+                        forward: Box::new(machine.get(&host_port).unwrap().clone()),
+                        dst: machine.addrs,
+                        pool,
+                    });
+                }
+                Some(_) => {}
+                None => {
+                    if old_owner.is_some() {
+                        actions.push(ForwardAction::Remove {
+                            protocol: host_port.0,
+                            src_port: host_port.1,
+                        });
+                    }
+                }
+            }
+        }
+
+        actions
+    }
+
+    fn drop_claims(&mut self, hostname: &str, host_ports: &HashSet<HostPort>) {
+        for host_port in host_ports {
+            if let Some(claimants) = self.claimants.get_mut(host_port) {
+                claimants.retain(|h| h != hostname);
+                if claimants.is_empty() {
+                    self.claimants.remove(host_port);
+                }
+            }
+        }
+    }
+
+    pub fn update(
+        &mut self,
+        hostname: &str,
+        addrs: GuestAddrs,
+        forward_list: PortforwardList,
+    ) -> Vec<ForwardAction> {
+        let effective = match self.overrides.get(hostname) {
+            Some(overrides) => overrides.apply(forward_list.clone()),
+            None => forward_list.clone(),
+        };
+        let machine = MachineForwards {
+            addrs,
+            declared: forward_list,
+            ..self.expand(hostname, effective)
+        };
+        let new_ports = host_ports(&machine.forward_list);
+        let old_ports = self
+            .machines
+            .insert(hostname.to_string(), machine)
+            .map(|old| host_ports(&old.forward_list))
+            .unwrap_or_default();
+
+        self.drop_claims(hostname, &(&old_ports - &new_ports));
+        for host_port in new_ports.difference(&old_ports) {
+            self.claimants
+                .entry(*host_port)
+                .or_default()
+                .push(hostname.to_string());
+        }
+
+        self.reassign(&old_ports | &new_ports, hostname)
+    }
+
+    pub fn remove_machine(&mut self, hostname: &str) -> Vec<ForwardAction> {
+        let Some(old) = self.machines.remove(hostname) else {
+            return vec![];
+        };
+        let old_ports = host_ports(&old.forward_list);
+
+        self.allocations.retain(|key, _| key.0 != hostname);
+        self.overrides.remove(hostname);
+        self.drop_claims(hostname, &old_ports);
+        self.reassign(old_ports, hostname)
+    }
+
+    // Re-applies the last heartbeat of the machine with the changed overrides.
+    fn reapply(&mut self, hostname: &str) -> Vec<ForwardAction> {
+        let machine = &self.machines[hostname];
+        let (addrs, declared) = (machine.addrs, machine.declared.clone());
+
+        self.update(hostname, addrs, declared)
+    }
+
+    // None if the machine has not sent a heartbeat yet
+    pub fn add_forward(
+        &mut self,
+        hostname: &str,
+        forward: PortforwardSpec,
+    ) -> Option<Vec<ForwardAction>> {
+        if !self.machines.contains_key(hostname) {
+            return None;
+        }
+
+        let overrides = self.overrides.entry(hostname.to_string()).or_default();
+        for i in 0..forward.port_count.max(1) {
+            if let Some(guest_port) = forward.guest_port.checked_add(i) {
+                overrides.removed.remove(&(forward.protocol, guest_port));
+            }
+        }
+        if !overrides.added.has_elem(&forward) {
+            overrides.added.append_elem(forward);
+        }
+
+        Some(self.reapply(hostname))
+    }
+
+    // None if the machine has not sent a heartbeat yet
+    pub fn remove_forward(
+        &mut self,
+        hostname: &str,
+        protocol: PortforwardProtocol,
+        guest_port: u16,
+    ) -> Option<Vec<ForwardAction>> {
+        if !self.machines.contains_key(hostname) {
+            return None;
+        }
+
+        let overrides = self.overrides.entry(hostname.to_string()).or_default();
+        overrides.added.remove_guest_port(protocol, guest_port);
+        overrides.removed.insert((protocol, guest_port));
+
+        Some(self.reapply(hostname))
+    }
+
+    pub fn status(&self, hostname: &str) -> PortforwardStatus {
+        let Some(machine) = self.machines.get(hostname) else {
+            return PortforwardStatus::default();
+        };
+
+        PortforwardStatus {
+            conflicts: self.conflicts(hostname, machine),
+            allocated: machine.allocated.clone(),
+            unallocated: machine.unallocated.clone(),
+        }
+    }
+
+    fn is_active(&self, hostname: &str, host_port: &HostPort) -> bool {
+        self.owners.get(host_port).map(|o| o.as_str()) == Some(hostname)
+            || self.pool_members(host_port).iter().any(|h| *h == hostname)
+    }
+
+    // the forwards of the machine which are not active because another machine owns the host port
+    fn conflicts(&self, hostname: &str, machine: &MachineForwards) -> Vec<PortforwardConflict> {
+        machine
+            .forward_list
+            .forwards
+            .iter()
+            .filter_map(|forward| {
+                let host_port = (forward.protocol, forward.host_port);
+                let owner = self.owners.get(&host_port);
+                (!self.is_active(hostname, &host_port)).then(|| PortforwardConflict {
+                    forward: forward.clone(),
+                    owner: owner.cloned(),
+                })
+            })
+            .collect()
+    }
+
+    pub fn owner(&self, protocol: PortforwardProtocol, host_port: u16) -> Option<&str> {
+        self.owners.get(&(protocol, host_port)).map(|o| o.as_str())
+    }
+
+    // the member of a pool at the address, or the owner of the host port
+    pub fn hostname_at(
+        &self,
+        protocol: PortforwardProtocol,
+        host_port: u16,
+        guest_addr: Option<SocketAddr>,
+    ) -> Option<&str> {
+        let host_port = (protocol, host_port);
+        let member = guest_addr.and_then(|guest_addr| {
+            self.pool_members(&host_port).into_iter().find(|hostname| {
+                let addrs = self.machines[*hostname].addrs;
+                match guest_addr.ip().to_canonical() {
+                    IpAddr::V4(ip) => addrs.ipv4 == Some(ip),
+                    IpAddr::V6(ip) => addrs.ipv6.is_some_and(|addr| *addr.ip() == ip),
+                }
+            })
+        });
+
+        member
+            .map(|hostname| hostname.as_str())
+            .or_else(|| self.owner(protocol, host_port.1))
+    }
+
+    pub fn entries(&self) -> Vec<PortforwardEntry> {
+        let mut entries: Vec<_> = self
+            .machines
+            .iter()
+            .flat_map(|(hostname, machine)| {
+                machine
+                    .forward_list
+                    .forwards
+                    .iter()
+                    .map(|forward| PortforwardEntry {
+                        hostname: hostname.clone(),
+                        forward: forward.clone(),
+                        active: self.is_active(hostname, &(forward.protocol, forward.host_port)),
+                        counters: None,
+                    })
+            })
+            .collect();
+        entries.sort_by_key(|e| {
+            (
+                e.forward.host_port,
+                e.forward.protocol as u8,
+                !e.active,
+                e.hostname.clone(),
+            )
+        });
+
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn addrs(last: u8) -> GuestAddrs {
+        GuestAddrs {
+            ipv4: Some(Ipv4Addr::new(192, 168, 2, last)),
+            ipv6: None,
+        }
+    }
+
+    fn forwards(ports: &[(u16, u16)]) -> PortforwardList {
+        PortforwardList::new(
+            ports
+                .iter()
+                .map(|(host_port, guest_port)| {
+                    PortforwardSpec::new(*host_port, *guest_port, PortforwardProtocol::Tcp)
+                })
+                .collect(),
+        )
+    }
+
+    // starts at a port given by the kernel, so that tests running in parallel don't share a fixed range
+    fn free_ports(count: u16) -> RangeInclusive<u16> {
+        loop {
+            let first = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0))
+                .and_then(|listener| listener.local_addr())
+                .unwrap()
+                .port();
+            let Some(last) = first.checked_add(count - 1) else {
+                continue;
+            };
+            if (first..=last).all(|port| is_bindable(PortforwardProtocol::Tcp, None, port)) {
+                return first..=last;
+            }
+        }
+    }
+
+    fn table(policy: ConflictPolicy) -> ForwardTable {
+        ForwardTable::new(policy, HashMap::new(), free_ports(10))
+    }
+
+    fn removed(actions: &[ForwardAction]) -> Vec<u16> {
+        let mut ports: Vec<_> = actions
+            .iter()
+            .filter_map(|action| match action {
+                ForwardAction::Remove { src_port, .. } => Some(*src_port),
+                _ => None,
+            })
+            .collect();
+        ports.sort();
+
+        ports
+    }
+
+    // (hostname, host port) of the rules pointed at a machine
+    fn updated(actions: &[ForwardAction]) -> Vec<(String, u16)> {
+        let mut rules: Vec<_> = actions
+            .iter()
+            .filter_map(|action| match action {
+                ForwardAction::Update {
+                    hostname, forward, ..
+                } => Some((hostname.clone(), forward.host_port)),
+                _ => None,
+            })
+            .collect();
+        rules.sort();
+
+        rules
+    }
+
+    #[test]
+    fn forward_dropped_from_heartbeat_is_removed() {
+        let mut table = table(ConflictPolicy::FirstWins);
+        let actions = table.update("vm1", addrs(10), forwards(&[(8080, 80), (8081, 81)]));
+        assert_eq!(
+            updated(&actions),
+            [("vm1".to_string(), 8080), ("vm1".to_string(), 8081)]
+        );
+
+        let actions = table.update("vm1", addrs(10), forwards(&[(8080, 80)]));
+        assert_eq!(removed(&actions), [8081]);
+        assert_eq!(updated(&actions), [("vm1".to_string(), 8080)]);
+        assert_eq!(table.owner(PortforwardProtocol::Tcp, 8081), None);
+    }
+
+    #[test]
+    fn forwards_of_expired_machine_are_removed() {
+        let mut table = table(ConflictPolicy::FirstWins);
+        table.update("vm1", addrs(10), forwards(&[(8080, 80), (8081, 81)]));
+
+        assert_eq!(removed(&table.remove_machine("vm1")), [8080, 8081]);
+        assert!(table.entries().is_empty());
+        assert!(table.remove_machine("vm1").is_empty());
+    }
+
+    fn owner(table: &ForwardTable, host_port: u16) -> Option<&str> {
+        table.owner(PortforwardProtocol::Tcp, host_port)
+    }
+
+    #[test]
+    fn first_claimant_wins_until_it_drops_the_forward() {
+        let mut table = table(ConflictPolicy::FirstWins);
+        table.update("vm1", addrs(10), forwards(&[(8080, 80)]));
+
+        let actions = table.update("vm2", addrs(11), forwards(&[(8080, 8000)]));
+        assert!(actions.is_empty());
+        assert_eq!(owner(&table, 8080), Some("vm1"));
+        let conflicts = table.status("vm2").conflicts;
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].owner.as_deref(), Some("vm1"));
+        assert!(table.status("vm1").conflicts.is_empty());
+
+        let actions = table.update("vm1", addrs(10), forwards(&[]));
+        assert_eq!(updated(&actions), [("vm2".to_string(), 8080)]);
+        assert!(removed(&actions).is_empty());
+        assert!(table.status("vm2").conflicts.is_empty());
+    }
+
+    #[test]
+    fn port_of_expired_owner_goes_to_next_claimant() {
+        let mut table = table(ConflictPolicy::FirstWins);
+        table.update("vm1", addrs(10), forwards(&[(8080, 80)]));
+        table.update("vm2", addrs(11), forwards(&[(8080, 80)]));
+        table.update("vm3", addrs(12), forwards(&[(8080, 80)]));
+
+        let actions = table.remove_machine("vm1");
+        assert_eq!(updated(&actions), [("vm2".to_string(), 8080)]);
+        let actions = table.remove_machine("vm2");
+        assert_eq!(updated(&actions), [("vm3".to_string(), 8080)]);
+        assert_eq!(removed(&table.remove_machine("vm3")), [8080]);
+    }
+
+    #[test]
+    fn contended_port_is_forwarded_to_none_with_reject() {
+        let mut table = table(ConflictPolicy::Reject);
+        table.update("vm1", addrs(10), forwards(&[(8080, 80), (8081, 81)]));
+
+        let actions = table.update("vm2", addrs(11), forwards(&[(8080, 80)]));
+        assert_eq!(removed(&actions), [8080]);
+        assert_eq!(owner(&table, 8080), None);
+        assert_eq!(owner(&table, 8081), Some("vm1"));
+        assert_eq!(table.status("vm1").conflicts.len(), 1);
+        assert_eq!(table.status("vm2").conflicts.len(), 1);
+
+        let actions = table.remove_machine("vm2");
+        assert_eq!(updated(&actions), [("vm1".to_string(), 8080)]);
+        assert!(table.status("vm1").conflicts.is_empty());
+    }
+
+    #[test]
+    fn higher_priority_takes_over_with_priority() {
+        let priorities = HashMap::from([("vm2".to_string(), 10)]);
+        let mut table = ForwardTable::new(ConflictPolicy::Priority, priorities, free_ports(10));
+        table.update("vm1", addrs(10), forwards(&[(8080, 80)]));
+
+        let actions = table.update("vm2", addrs(11), forwards(&[(8080, 80)]));
+        assert_eq!(updated(&actions), [("vm2".to_string(), 8080)]);
+        assert_eq!(owner(&table, 8080), Some("vm2"));
+
+        // heartbeats of the lower one don't take it back
+        let actions = table.update("vm1", addrs(10), forwards(&[(8080, 80)]));
+        assert!(actions.is_empty());
+
+        let actions = table.remove_machine("vm2");
+        assert_eq!(updated(&actions), [("vm1".to_string(), 8080)]);
+    }
+
+    #[test]
+    fn priority_ties_are_broken_by_first_claim() {
+        let priorities = HashMap::from([("vm1".to_string(), 5), ("vm2".to_string(), 5)]);
+        let mut table = ForwardTable::new(ConflictPolicy::Priority, priorities, free_ports(10));
+        table.update("vm2", addrs(11), forwards(&[(8080, 80)]));
+        table.update("vm1", addrs(10), forwards(&[(8080, 80)]));
+        assert_eq!(owner(&table, 8080), Some("vm2"));
+
+        // unlisted machines have priority 0
+        table.update("vm3", addrs(12), forwards(&[(9090, 90)]));
+        table.update("vm4", addrs(13), forwards(&[(9090, 90)]));
+        assert_eq!(owner(&table, 9090), Some("vm3"));
+    }
+
+    #[test]
+    fn owner_heartbeat_follows_its_address() {
+        let mut table = table(ConflictPolicy::FirstWins);
+        table.update("vm1", addrs(10), forwards(&[(8080, 80)]));
+
+        let actions = table.update("vm1", addrs(20), forwards(&[(8080, 80)]));
+        assert!(matches!(
+            &actions[..],
+            [ForwardAction::Update { dst, .. }] if *dst == addrs(20)
+        ));
+    }
+
+    #[test]
+    fn port_range_is_expanded_to_single_ports() {
+        let mut table = table(ConflictPolicy::FirstWins);
+        let mut range = forwards(&[(9000, 80)]);
+        range.forwards[0].port_count = 3;
+
+        let actions = table.update("vm1", addrs(10), range);
+        let mut rules: Vec<_> = actions
+            .iter()
+            .filter_map(|action| match action {
+                ForwardAction::Update { forward, .. } => {
+                    Some((forward.host_port, forward.guest_port, forward.port_count))
+                }
+                _ => None,
+            })
+            .collect();
+        rules.sort();
+        assert_eq!(rules, [(9000, 80, 1), (9001, 81, 1), (9002, 82, 1)]);
+
+        assert_eq!(removed(&table.remove_machine("vm1")), [9000, 9001, 9002]);
+    }
+
+    fn allocated(table: &ForwardTable, hostname: &str) -> Vec<(u16, u16)> {
+        table
+            .status(hostname)
+            .allocated
+            .iter()
+            .map(|forward| (forward.guest_port, forward.host_port))
+            .collect()
+    }
+
+    #[test]
+    fn allocated_port_is_kept_across_heartbeats() {
+        let ports = free_ports(10);
+        let mut table = ForwardTable::new(ConflictPolicy::FirstWins, HashMap::new(), ports.clone());
+        table.update("vm1", addrs(10), forwards(&[(0, 80), (0, 81)]));
+        let first = allocated(&table, "vm1");
+        assert_eq!(first.len(), 2);
+        assert!(first.iter().all(|(_, host_port)| ports.contains(host_port)));
+        assert_ne!(first[0].1, first[1].1);
+
+        let actions = table.update("vm1", addrs(10), forwards(&[(0, 80), (0, 81)]));
+        assert!(removed(&actions).is_empty());
+        assert_eq!(allocated(&table, "vm1"), first);
+
+        // the same guest port of another machine gets a port of its own
+        table.update("vm2", addrs(11), forwards(&[(0, 80)]));
+        let other = allocated(&table, "vm2");
+        assert_eq!(other.len(), 1);
+        assert!(first.iter().all(|(_, host_port)| *host_port != other[0].1));
+        assert!(table.status("vm2").conflicts.is_empty());
+    }
+
+    #[test]
+    fn exhausted_auto_ports_are_reported_unallocated() {
+        let mut table = ForwardTable::new(ConflictPolicy::FirstWins, HashMap::new(), free_ports(2));
+        table.update("vm1", addrs(10), forwards(&[(0, 80), (0, 81), (0, 82)]));
+
+        let status = table.status("vm1");
+        assert_eq!(status.allocated.len() + status.unallocated.len(), 3);
+        // ports are allocated in the declared order, so the last one is always left out
+        assert_eq!(
+            status.unallocated.last().map(|forward| forward.guest_port),
+            Some(82)
+        );
+        assert!(status
+            .unallocated
+            .iter()
+            .all(|forward| forward.host_port == 0));
+    }
+}
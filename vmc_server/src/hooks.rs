@@ -0,0 +1,214 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::io::prelude::*;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::{Duration, Instant};
+use vmc_common::types::MachineInfo;
+
+const HOOK_POST_TIMEOUT: Duration = Duration::from_secs(5);
+// hooks run one after another, so a hung command would hold back every later event
+const HOOK_COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+const HOOK_COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistryEvent {
+    Registered,
+    AddressChanged,
+    Stale,
+    Deregistered,
+}
+
+impl RegistryEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RegistryEvent::Registered => "registered",
+            RegistryEvent::AddressChanged => "address_changed",
+            RegistryEvent::Stale => "stale",
+            RegistryEvent::Deregistered => "deregistered",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct RegistryChange {
+    pub event: RegistryEvent,
+    pub hostname: String,
+    pub old: Option<MachineInfo>,
+    pub new: Option<MachineInfo>,
+}
+
+impl RegistryChange {
+    pub fn new(
+        event: RegistryEvent,
+        hostname: &str,
+        old: Option<MachineInfo>,
+        new: Option<MachineInfo>,
+    ) -> Self {
+        Self {
+            event,
+            hostname: hostname.to_string(),
+            old,
+            new,
+        }
+    }
+}
+
+/*
+ * "command": ["/usr/local/bin/update-lb", "--reload"]
+ *   the change is passed as JSON on stdin, and as VMC_* env vars
+ * "post": "127.0.0.1:8080/vmc-hook" or "unix:/run/vmc-hook.sock/vmc-hook"
+ *   the change is sent as the JSON body of an HTTP/1.1 POST request
+ */
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum HookAction {
+    Command(Vec<String>),
+    Post(String),
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct HookSpec {
+    // empty means all events
+    #[serde(default)]
+    pub events: Vec<RegistryEvent>,
+    #[serde(flatten)]
+    pub action: HookAction,
+}
+
+impl HookSpec {
+    fn is_subscribed(&self, event: RegistryEvent) -> bool {
+        self.events.is_empty() || self.events.contains(&event)
+    }
+}
+
+fn run_command_hook(args: &[String], change: &RegistryChange, payload: &str) -> Result<(), String> {
+    let (program, args) = args
+        .split_first()
+        .ok_or_else(|| "empty command".to_string())?;
+    let to_json = |mi: &Option<MachineInfo>| {
+        mi.as_ref()
+            .map(|mi| serde_json::to_string(mi).unwrap())
+            .unwrap_or_default()
+    };
+
+    let mut child = Command::new(program)
+        .args(args)
+        .env("VMC_EVENT", change.event.as_str())
+        .env("VMC_HOSTNAME", &change.hostname)
+        .env("VMC_OLD_MACHINE_INFO", to_json(&change.old))
+        .env("VMC_NEW_MACHINE_INFO", to_json(&change.new))
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("failed to spawn {program}: {e}"))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // the hook may not read stdin at all
+        let _ = stdin.write_all(payload.as_bytes());
+    }
+
+    let deadline = Instant::now() + HOOK_COMMAND_TIMEOUT;
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(|e| e.to_string())? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!(
+                "{program} was killed after {} secs",
+                HOOK_COMMAND_TIMEOUT.as_secs()
+            ));
+        }
+        thread::sleep(HOOK_COMMAND_POLL_INTERVAL);
+    };
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("{program} exited with {status}"))
+    }
+}
+
+fn send_post_request<T>(stream: &mut T, host: &str, path: &str, payload: &str) -> Result<(), String>
+where
+    T: Read + Write,
+{
+    let req = format!(
+        "POST {path} HTTP/1.1\r\nHost: {host}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{payload}",
+        payload.len()
+    );
+    stream
+        .write_all(req.as_bytes())
+        .map_err(|e| e.to_string())?;
+
+    let mut res = String::new();
+    let _ = stream.read_to_string(&mut res);
+    let status_line = res.lines().next().unwrap_or_default();
+
+    match status_line.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(format!("unexpected response: {status_line:?}")),
+    }
+}
+
+fn run_post_hook(target: &str, payload: &str) -> Result<(), String> {
+    #[cfg(unix)]
+    if let Some(target) = target.strip_prefix("unix:") {
+        // the socket path ends where the request path begins: unix:/run/hook.sock/path
+        let idx = target
+            .find(".sock")
+            .map(|idx| idx + ".sock".len())
+            .unwrap_or(target.len());
+        let (sock_path, path) = target.split_at(idx);
+        let path = if path.is_empty() { "/" } else { path };
+
+        let mut stream = std::os::unix::net::UnixStream::connect(sock_path)
+            .map_err(|e| format!("failed to connect to {sock_path}: {e}"))?;
+        stream
+            .set_read_timeout(Some(HOOK_POST_TIMEOUT))
+            .map_err(|e| e.to_string())?;
+
+        return send_post_request(&mut stream, "localhost", path, payload);
+    }
+
+    let (host, path) = match target.find('/') {
+        Some(idx) => target.split_at(idx),
+        None => (target, "/"),
+    };
+    let addr = host
+        .to_socket_addrs()
+        .map_err(|e| format!("failed to resolve {host}: {e}"))?
+        .next()
+        .ok_or_else(|| format!("failed to resolve {host}"))?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, HOOK_POST_TIMEOUT)
+        .map_err(|e| format!("failed to connect to {host}: {e}"))?;
+    stream
+        .set_read_timeout(Some(HOOK_POST_TIMEOUT))
+        .map_err(|e| e.to_string())?;
+
+    send_post_request(&mut stream, host, path, payload)
+}
+
+pub fn start_hook_service(hooks: Vec<HookSpec>, recv: Receiver<RegistryChange>) {
+    thread::spawn(move || loop {
+        let change = recv.recv().expect("failed to unwrap RegistryChange");
+        info!("[Hook Service] {:?} @ {}", change.event, change.hostname);
+
+        let payload = serde_json::to_string(&change).expect("failed to serialize RegistryChange");
+
+        for hook in hooks.iter().filter(|hook| hook.is_subscribed(change.event)) {
+            let result = match &hook.action {
+                HookAction::Command(args) => run_command_hook(args, &change, &payload),
+                HookAction::Post(target) => run_post_hook(target, &payload),
+            };
+
+            if let Err(e) = result {
+                warn!("[Hook Service] hook {:?} failed: {e}", hook.action);
+            }
+        }
+    });
+}
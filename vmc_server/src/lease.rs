@@ -0,0 +1,399 @@
+use chrono::NaiveDateTime;
+use log::{info, warn};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use vmc_common::types::MachineInfo;
+
+use crate::hooks::RegistryChange;
+use crate::machine_map::MachineMap;
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum LeaseFormat {
+    // /var/lib/misc/dnsmasq.leases
+    Dnsmasq,
+    // /var/lib/dhcp/dhcpd.leases
+    Isc,
+    // /var/lib/libvirt/dnsmasq/<bridge>.status
+    LibvirtStatus,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct LeaseSource {
+    pub path: String,
+    pub format: LeaseFormat,
+}
+
+#[derive(Debug, Clone)]
+struct Lease {
+    hostname: String,
+    ip_addr: String,
+    // unix time, None means infinite
+    expiry: Option<u64>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// <expiry> <mac> <ip> <hostname> <client-id>
+fn parse_dnsmasq(content: &str) -> Vec<Lease> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            if fields.len() < 4 || fields[3] == "*" {
+                return None;
+            }
+            let expiry = fields[0].parse::<u64>().ok()?;
+
+            Some(Lease {
+                hostname: fields[3].to_string(),
+                ip_addr: fields[2].to_string(),
+                expiry: (expiry != 0).then_some(expiry),
+            })
+        })
+        .collect()
+}
+
+#[derive(Default)]
+struct IscBlock {
+    ip_addr: String,
+    mac: Option<String>,
+    hostname: Option<String>,
+    expiry: Option<u64>,
+    active: bool,
+    // a block with an unreadable end is dropped rather than taken as infinite
+    malformed: bool,
+}
+
+fn parse_isc(content: &str) -> Vec<Lease> {
+    // later declarations of the same address or the same client supersede earlier ones
+    let mut leases = HashMap::new();
+    let mut addr_of_mac = HashMap::new();
+    let mut current: Option<IscBlock> = None;
+
+    for line in content.lines() {
+        let line = line.trim().trim_end_matches(';');
+
+        if let Some(rest) = line.strip_prefix("lease ") {
+            current = Some(IscBlock {
+                ip_addr: rest.trim_end_matches('{').trim().to_string(),
+                active: true,
+                ..Default::default()
+            });
+        } else if line == "}" {
+            let Some(block) = current.take() else {
+                continue;
+            };
+            leases.remove(&block.ip_addr);
+            if let Some(mac) = block.mac {
+                if let Some(old) = addr_of_mac.insert(mac, block.ip_addr.clone()) {
+                    leases.remove(&old);
+                }
+            }
+            if let (Some(hostname), true, false) = (block.hostname, block.active, block.malformed) {
+                leases.insert(
+                    block.ip_addr.clone(),
+                    Lease {
+                        hostname,
+                        ip_addr: block.ip_addr,
+                        expiry: block.expiry,
+                    },
+                );
+            }
+        } else if let Some(block) = current.as_mut() {
+            if let Some(name) = line.strip_prefix("client-hostname ") {
+                block.hostname = Some(name.trim_matches('"').to_string());
+            } else if let Some(mac) = line.strip_prefix("hardware ethernet ") {
+                block.mac = Some(mac.to_ascii_lowercase());
+            } else if let Some(ends) = line.strip_prefix("ends ") {
+                // ends <weekday> <yyyy/mm/dd> <hh:mm:ss> (UTC) or ends never
+                if ends != "never" {
+                    block.expiry = ends.split_once(' ').and_then(|(_, date)| {
+                        NaiveDateTime::parse_from_str(date, "%Y/%m/%d %H:%M:%S")
+                            .ok()
+                            .map(|t| t.timestamp() as u64)
+                    });
+                    block.malformed = block.expiry.is_none();
+                }
+            } else if let Some(state) = line.strip_prefix("binding state ") {
+                block.active = state == "active";
+            }
+        }
+    }
+
+    leases.into_values().collect()
+}
+
+#[derive(Debug, Deserialize)]
+struct LibvirtStatusEntry {
+    #[serde(rename = "ip-address")]
+    ip_address: String,
+    hostname: Option<String>,
+    #[serde(rename = "expiry-time")]
+    expiry_time: Option<u64>,
+}
+
+fn parse_libvirt_status(content: &str) -> Vec<Lease> {
+    if content.trim().is_empty() {
+        return vec![];
+    }
+
+    match serde_json::from_str::<Vec<LibvirtStatusEntry>>(content) {
+        Ok(entries) => entries
+            .into_iter()
+            .filter_map(|e| {
+                Some(Lease {
+                    hostname: e.hostname?,
+                    ip_addr: e.ip_address,
+                    expiry: e.expiry_time.filter(|t| *t != 0),
+                })
+            })
+            .collect(),
+        Err(e) => {
+            warn!("[Lease Watcher] failed to parse libvirt status: {e}");
+            vec![]
+        }
+    }
+}
+
+fn parse_leases(format: LeaseFormat, content: &str) -> Vec<Lease> {
+    match format {
+        LeaseFormat::Dnsmasq => parse_dnsmasq(content),
+        LeaseFormat::Isc => parse_isc(content),
+        LeaseFormat::LibvirtStatus => parse_libvirt_status(content),
+    }
+}
+
+// merges v4 and v6 leases of the same host, hosts without any v4 lease can't be registered
+fn to_machine_infos(leases: &[Lease]) -> Vec<MachineInfo> {
+    let now = now();
+    let mut machines = HashMap::<String, (Option<String>, Option<String>)>::new();
+
+    for lease in leases.iter().filter(|l| l.expiry.is_none_or(|t| t > now)) {
+        let (ipv4_addr, ipv6_addr) = machines.entry(lease.hostname.clone()).or_default();
+        if lease.ip_addr.contains(':') {
+            *ipv6_addr = Some(lease.ip_addr.clone());
+        } else {
+            *ipv4_addr = Some(lease.ip_addr.clone());
+        }
+    }
+
+    machines
+        .into_iter()
+        .filter_map(|(hostname, (ipv4_addr, ipv6_addr))| {
+            Some(MachineInfo {
+                hostname,
+                ipv4_addr: ipv4_addr?,
+                ipv6_addr,
+            })
+        })
+        .collect()
+}
+
+pub fn start_lease_watcher(
+    sources: Vec<LeaseSource>,
+    poll_interval: Duration,
+    mmap: Arc<Mutex<MachineMap>>,
+    hook_req: Sender<RegistryChange>,
+) {
+    if sources.is_empty() {
+        return;
+    }
+
+    thread::spawn(move || {
+        let mut parsed = HashMap::<String, (SystemTime, Vec<Lease>)>::new();
+
+        loop {
+            for source in sources.iter() {
+                let Ok(modified) = fs::metadata(&source.path).and_then(|m| m.modified()) else {
+                    parsed.remove(&source.path);
+                    continue;
+                };
+
+                if parsed.get(&source.path).map(|(t, _)| *t) != Some(modified) {
+                    match fs::read_to_string(&source.path) {
+                        Ok(content) => {
+                            let leases = parse_leases(source.format, &content);
+                            info!(
+                                "[Lease Watcher] {} leases loaded from {}",
+                                leases.len(),
+                                source.path
+                            );
+                            parsed.insert(source.path.clone(), (modified, leases));
+                        }
+                        Err(e) => warn!("[Lease Watcher] failed to read {}: {e}", source.path),
+                    }
+                }
+            }
+
+            // re-applied every time to keep the entries alive while their leases are valid
+            let leases: Vec<_> = parsed.values().flat_map(|(_, l)| l.clone()).collect();
+            let changes: Vec<_> = {
+                let mut mmap = mmap.lock().unwrap();
+                to_machine_infos(&leases)
+                    .iter()
+                    .filter_map(|mi| mmap.update_from_lease(mi))
+                    .collect()
+            };
+
+            for change in changes {
+                info!(
+                    "MachineInfo {:?} (lease) : {}",
+                    change.event, change.hostname
+                );
+                hook_req
+                    .send(change)
+                    .expect("failed to send RegistryChange");
+            }
+
+            thread::sleep(poll_interval);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(mut leases: Vec<Lease>) -> Vec<(String, String, Option<u64>)> {
+        leases.sort_by(|a, b| a.ip_addr.cmp(&b.ip_addr));
+        leases
+            .into_iter()
+            .map(|l| (l.hostname, l.ip_addr, l.expiry))
+            .collect()
+    }
+
+    fn lease(hostname: &str, ip_addr: &str, expiry: Option<u64>) -> (String, String, Option<u64>) {
+        (hostname.to_string(), ip_addr.to_string(), expiry)
+    }
+
+    #[test]
+    fn dnsmasq_skips_unnamed_and_malformed_lines() {
+        let content = "\
+1700000000 52:54:00:00:00:01 192.168.122.10 alpha 01:52:54:00:00:00:01
+0 52:54:00:00:00:02 192.168.122.11 beta *
+1700000000 52:54:00:00:00:03 192.168.122.12 * *
+garbage
+soon 52:54:00:00:00:04 192.168.122.13 gamma *
+1700000000 52:54:00:00:00:05 fd00::14 alpha *
+";
+        assert_eq!(
+            sorted(parse_dnsmasq(content)),
+            vec![
+                lease("alpha", "192.168.122.10", Some(1700000000)),
+                lease("beta", "192.168.122.11", None),
+                lease("alpha", "fd00::14", Some(1700000000)),
+            ]
+        );
+    }
+
+    #[test]
+    fn isc_keeps_the_latest_active_block() {
+        let content = r#"
+# The format of this file is documented in the dhcpd.leases(5) manual page.
+lease 10.0.0.10 {
+  starts 1 2023/11/13 10:00:00;
+  ends 1 2023/11/13 22:00:00;
+  binding state active;
+  hardware ethernet 52:54:00:00:00:01;
+  client-hostname "alpha";
+}
+lease 10.0.0.11 {
+  ends never;
+  binding state active;
+  client-hostname "beta";
+}
+lease 10.0.0.12 {
+  ends 1 2023/11/13 22:00:00;
+  binding state active;
+  client-hostname "gamma";
+}
+lease 10.0.0.12 {
+  ends 1 2023/11/13 23:00:00;
+  binding state free;
+}
+lease 10.0.0.13 {
+  ends 1 2023/11/13 22:00:00;
+  binding state active;
+  hardware ethernet 52:54:00:00:00:01;
+  client-hostname "alpha";
+}
+lease 10.0.0.14 {
+  ends sometime;
+  binding state active;
+  client-hostname "delta";
+}
+"#;
+        assert_eq!(
+            sorted(parse_isc(content)),
+            vec![
+                lease("beta", "10.0.0.11", None),
+                lease("alpha", "10.0.0.13", Some(1699912800)),
+            ]
+        );
+    }
+
+    #[test]
+    fn libvirt_status_skips_unnamed_entries() {
+        let content = r#"[
+  { "ip-address": "192.168.122.10", "mac-address": "52:54:00:00:00:01", "hostname": "alpha", "expiry-time": 1700000000 },
+  { "ip-address": "192.168.122.11", "mac-address": "52:54:00:00:00:02", "expiry-time": 1700000000 },
+  { "ip-address": "192.168.122.12", "mac-address": "52:54:00:00:00:03", "hostname": "beta", "expiry-time": 0 }
+]"#;
+        assert_eq!(
+            sorted(parse_libvirt_status(content)),
+            vec![
+                lease("alpha", "192.168.122.10", Some(1700000000)),
+                lease("beta", "192.168.122.12", None),
+            ]
+        );
+        assert!(parse_libvirt_status("").is_empty());
+        assert!(parse_libvirt_status("[{").is_empty());
+    }
+
+    #[test]
+    fn expired_and_v6_only_hosts_are_not_registered() {
+        let future = now() + 3600;
+        let leases = [
+            ("alpha", "192.168.122.10", Some(future)),
+            ("alpha", "fd00::10", None),
+            ("beta", "192.168.122.11", Some(1)),
+            ("gamma", "fd00::12", None),
+            ("delta", "192.168.122.13", Some(1)),
+            ("delta", "192.168.122.14", None),
+        ]
+        .map(|(hostname, ip_addr, expiry)| Lease {
+            hostname: hostname.to_string(),
+            ip_addr: ip_addr.to_string(),
+            expiry,
+        });
+
+        let mut machines = to_machine_infos(&leases);
+        machines.sort_by(|a, b| a.hostname.cmp(&b.hostname));
+        let machines: Vec<_> = machines
+            .into_iter()
+            .map(|mi| (mi.hostname, mi.ipv4_addr, mi.ipv6_addr))
+            .collect();
+        assert_eq!(
+            machines,
+            vec![
+                (
+                    "alpha".to_string(),
+                    "192.168.122.10".to_string(),
+                    Some("fd00::10".to_string())
+                ),
+                ("delta".to_string(), "192.168.122.14".to_string(), None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,8 @@
+pub mod config;
+pub mod forward_table;
+pub mod hooks;
+pub mod lease;
+pub mod machine_map;
+pub mod port_forward;
+pub mod proxy;
+pub mod tunnel;
@@ -0,0 +1,264 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use vmc_common::types::{MachineInfo, MachineStats};
+
+use crate::hooks::{RegistryChange, RegistryEvent};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IpAddrPair {
+    pub ipv4_addr: String,
+    pub ipv6_addr: Option<String>,
+}
+
+impl IpAddrPair {
+    pub fn new(ipv4_addr: String, ipv6_addr: Option<String>) -> Self {
+        Self {
+            ipv4_addr,
+            ipv6_addr,
+        }
+    }
+
+    pub fn to_machine_info(&self, hostname: &str) -> MachineInfo {
+        MachineInfo {
+            hostname: hostname.to_string(),
+            ipv4_addr: self.ipv4_addr.clone(),
+            ipv6_addr: self.ipv6_addr.clone(),
+        }
+    }
+}
+
+// heartbeats of vmc_ip_reporter take priority over DHCP leases
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSource {
+    Heartbeat,
+    Lease,
+}
+
+#[derive(Debug)]
+pub struct MachineEntry {
+    pub ipaddr_pair: IpAddrPair,
+    pub source: AddressSource,
+    pub last_seen: Instant,
+    pub stale: bool,
+    pub stats_history: VecDeque<MachineStats>,
+}
+
+#[derive(Debug)]
+pub struct MachineMap {
+    map: HashMap<String, MachineEntry>,
+    stats_history_len: usize,
+}
+
+impl MachineMap {
+    pub fn new(stats_history_len: usize) -> Self {
+        Self {
+            map: HashMap::new(),
+            stats_history_len,
+        }
+    }
+
+    // Records a heartbeat and returns the registry change caused by it, if any.
+    // A machine coming back from the stale state is reported as registered again.
+    pub fn update(&mut self, mi: &MachineInfo) -> Option<RegistryChange> {
+        self.update_from(mi, AddressSource::Heartbeat)
+    }
+
+    // Leases never override a machine which is alive by heartbeats.
+    pub fn update_from_lease(&mut self, mi: &MachineInfo) -> Option<RegistryChange> {
+        if let Some(entry) = self.map.get(&mi.hostname) {
+            if entry.source == AddressSource::Heartbeat && !entry.stale {
+                return None;
+            }
+        }
+
+        self.update_from(mi, AddressSource::Lease)
+    }
+
+    fn update_from(&mut self, mi: &MachineInfo, source: AddressSource) -> Option<RegistryChange> {
+        let ipaddr_pair = IpAddrPair::new(mi.ipv4_addr.clone(), mi.ipv6_addr.clone());
+        let new = Some(mi.clone());
+
+        match self.map.get_mut(&mi.hostname) {
+            Some(entry) => {
+                let old = Some(entry.ipaddr_pair.to_machine_info(&mi.hostname));
+                let was_stale = entry.stale;
+                let changed = entry.ipaddr_pair != ipaddr_pair;
+
+                entry.ipaddr_pair = ipaddr_pair;
+                entry.source = source;
+                entry.last_seen = Instant::now();
+                entry.stale = false;
+
+                if changed {
+                    Some(RegistryChange::new(
+                        RegistryEvent::AddressChanged,
+                        &mi.hostname,
+                        old,
+                        new,
+                    ))
+                } else if was_stale {
+                    Some(RegistryChange::new(
+                        RegistryEvent::Registered,
+                        &mi.hostname,
+                        old,
+                        new,
+                    ))
+                } else {
+                    None
+                }
+            }
+            None => {
+                self.map.insert(
+                    mi.hostname.clone(),
+                    MachineEntry {
+                        ipaddr_pair,
+                        source,
+                        last_seen: Instant::now(),
+                        stale: false,
+                        stats_history: VecDeque::new(),
+                    },
+                );
+
+                Some(RegistryChange::new(
+                    RegistryEvent::Registered,
+                    &mi.hostname,
+                    None,
+                    new,
+                ))
+            }
+        }
+    }
+
+    // Machines silent for `stale_after` are marked as stale, and removed after `expire_after`.
+    pub fn sweep(&mut self, stale_after: Duration, expire_after: Duration) -> Vec<RegistryChange> {
+        let mut changes = vec![];
+        let now = Instant::now();
+
+        self.map.retain(|hostname, entry| {
+            let elapsed = now.duration_since(entry.last_seen);
+            let old = Some(entry.ipaddr_pair.to_machine_info(hostname));
+
+            if elapsed >= expire_after {
+                changes.push(RegistryChange::new(
+                    RegistryEvent::Deregistered,
+                    hostname,
+                    old,
+                    None,
+                ));
+                false
+            } else {
+                if elapsed >= stale_after && !entry.stale {
+                    entry.stale = true;
+                    changes.push(RegistryChange::new(
+                        RegistryEvent::Stale,
+                        hostname,
+                        old.clone(),
+                        old,
+                    ));
+                }
+                true
+            }
+        });
+
+        changes
+    }
+
+    pub fn push_stats(&mut self, hostname: &str, stats: MachineStats) {
+        if let Some(entry) = self.map.get_mut(hostname) {
+            if entry.stats_history.len() >= self.stats_history_len {
+                entry.stats_history.pop_front();
+            }
+            entry.stats_history.push_back(stats);
+        }
+    }
+
+    pub fn get_stats(&self, hostname: &str) -> Option<Vec<MachineStats>> {
+        self.map
+            .get(hostname)
+            .map(|entry| entry.stats_history.iter().cloned().collect())
+    }
+
+    pub fn get(&self, hostname: &str) -> Option<&IpAddrPair> {
+        self.map.get(hostname).map(|entry| &entry.ipaddr_pair)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &IpAddrPair)> {
+        self.map
+            .iter()
+            .map(|(hostname, entry)| (hostname, &entry.ipaddr_pair))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn machine(hostname: &str, ipv4_addr: &str) -> MachineInfo {
+        MachineInfo {
+            hostname: hostname.to_string(),
+            ipv4_addr: ipv4_addr.to_string(),
+            ipv6_addr: None,
+        }
+    }
+
+    fn events(changes: &[RegistryChange]) -> Vec<RegistryEvent> {
+        changes.iter().map(|change| change.event).collect()
+    }
+
+    #[test]
+    fn heartbeats_report_registration_and_address_changes() {
+        let mut mmap = MachineMap::new(1);
+
+        let change = mmap.update(&machine("vm1", "192.168.2.10")).unwrap();
+        assert_eq!(change.event, RegistryEvent::Registered);
+        assert!(change.old.is_none());
+        assert!(mmap.update(&machine("vm1", "192.168.2.10")).is_none());
+
+        let change = mmap.update(&machine("vm1", "192.168.2.11")).unwrap();
+        assert_eq!(change.event, RegistryEvent::AddressChanged);
+        assert_eq!(change.old.unwrap().ipv4_addr, "192.168.2.10");
+        assert_eq!(change.new.unwrap().ipv4_addr, "192.168.2.11");
+    }
+
+    #[test]
+    fn silent_machine_goes_stale_once_and_comes_back() {
+        let mut mmap = MachineMap::new(1);
+        mmap.update(&machine("vm1", "192.168.2.10"));
+
+        let changes = mmap.sweep(Duration::ZERO, Duration::MAX);
+        assert_eq!(events(&changes), [RegistryEvent::Stale]);
+        assert!(mmap.sweep(Duration::ZERO, Duration::MAX).is_empty());
+
+        let change = mmap.update(&machine("vm1", "192.168.2.10")).unwrap();
+        assert_eq!(change.event, RegistryEvent::Registered);
+    }
+
+    #[test]
+    fn expired_machine_is_deregistered() {
+        let mut mmap = MachineMap::new(1);
+        mmap.update(&machine("vm1", "192.168.2.10"));
+
+        let changes = mmap.sweep(Duration::ZERO, Duration::ZERO);
+        assert_eq!(events(&changes), [RegistryEvent::Deregistered]);
+        assert!(changes[0].new.is_none());
+        assert!(mmap.get("vm1").is_none());
+    }
+
+    #[test]
+    fn lease_yields_to_live_heartbeats() {
+        let mut mmap = MachineMap::new(1);
+        mmap.update(&machine("vm1", "192.168.2.10"));
+
+        assert!(mmap
+            .update_from_lease(&machine("vm1", "192.168.2.99"))
+            .is_none());
+        assert_eq!(mmap.get("vm1").unwrap().ipv4_addr, "192.168.2.10");
+
+        mmap.sweep(Duration::ZERO, Duration::MAX);
+        let change = mmap
+            .update_from_lease(&machine("vm1", "192.168.2.99"))
+            .unwrap();
+        assert_eq!(change.event, RegistryEvent::AddressChanged);
+    }
+}
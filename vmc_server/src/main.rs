@@ -1,66 +1,163 @@
-pub mod port_forward;
-
-use std::collections::HashSet;
 use std::io::prelude::*;
 use std::sync::mpsc::channel;
+use std::time::Duration;
 use std::{
-    collections::HashMap,
-    net::TcpListener,
+    net::{IpAddr, TcpListener},
+    path::{Component, Path, PathBuf},
     process::Command,
     sync::{Arc, Mutex},
     thread,
 };
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::oneshot;
 use vmc_common::protocol::calc_protocol_digest;
 use vmc_common::{
+    mux::Mux,
     protocol::{
-        CBRequest, CBResponse, ExecRequest, ExecResponse, NSRequest, NSResponse, NTFRequest,
-        Request, Response,
+        CBRequest, CBResponse, ChannelTarget, ExecRequest, ExecResponse, NSRequest, NSResponse,
+        NTFRequest, Request, Response, TunnelRequest, TunnelResponse,
     },
-    types::{MachineInfo, SerializedDataContainer},
+    relay::{relay, AnyStream},
+    types::{MachineInfo, PortforwardProtocol, SerializedDataContainer},
 };
 use winrt_notification::Toast;
 
-use log::info;
+use log::{info, warn};
+use socket2::{SockRef, TcpKeepalive};
 use std::env;
 
-use crate::port_forward::{spawn_pf_front_server, start_port_forward_service, PortforwardRequest};
-
-#[derive(Debug)]
-struct IpAddrPair {
-    pub ipv4_addr: String,
-    pub ipv6_addr: Option<String>,
-}
+use vmc_server::config::load_server_config;
+use vmc_server::forward_table::{ForwardAction, ForwardTable};
+use vmc_server::hooks::{start_hook_service, RegistryEvent};
+use vmc_server::lease::start_lease_watcher;
+use vmc_server::machine_map::MachineMap;
+use vmc_server::port_forward::{
+    start_port_forward_service, AccessList, GuestAddrs, PoolTarget, PortforwardRequest,
+    TcpSocketOptions,
+};
+use vmc_server::proxy::{start_proxy_service, start_reverse_proxy_service};
+use vmc_server::tunnel::{TunnelSlots, TunnelTarget};
 
-impl IpAddrPair {
-    pub fn new(ipv4_addr: String, ipv6_addr: Option<String>) -> Self {
-        Self {
-            ipv4_addr,
-            ipv6_addr,
-        }
-    }
-}
+static SERVER_ADDR: &str = "0.0.0.0:12345";
 
-#[derive(Debug, Default)]
-struct MachineMap {
-    map: HashMap<String, IpAddrPair>,
+// a session of a guest which is gone without closing it has to give up its slot
+const TUNNEL_KEEPALIVE: Duration = Duration::from_secs(30);
+
+// the tunnel of a hostname is attached only from the address registered for it
+fn is_registered_addr(mmap: &MachineMap, hostname: &str, peer: IpAddr) -> bool {
+    let peer = peer.to_canonical();
+    mmap.get(hostname).is_some_and(|pair| {
+        std::iter::once(&pair.ipv4_addr)
+            .chain(&pair.ipv6_addr)
+            .any(|addr| addr.parse::<IpAddr>().is_ok_and(|addr| addr == peer))
+    })
 }
 
-impl MachineMap {
-    fn insert(&mut self, hostname: String, ipaddr_pair: IpAddrPair) {
-        self.map.insert(hostname, ipaddr_pair);
-    }
-
-    fn get(&self, hostname: &str) -> Option<&IpAddrPair> {
-        self.map.get(hostname)
+// names given by clients, like host_path of forwards, have to be plain file names in the dir
+fn path_in_dir(dir: Option<&Path>, name: &str) -> Option<PathBuf> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) => Some(dir?.join(name)),
+        _ => None,
     }
+}
 
-    fn iter(&self) -> std::collections::hash_map::Iter<String, IpAddrPair> {
-        self.map.iter()
+fn apply_forward_actions(
+    pf_req: &UnboundedSender<PortforwardRequest>,
+    tunnel_slots: &TunnelSlots,
+    socket_dir: Option<&Path>,
+    actions: Vec<ForwardAction>,
+) {
+    for action in actions {
+        let req = match action {
+            ForwardAction::Update {
+                hostname,
+                forward,
+                dst,
+                pool,
+            } => {
+                let host_path = match forward
+                    .host_path
+                    .as_deref()
+                    .filter(|_| forward.protocol == PortforwardProtocol::Tcp)
+                {
+                    Some(name) => match path_in_dir(socket_dir, name) {
+                        Some(path) => Some(path),
+                        None => {
+                            warn!("Port Forward {}: host_path {name:?} is not a file name in forward_socket_dir, removed", forward.host_port);
+                            pf_req
+                                .send(PortforwardRequest::RemoveRoutingRule {
+                                    protocol: forward.protocol,
+                                    src_port: forward.host_port,
+                                })
+                                .expect("failed to send PortforwardRequest");
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+
+                PortforwardRequest::UpdateRoutingRule {
+                    protocol: forward.protocol,
+                    src_port: forward.host_port,
+                    dst_addrs: dst.socket_addrs(forward.guest_port, forward.ip_preference),
+                    bind_addr: forward.bind_addr,
+                    access: AccessList::parse(&forward.allow, &forward.deny).unwrap_or_else(|e| {
+                        warn!("Port Forward {}: {e}, deny all", forward.host_port);
+                        AccessList::deny_all()
+                    }),
+                    socket_options: TcpSocketOptions::from_spec(&forward),
+                    tunnel: match (&forward.guest_path, forward.protocol) {
+                        // a unix socket of the guest is reached only through the tunnel
+                        (Some(path), PortforwardProtocol::Tcp) => {
+                            Some(ChannelTarget::Path(path.clone()))
+                        }
+                        (None, PortforwardProtocol::Tcp) if forward.tunnel => {
+                            Some(ChannelTarget::Port(forward.guest_port))
+                        }
+                        _ => None,
+                    }
+                    .map(|target| TunnelTarget {
+                        slot: tunnel_slots.slot(&hostname),
+                        target,
+                    }),
+                    proxy_protocol: forward.proxy_protocol,
+                    host_path,
+                    // members are connected at their addresses, so a tunneled forward is never pooled
+                    pool: forward
+                        .pool
+                        .filter(|_| {
+                            forward.protocol == PortforwardProtocol::Tcp
+                                && !forward.tunnel
+                                && forward.guest_path.is_none()
+                        })
+                        .map(|spec| {
+                            Box::new(PoolTarget {
+                                members: pool
+                                    .iter()
+                                    .map(|member| {
+                                        member
+                                            .dst
+                                            .socket_addrs(member.guest_port, forward.ip_preference)
+                                    })
+                                    .filter(|addrs| !addrs.is_empty())
+                                    .collect(),
+                                balance: spec.balance,
+                                sticky: spec.sticky,
+                                health_check: (spec.health_check_secs > 0)
+                                    .then(|| Duration::from_secs(spec.health_check_secs)),
+                            })
+                        }),
+                }
+            }
+            ForwardAction::Remove { protocol, src_port } => {
+                PortforwardRequest::RemoveRoutingRule { protocol, src_port }
+            }
+        };
+        pf_req.send(req).expect("failed to send PortforwardRequest");
     }
 }
 
-static SERVER_ADDR: &str = "0.0.0.0:12345";
-
 fn main() {
     env::set_var("RUST_LOG", "info");
     env_logger::init();
@@ -69,18 +166,91 @@ fn main() {
 
     info!("Server is started with {} !", SERVER_ADDR);
 
-    let mmap = Arc::new(Mutex::new(MachineMap::default()));
+    let config = load_server_config();
+    let portforward_options = config.portforward_options();
+    let proxy_options = config.proxy_options();
+    let reverse_proxy_options = config.reverse_proxy_options();
+
+    let mmap = Arc::new(Mutex::new(MachineMap::new(config.stats_history_len)));
+
+    let (hook_req, hook_recv) = channel();
+    start_hook_service(config.hooks, hook_recv);
 
-    let (pf_req, pf_recv) = channel();
-    start_port_forward_service(pf_recv);
+    let tunnel_connect_timeout = portforward_options.connect_timeout;
+    let (pf_req, pf_recv) = unbounded_channel();
+    start_port_forward_service(portforward_options, pf_recv);
 
-    let forward_port_set = Arc::new(Mutex::new(HashSet::new()));
+    let forward_ipv6_interface = config.forward_ipv6_interface.clone();
+    let forward_table = Arc::new(Mutex::new(ForwardTable::new(
+        config.forward_conflict_policy,
+        config.forward_priorities,
+        config.forward_auto_port_range.0..=config.forward_auto_port_range.1,
+    )));
+    let tunnel_slots = Arc::new(TunnelSlots::default());
+    let forward_socket_dir = config.forward_socket_dir.clone();
+    let capture_dir = config.capture_dir.clone();
+    let reverse_forward_targets = Arc::new(config.reverse_forward_targets);
+
+    {
+        let mmap = mmap.clone();
+        let hook_req = hook_req.clone();
+        let pf_req = pf_req.clone();
+        let forward_table = forward_table.clone();
+        let tunnel_slots = tunnel_slots.clone();
+        let forward_socket_dir = forward_socket_dir.clone();
+        let stale_after = Duration::from_secs(config.stale_after_secs);
+        let expire_after = Duration::from_secs(config.expire_after_secs);
+
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(5));
+
+            let changes = mmap.lock().unwrap().sweep(stale_after, expire_after);
+            for change in changes {
+                info!("MachineInfo {:?} : {}", change.event, change.hostname);
+                if change.event == RegistryEvent::Deregistered {
+                    let actions = forward_table
+                        .lock()
+                        .unwrap()
+                        .remove_machine(&change.hostname);
+                    apply_forward_actions(
+                        &pf_req,
+                        &tunnel_slots,
+                        forward_socket_dir.as_deref(),
+                        actions,
+                    );
+                }
+                hook_req
+                    .send(change)
+                    .expect("failed to send RegistryChange");
+            }
+        });
+    }
+
+    if let Some(proxy_options) = proxy_options {
+        start_proxy_service(proxy_options, mmap.clone());
+    }
+    if let Some(reverse_proxy_options) = reverse_proxy_options {
+        start_reverse_proxy_service(reverse_proxy_options, mmap.clone());
+    }
+
+    start_lease_watcher(
+        config.lease_sources,
+        Duration::from_secs(config.lease_poll_interval_secs),
+        mmap.clone(),
+        hook_req.clone(),
+    );
 
     for client in server.incoming().flatten() {
         let mmap = mmap.clone();
         let mut client = client.try_clone().unwrap();
-        let forward_port_set = forward_port_set.clone();
         let pf_req = pf_req.clone();
+        let hook_req = hook_req.clone();
+        let forward_table = forward_table.clone();
+        let forward_ipv6_interface = forward_ipv6_interface.clone();
+        let tunnel_slots = tunnel_slots.clone();
+        let forward_socket_dir = forward_socket_dir.clone();
+        let capture_dir = capture_dir.clone();
+        let reverse_forward_targets = reverse_forward_targets.clone();
 
         thread::spawn(move || {
             if let Ok(sdc) = SerializedDataContainer::from_reader(&mut client) {
@@ -131,44 +301,66 @@ fn main() {
                                 .unwrap();
                         }
                         Request::NameService(ns) => match ns {
-                            NSRequest::Heartbeat(mi, given_forward_list) => {
-                                info!("NSRequest::Heartbeat({mi:?}, {given_forward_list:?})");
-                                {
+                            NSRequest::Heartbeat(mi, given_forward_list, stats) => {
+                                info!("NSRequest::Heartbeat({mi:?}, {given_forward_list:?}, {stats:?})");
+                                let change = {
                                     let mut mmap = mmap.lock().unwrap();
-                                    info!("New MachineInfo registered! : {:?}", &mi);
-                                    mmap.insert(
-                                        mi.hostname.clone(),
-                                        IpAddrPair::new(mi.ipv4_addr.clone(), mi.ipv6_addr),
-                                    );
+                                    let change = mmap.update(&mi);
+                                    if let Some(stats) = stats {
+                                        mmap.push_stats(&mi.hostname, stats);
+                                    }
+                                    change
+                                };
+                                if let Some(change) = change {
+                                    info!("MachineInfo {:?} : {:?}", change.event, &mi);
+                                    hook_req
+                                        .send(change)
+                                        .expect("failed to send RegistryChange");
                                 }
 
-                                for forward in given_forward_list.forwards {
-                                    let src_port = forward.host_port;
-                                    let dst_ip = mi
-                                        .ipv4_addr
-                                        .clone()
-                                        .parse()
-                                        .expect("failed to parse Port Forward dst ip");
-                                    let dst_port = forward.guest_port;
-
-                                    pf_req
-                                        .send(PortforwardRequest::UpdateRoutingRule {
-                                            src_port,
-                                            dst_ip,
-                                            dst_port,
-                                        })
-                                        .expect(
-                                            "failed to send PortforwardRequest::UpdateRoutingRule",
-                                        );
-                                    let mut forward_port_set = forward_port_set
-                                        .lock()
-                                        .expect("failed to aquire lock of forward_port_set");
-                                    if !forward_port_set.contains(&src_port) {
-                                        forward_port_set.insert(src_port);
-
-                                        spawn_pf_front_server(src_port, pf_req.clone());
-                                    }
+                                let guest_addrs = GuestAddrs::from_machine_info(
+                                    &mi,
+                                    forward_ipv6_interface.as_deref(),
+                                );
+                                let status = {
+                                    let mut forward_table = forward_table.lock().unwrap();
+                                    let actions = forward_table.update(
+                                        &mi.hostname,
+                                        guest_addrs,
+                                        given_forward_list,
+                                    );
+                                    apply_forward_actions(
+                                        &pf_req,
+                                        &tunnel_slots,
+                                        forward_socket_dir.as_deref(),
+                                        actions,
+                                    );
+                                    forward_table.status(&mi.hostname)
+                                };
+                                for conflict in status.conflicts.iter() {
+                                    info!(
+                                        "Port Forward conflict: {} declares {:?}, owned by {:?}",
+                                        mi.hostname, conflict.forward, conflict.owner
+                                    );
+                                }
+                                for forward in status.unallocated.iter() {
+                                    info!(
+                                        "Port Forward: no free host port for {} {:?}",
+                                        mi.hostname, forward
+                                    );
                                 }
+
+                                client
+                                    .write_all(
+                                        &SerializedDataContainer::from_serializable_data(
+                                            &Response::NameService(NSResponse::HeartbeatAck(
+                                                status,
+                                            )),
+                                        )
+                                        .unwrap()
+                                        .to_one_vec(),
+                                    )
+                                    .unwrap();
                             }
                             NSRequest::QueryIp(hostname) => {
                                 info!("NSRequest::QueryIp({hostname:?})");
@@ -227,6 +419,221 @@ fn main() {
                                     )
                                     .unwrap();
                             }
+                            NSRequest::GetStats(hostname) => {
+                                info!("NSRequest::GetStats({hostname:?})");
+                                let stats = mmap.lock().unwrap().get_stats(&hostname);
+
+                                client
+                                    .write_all(
+                                        &SerializedDataContainer::from_serializable_data(
+                                            &Response::NameService(NSResponse::Stats(stats)),
+                                        )
+                                        .unwrap()
+                                        .to_one_vec(),
+                                    )
+                                    .unwrap();
+                            }
+                            NSRequest::GetForwardList => {
+                                info!("NSRequest::GetForwardList");
+                                let mut entries = forward_table.lock().unwrap().entries();
+
+                                let (reply, reply_recv) = oneshot::channel();
+                                pf_req
+                                    .send(PortforwardRequest::GetCounters(reply))
+                                    .expect("failed to send PortforwardRequest::GetCounters");
+                                let counters = reply_recv.blocking_recv().unwrap_or_default();
+                                for entry in entries.iter_mut().filter(|e| e.active) {
+                                    entry.counters = counters
+                                        .get(&(entry.forward.protocol, entry.forward.host_port))
+                                        .cloned();
+                                }
+
+                                client
+                                    .write_all(
+                                        &SerializedDataContainer::from_serializable_data(
+                                            &Response::NameService(NSResponse::ForwardList(
+                                                entries,
+                                            )),
+                                        )
+                                        .unwrap()
+                                        .to_one_vec(),
+                                    )
+                                    .unwrap();
+                            }
+                            NSRequest::GetConnections => {
+                                info!("NSRequest::GetConnections");
+                                let (reply, reply_recv) = oneshot::channel();
+                                pf_req
+                                    .send(PortforwardRequest::GetConnections(reply))
+                                    .expect("failed to send PortforwardRequest::GetConnections");
+                                let mut connections =
+                                    reply_recv.blocking_recv().unwrap_or_default();
+
+                                {
+                                    let forward_table = forward_table.lock().unwrap();
+                                    for connection in connections.iter_mut() {
+                                        connection.hostname = forward_table
+                                            .hostname_at(
+                                                connection.protocol,
+                                                connection.host_port,
+                                                connection.guest_addr,
+                                            )
+                                            .map(|hostname| hostname.to_string());
+                                    }
+                                }
+
+                                client
+                                    .write_all(
+                                        &SerializedDataContainer::from_serializable_data(
+                                            &Response::NameService(NSResponse::Connections(
+                                                connections,
+                                            )),
+                                        )
+                                        .unwrap()
+                                        .to_one_vec(),
+                                    )
+                                    .unwrap();
+                            }
+                            NSRequest::KillConnection(id) => {
+                                info!("NSRequest::KillConnection({id})");
+                                let (reply, reply_recv) = oneshot::channel();
+                                pf_req
+                                    .send(PortforwardRequest::KillConnection(id, reply))
+                                    .expect("failed to send PortforwardRequest::KillConnection");
+                                let killed = reply_recv.blocking_recv().unwrap_or(false);
+
+                                client
+                                    .write_all(
+                                        &SerializedDataContainer::from_serializable_data(
+                                            &Response::NameService(NSResponse::ConnectionKilled(
+                                                killed,
+                                            )),
+                                        )
+                                        .unwrap()
+                                        .to_one_vec(),
+                                    )
+                                    .unwrap();
+                            }
+                            NSRequest::StartCapture(capture) => {
+                                info!("NSRequest::StartCapture({capture:?})");
+                                let started =
+                                    match path_in_dir(capture_dir.as_deref(), &capture.path) {
+                                        Some(path) => {
+                                            let (reply, reply_recv) = oneshot::channel();
+                                            pf_req
+                                            .send(PortforwardRequest::StartCapture {
+                                                protocol: capture.protocol,
+                                                src_port: capture.host_port,
+                                                format: capture.format,
+                                                path,
+                                                max_bytes: capture.max_bytes,
+                                                reply,
+                                            })
+                                            .expect(
+                                                "failed to send PortforwardRequest::StartCapture",
+                                            );
+                                            reply_recv.blocking_recv().unwrap_or(Err(
+                                                "the port forward service is gone".to_string(),
+                                            ))
+                                        }
+                                        None => Err(format!(
+                                            "{} is not a file name in capture_dir",
+                                            capture.path
+                                        )),
+                                    };
+
+                                client
+                                    .write_all(
+                                        &SerializedDataContainer::from_serializable_data(
+                                            &Response::NameService(NSResponse::CaptureStarted(
+                                                started,
+                                            )),
+                                        )
+                                        .unwrap()
+                                        .to_one_vec(),
+                                    )
+                                    .unwrap();
+                            }
+                            NSRequest::StopCapture(protocol, host_port) => {
+                                info!("NSRequest::StopCapture({protocol:?}, {host_port})");
+                                let (reply, reply_recv) = oneshot::channel();
+                                pf_req
+                                    .send(PortforwardRequest::StopCapture(
+                                        protocol, host_port, reply,
+                                    ))
+                                    .expect("failed to send PortforwardRequest::StopCapture");
+                                let written = reply_recv.blocking_recv().unwrap_or(None);
+
+                                client
+                                    .write_all(
+                                        &SerializedDataContainer::from_serializable_data(
+                                            &Response::NameService(NSResponse::CaptureStopped(
+                                                written,
+                                            )),
+                                        )
+                                        .unwrap()
+                                        .to_one_vec(),
+                                    )
+                                    .unwrap();
+                            }
+                            NSRequest::AddForward(hostname, forward) => {
+                                info!("NSRequest::AddForward({hostname:?}, {forward:?})");
+                                let status = {
+                                    let mut forward_table = forward_table.lock().unwrap();
+                                    forward_table
+                                        .add_forward(&hostname, forward)
+                                        .map(|actions| {
+                                            apply_forward_actions(
+                                                &pf_req,
+                                                &tunnel_slots,
+                                                forward_socket_dir.as_deref(),
+                                                actions,
+                                            );
+                                            forward_table.status(&hostname)
+                                        })
+                                };
+
+                                client
+                                    .write_all(
+                                        &SerializedDataContainer::from_serializable_data(
+                                            &Response::NameService(NSResponse::ForwardUpdated(
+                                                status,
+                                            )),
+                                        )
+                                        .unwrap()
+                                        .to_one_vec(),
+                                    )
+                                    .unwrap();
+                            }
+                            NSRequest::RemoveForward(hostname, protocol, guest_port) => {
+                                info!("NSRequest::RemoveForward({hostname:?}, {protocol:?}, {guest_port})");
+                                let status = {
+                                    let mut forward_table = forward_table.lock().unwrap();
+                                    forward_table
+                                        .remove_forward(&hostname, protocol, guest_port)
+                                        .map(|actions| {
+                                            apply_forward_actions(
+                                                &pf_req,
+                                                &tunnel_slots,
+                                                forward_socket_dir.as_deref(),
+                                                actions,
+                                            );
+                                            forward_table.status(&hostname)
+                                        })
+                                };
+
+                                client
+                                    .write_all(
+                                        &SerializedDataContainer::from_serializable_data(
+                                            &Response::NameService(NSResponse::ForwardUpdated(
+                                                status,
+                                            )),
+                                        )
+                                        .unwrap()
+                                        .to_one_vec(),
+                                    )
+                                    .unwrap();
+                            }
                         },
                         Request::ClipBoard(cb) => match cb {
                             CBRequest::SetClipboard(s) => {
@@ -305,6 +712,78 @@ fn main() {
                                     .expect("unable to toast");
                             }
                         },
+                        Request::Tunnel(tunnel) => match tunnel {
+                            TunnelRequest::Connect(host_addr) => {
+                                info!("TunnelRequest::Connect({host_addr})");
+                                let host_stream = if reverse_forward_targets.contains(&host_addr) {
+                                    AnyStream::connect(&host_addr, tunnel_connect_timeout)
+                                } else {
+                                    Err(std::io::Error::new(
+                                        std::io::ErrorKind::PermissionDenied,
+                                        format!("{host_addr} is not in reverse_forward_targets"),
+                                    ))
+                                };
+                                if let Err(e) = &host_stream {
+                                    warn!("Tunnel: failed to connect to {host_addr}: {e}");
+                                }
+
+                                client
+                                    .write_all(
+                                        &SerializedDataContainer::from_serializable_data(
+                                            &Response::Tunnel(TunnelResponse::Connect(
+                                                host_stream
+                                                    .as_ref()
+                                                    .map(|_| ())
+                                                    .map_err(|e| e.to_string()),
+                                            )),
+                                        )
+                                        .unwrap()
+                                        .to_one_vec(),
+                                    )
+                                    .unwrap();
+
+                                // the rest of the connection belongs to the tunnel
+                                if let Ok(host_stream) = host_stream {
+                                    match relay(client, host_stream) {
+                                        Ok((sent, received)) => info!("Tunnel to {host_addr} is closed, sent {sent} bytes and received {received} bytes"),
+                                        Err(e) => info!("Tunnel to {host_addr} is aborted: {e}"),
+                                    }
+                                    return;
+                                }
+                            }
+                            TunnelRequest::Attach(hostname) => {
+                                info!("TunnelRequest::Attach({hostname})");
+                                let registered = client.peer_addr().is_ok_and(|peer| {
+                                    is_registered_addr(&mmap.lock().unwrap(), &hostname, peer.ip())
+                                });
+                                if !registered {
+                                    warn!("Tunnel: {client:?} is not the registered address of {hostname}");
+                                    return;
+                                }
+
+                                let mux = Mux::new(&client).unwrap();
+                                let slot = tunnel_slots.slot(&hostname);
+                                if !slot.attach(mux.clone()) {
+                                    warn!("Tunnel: the session of {hostname} is already attached");
+                                    return;
+                                }
+                                let _ = SockRef::from(&client).set_tcp_keepalive(
+                                    &TcpKeepalive::new().with_time(TUNNEL_KEEPALIVE),
+                                );
+
+                                // the rest of the connection belongs to the session, channels are opened only by this side
+                                let served = mux.serve(client, |channel, target| {
+                                    warn!("Tunnel: {hostname} tried to open a channel to {target}");
+                                    channel.reject("channels are opened by the server".to_string());
+                                });
+                                slot.detach(&mux);
+                                match served {
+                                    Ok(()) => info!("Tunnel session of {hostname} is closed"),
+                                    Err(e) => info!("Tunnel session of {hostname} is aborted: {e}"),
+                                }
+                                return;
+                            }
+                        },
                     }
                 } else {
                     info!("VMC Client Disconnected.");
@@ -1,11 +1,269 @@
-use log::{info, trace};
+mod access;
+mod capture;
+mod connections;
+mod front;
+mod pool;
+mod proxy_protocol;
+#[cfg(target_os = "linux")]
+mod splice;
+
+pub use access::AccessList;
+use connections::{ByteCounter, ConnectionBytes, ConnectionGuard, ConnectionTable};
+#[cfg(unix)]
+use front::SocketFile;
+use front::{FrontStream, Listen};
+pub use pool::PoolTarget;
+use pool::{Balancer, MemberGuard};
+
+use log::{info, trace, warn};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::io::prelude::*;
-use std::net::{Ipv4Addr, TcpListener, TcpStream};
-use std::sync::mpsc::{Receiver, Sender};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinHandle;
+use vmc_common::protocol::ChannelTarget;
+use vmc_common::relay::LocalStream;
+use vmc_common::types::{
+    CaptureFormat, IpPreference, MachineInfo, PortforwardConnection, PortforwardCounters,
+    PortforwardProtocol, PortforwardSpec, ProxyProtocolVersion,
+};
+
+use crate::tunnel::TunnelTarget;
+
+pub const DEFAULT_BUF_SIZE: usize = 64 * 1024;
+// the max size of a UDP payload
+const UDP_BUF_SIZE: usize = 65536;
+const UDP_SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+// datagrams from a client are dropped while its session is this much behind
+const UDP_SESSION_QUEUE_LEN: usize = 256;
+// e.g. EMFILE, retrying immediately would just spin
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone)]
+pub struct PortforwardOptions {
+    pub buf_size: usize,
+    // zero-copy transfer by splice(2), only on linux
+    pub use_splice: bool,
+    // connections of a removed rule are left to finish instead of being closed
+    pub drain_on_remove: bool,
+    // connections of an old rule are left to finish for this long, or closed at once if None
+    pub drain_timeout: Option<Duration>,
+    pub connect_timeout: Duration,
+    // attempts after the first failed connect to the remote
+    pub connect_retries: u32,
+    pub connect_retry_interval: Duration,
+}
+
+impl Default for PortforwardOptions {
+    fn default() -> Self {
+        Self {
+            buf_size: DEFAULT_BUF_SIZE,
+            use_splice: false,
+            drain_on_remove: false,
+            drain_timeout: None,
+            connect_timeout: Duration::from_secs(5),
+            connect_retries: 2,
+            connect_retry_interval: Duration::from_millis(500),
+        }
+    }
+}
 
-const DEFAULT_BUF_SIZE: usize = 1024;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TcpSocketOptions {
+    pub nodelay: bool,
+    pub keepalive: Option<Duration>,
+}
+
+impl TcpSocketOptions {
+    pub fn from_spec(forward: &PortforwardSpec) -> Self {
+        Self {
+            nodelay: forward.nodelay,
+            keepalive: forward.keepalive_secs.map(Duration::from_secs),
+        }
+    }
+
+    fn apply(&self, stream: &TcpStream) -> std::io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        if let Some(keepalive) = self.keepalive {
+            SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(keepalive))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn scope_id_of(ifname: &str) -> u32 {
+    std::ffi::CString::new(ifname)
+        .map(|ifname| unsafe { libc::if_nametoindex(ifname.as_ptr()) })
+        .unwrap_or(0)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn scope_id_of(_ifname: &str) -> u32 {
+    0
+}
+
+// the addresses a guest can be reached at from this machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct GuestAddrs {
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<SocketAddrV6>,
+}
+
+impl GuestAddrs {
+    // The scope of a link-local "fe80::1%eth0" names an interface of the guest, which is resolved
+    // on this machine unless `ipv6_interface` is given.
+    pub fn from_machine_info(mi: &MachineInfo, ipv6_interface: Option<&str>) -> Self {
+        let ipv6 = mi.ipv6_addr.as_deref().and_then(|ipv6_addr| {
+            let (addr, scope) = match ipv6_addr.split_once('%') {
+                Some((addr, scope)) => (addr, Some(scope)),
+                None => (ipv6_addr, None),
+            };
+            let addr: Ipv6Addr = addr.parse().ok()?;
+            let scope_id = if addr.segments()[0] & 0xffc0 == 0xfe80 {
+                match (ipv6_interface, scope) {
+                    (Some(ifname), _) => scope_id_of(ifname),
+                    (None, Some(scope)) => scope.parse().unwrap_or_else(|_| scope_id_of(scope)),
+                    (None, None) => 0,
+                }
+            } else {
+                0
+            };
+
+            Some(SocketAddrV6::new(addr, 0, 0, scope_id))
+        });
+
+        Self {
+            ipv4: mi.ipv4_addr.parse().ok(),
+            ipv6,
+        }
+    }
+
+    // in the order to try, the other family is the fallback
+    pub fn socket_addrs(&self, port: u16, preference: IpPreference) -> Vec<SocketAddr> {
+        let ipv4 = self.ipv4.map(|ip| SocketAddr::from((ip, port)));
+        let ipv6 = self.ipv6.map(|mut addr| {
+            addr.set_port(port);
+            SocketAddr::V6(addr)
+        });
+
+        match preference {
+            IpPreference::V4 => [ipv4, ipv6],
+            IpPreference::V6 => [ipv6, ipv4],
+        }
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
+
+#[derive(Debug)]
+pub enum PortforwardRequest {
+    UpdateRoutingRule {
+        protocol: PortforwardProtocol,
+        src_port: u16,
+        dst_addrs: Vec<SocketAddr>,
+        // None is [::], or 0.0.0.0 without ipv6
+        bind_addr: Option<IpAddr>,
+        access: AccessList,
+        socket_options: TcpSocketOptions,
+        // tcp only, connections go through the tunnel session of the guest instead of dst_addrs
+        tunnel: Option<TunnelTarget>,
+        // tcp only, sent to the guest before the data of each connection
+        proxy_protocol: Option<ProxyProtocolVersion>,
+        // tcp only, listens on this unix socket instead of bind_addr:src_port
+        host_path: Option<PathBuf>,
+        // tcp only, connections are balanced over the members instead of dst_addrs
+        pool: Option<Box<PoolTarget>>,
+    },
+    RemoveRoutingRule {
+        protocol: PortforwardProtocol,
+        src_port: u16,
+    },
+    GetCounters(oneshot::Sender<HashMap<(PortforwardProtocol, u16), PortforwardCounters>>),
+    GetConnections(oneshot::Sender<Vec<PortforwardConnection>>),
+    // replies false if no such a connection is alive
+    KillConnection(u64, oneshot::Sender<bool>),
+    // replaces a running capture of the rule
+    StartCapture {
+        protocol: PortforwardProtocol,
+        src_port: u16,
+        format: CaptureFormat,
+        path: PathBuf,
+        max_bytes: u64,
+        reply: oneshot::Sender<Result<(), String>>,
+    },
+    // replies the bytes written, None if the rule was not captured
+    StopCapture(PortforwardProtocol, u16, oneshot::Sender<Option<u64>>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Target {
+    dst_addrs: Vec<SocketAddr>,
+    access: AccessList,
+    socket_options: TcpSocketOptions,
+    tunnel: Option<TunnelTarget>,
+    proxy_protocol: Option<ProxyProtocolVersion>,
+    pool: Option<PoolTarget>,
+    // the same one for every route of a front server
+    balancer: Balancer,
+}
+
+impl Target {
+    // whether a connection to addr still belongs to the rule
+    fn reaches(&self, addr: SocketAddr) -> bool {
+        self.tunnel.is_none()
+            && (self.dst_addrs.contains(&addr)
+                || self
+                    .pool
+                    .as_ref()
+                    .is_some_and(|pool| pool.members.iter().any(|member| member.contains(&addr))))
+    }
+}
+
+// connections are kept while only the access list or the socket options of the rule are changed
+type Route = Option<Target>;
+
+#[derive(Debug, Default)]
+struct RuleCounters {
+    connections: AtomicU64,
+    connect_failures: AtomicU64,
+    connect_timeouts: AtomicU64,
+    accept_errors: AtomicU64,
+    rejected: AtomicU64,
+    transfer_errors: AtomicU64,
+    bytes_to_guest: AtomicU64,
+    bytes_from_guest: AtomicU64,
+}
+
+impl RuleCounters {
+    fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> PortforwardCounters {
+        PortforwardCounters {
+            connections: self.connections.load(Ordering::Relaxed),
+            connect_failures: self.connect_failures.load(Ordering::Relaxed),
+            connect_timeouts: self.connect_timeouts.load(Ordering::Relaxed),
+            accept_errors: self.accept_errors.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            transfer_errors: self.transfer_errors.load(Ordering::Relaxed),
+            bytes_to_guest: self.bytes_to_guest.load(Ordering::Relaxed),
+            bytes_from_guest: self.bytes_from_guest.load(Ordering::Relaxed),
+        }
+    }
+}
 
 /*
  *
@@ -15,73 +273,449 @@ const DEFAULT_BUF_SIZE: usize = 1024;
  *   Local TCP Client ---> Redirector TCP Server -------> Redirect TCP Client ------> Remote TCP Server
  * */
 
-fn spawn_backend_stream(
-    header: String,
-    mut front_stream: TcpStream,
-    remote_ip: Ipv4Addr,
-    remote_port: u16,
-) -> TcpStream {
-    info!("{header} New client connected! {:?}", front_stream);
-
-    let backend_stream = TcpStream::connect(format!("{}:{}", remote_ip, remote_port)).unwrap();
-    trace!("{header} Connect to remote is ok!");
-
-    let local_tcp_handler = {
-        let mut front_stream = front_stream.try_clone().unwrap();
-        let mut backend_stream = backend_stream.try_clone().unwrap();
-        let header = header.clone();
-        thread::spawn(move || {
-            let mut buf: [u8; DEFAULT_BUF_SIZE] = [0; DEFAULT_BUF_SIZE];
-            loop {
-                let n = front_stream.read(&mut buf).unwrap_or(0);
-
-                trace!("{header} [CLIENT] read {} bytes from client", n);
-                if n == 0 {
-                    trace!("{header} [CLIENT] DISCONNECT!");
-                    break;
-                }
+// On EOF the peer is half-closed and the other direction is left running.
+// An error tears down the whole connection.
+async fn pump<R, W>(
+    header: &str,
+    label: &str,
+    mut reader: R,
+    mut writer: W,
+    buf_size: usize,
+    bytes: ByteCounter<'_>,
+) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; buf_size];
+
+    loop {
+        let n = reader.read(&mut buf).await.inspect_err(|e| {
+            trace!("{header} [{label}] failed to read: {e}");
+        })?;
+        trace!("{header} [{label}] read {n} bytes");
+
+        if n == 0 {
+            trace!("{header} [{label}] EOF, shutdown the peer");
+            return writer.shutdown().await;
+        }
+
+        writer.write_all(&buf[..n]).await.inspect_err(|e| {
+            trace!("{header} [{label}] failed to write: {e}");
+        })?;
+        bytes.record(&buf[..n]);
+    }
+}
+
+async fn transfer(
+    header: &str,
+    front_stream: FrontStream,
+    backend_stream: TcpStream,
+    options: &PortforwardOptions,
+    bytes: &ConnectionBytes,
+) -> std::io::Result<()> {
+    let (backend_read, backend_write) = backend_stream.into_split();
+    let buf_size = options.buf_size;
+    let (to_guest, to_client) = (bytes.towards_guest(), bytes.towards_client());
 
-                let _w = backend_stream.write(&buf[..n]).unwrap();
-                trace!("{header} [CLIENT] write {} bytes to remote", _w);
+    let front_stream = match front_stream {
+        FrontStream::Tcp(front_stream) => front_stream,
+        // splice(2) needs sockets of the same kind on both ends
+        #[cfg(unix)]
+        FrontStream::Unix(front_stream) => {
+            let (front_read, front_write) = front_stream.into_split();
+            return tokio::try_join!(
+                pump(
+                    header,
+                    "CLIENT",
+                    front_read,
+                    backend_write,
+                    buf_size,
+                    to_guest
+                ),
+                pump(
+                    header,
+                    "REMOTE",
+                    backend_read,
+                    front_write,
+                    buf_size,
+                    to_client
+                ),
+            )
+            .map(|_| ());
+        }
+    };
+    let (front_read, front_write) = front_stream.into_split();
+
+    #[cfg(target_os = "linux")]
+    if options.use_splice {
+        return tokio::try_join!(
+            splice::pump(
+                header,
+                "CLIENT",
+                front_read,
+                backend_write,
+                buf_size,
+                to_guest
+            ),
+            splice::pump(
+                header,
+                "REMOTE",
+                backend_read,
+                front_write,
+                buf_size,
+                to_client
+            ),
+        )
+        .map(|_| ());
+    }
+
+    tokio::try_join!(
+        pump(
+            header,
+            "CLIENT",
+            front_read,
+            backend_write,
+            buf_size,
+            to_guest
+        ),
+        pump(
+            header,
+            "REMOTE",
+            backend_read,
+            front_write,
+            buf_size,
+            to_client
+        ),
+    )
+    .map(|_| ())
+}
+
+fn format_dst_addrs(dst_addrs: &[SocketAddr]) -> String {
+    dst_addrs
+        .iter()
+        .map(|addr| addr.to_string())
+        .collect::<Vec<_>>()
+        .join(" | ")
+}
+
+fn format_dst(
+    dst_addrs: &[SocketAddr],
+    tunnel: Option<&TunnelTarget>,
+    pool: Option<&PoolTarget>,
+) -> String {
+    match (tunnel, pool) {
+        (Some(tunnel), _) => format!("tunnel:{}", tunnel.target),
+        (None, Some(pool)) => format!(
+            "pool[{}]",
+            pool.members
+                .iter()
+                .map(|member| format_dst_addrs(member))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        (None, None) => format_dst_addrs(dst_addrs),
+    }
+}
+
+// resolves when the routing rule no longer satisfies `still_valid`
+async fn route_changed<F>(route: &mut watch::Receiver<Route>, still_valid: F)
+where
+    F: Fn(&Target) -> bool,
+{
+    loop {
+        if route.changed().await.is_err() {
+            // the rule is removed, but the connection is allowed to drain
+            return std::future::pending().await;
+        }
+        if !route.borrow().as_ref().is_some_and(&still_valid) {
+            return;
+        }
+    }
+}
+
+/*
+ * resolves when an established connection has to be closed
+ *
+ *   rule changed   ---> drained for drain_timeout, new connections go to the new destination
+ *   rule removed   ---> closed at once, or drained with drain_on_remove (for drain_timeout if given)
+ */
+async fn route_drained<F>(
+    route: &mut watch::Receiver<Route>,
+    still_valid: F,
+    drain_timeout: Option<Duration>,
+) where
+    F: Fn(&Target) -> bool,
+{
+    let removed = loop {
+        if route.changed().await.is_err() {
+            break true;
+        }
+        match route.borrow().as_ref() {
+            None => return,
+            Some(target) if still_valid(target) => continue,
+            Some(_) => break false,
+        }
+    };
+
+    let Some(drain_timeout) = drain_timeout else {
+        if removed {
+            // drained without limit
+            return std::future::pending().await;
+        }
+        return;
+    };
+    let closed = async {
+        while route.changed().await.is_ok() {
+            if route.borrow().is_none() {
+                return;
             }
-        })
+        }
+        std::future::pending().await
     };
+    tokio::select! {
+        _ = tokio::time::sleep(drain_timeout) => {}
+        _ = closed => {}
+    }
+}
 
-    {
-        let mut remote = backend_stream.try_clone().unwrap();
-        thread::spawn(move || {
-            let mut buf: [u8; DEFAULT_BUF_SIZE] = [0; DEFAULT_BUF_SIZE];
-            loop {
-                let n = remote.read(&mut buf).unwrap_or(0);
-                trace!("{header} [REMOTE] read {} bytes from remote", n);
+// every attempt tries the addresses in order, so an unreachable family falls back to the other
+async fn connect_backend(
+    header: &str,
+    dst_addrs: &[SocketAddr],
+    options: &PortforwardOptions,
+    counters: &RuleCounters,
+) -> Option<TcpStream> {
+    for attempt in 0..=options.connect_retries {
+        if attempt > 0 {
+            tokio::time::sleep(options.connect_retry_interval).await;
+        }
 
-                if n == 0 {
-                    trace!("{header} [REMOTE] DISCONNECT!");
-                    break;
+        for dst in dst_addrs {
+            match tokio::time::timeout(options.connect_timeout, TcpStream::connect(dst)).await {
+                Ok(Ok(backend_stream)) => return Some(backend_stream),
+                Ok(Err(e)) => {
+                    RuleCounters::incr(&counters.connect_failures);
+                    warn!(
+                        "{header} failed to connect to {dst} (attempt {}): {e}",
+                        attempt + 1
+                    );
                 }
+                Err(_) => {
+                    RuleCounters::incr(&counters.connect_timeouts);
+                    warn!(
+                        "{header} connecting to {dst} timed out (attempt {})",
+                        attempt + 1
+                    );
+                }
+            }
+        }
+    }
+
+    None
+}
+
+async fn spawn_backend_stream(
+    header: String,
+    mut front_stream: FrontStream,
+    target: Target,
+    mut route: watch::Receiver<Route>,
+    options: Arc<PortforwardOptions>,
+    counters: Arc<RuleCounters>,
+    mut conn: ConnectionGuard,
+) {
+    info!(
+        "{header} New client connected! (id: {}) {:?}",
+        conn.id, front_stream
+    );
+    RuleCounters::incr(&counters.connections);
+    if let Some(tunnel) = target.tunnel {
+        // the socket options of the guest side are left to the guest
+        if let Err(e) = front_stream.apply_options(&target.socket_options) {
+            warn!("{header} failed to set socket options: {e}");
+        }
+        return tunnel_backend_stream(header, front_stream, tunnel, route, options, counters, conn)
+            .await;
+    }
+    let client_ip = match front_stream.addrs() {
+        Ok(Some((client_addr, _))) => Some(client_addr.ip().to_canonical()),
+        _ => None,
+    };
+
+    let connecting = async {
+        match &target.pool {
+            Some(pool) => pool::connect_member(
+                &header,
+                pool,
+                &target.balancer,
+                client_ip,
+                &options,
+                &counters,
+            )
+            .await
+            .map(|(backend_stream, member)| (backend_stream, Some(member))),
+            None => connect_backend(&header, &target.dst_addrs, &options, &counters)
+                .await
+                .map(|backend_stream| (backend_stream, None)),
+        }
+    };
+    let backend_stream = tokio::select! {
+        backend_stream = connecting => backend_stream,
+        _ = route_changed(&mut route, |t| t.tunnel.is_none() && t.dst_addrs == target.dst_addrs && t.pool == target.pool) => None,
+        _ = &mut conn.killed => None,
+    };
+    // counted as an active connection of the member while it's alive
+    let Some((mut backend_stream, _member)): Option<(TcpStream, Option<MemberGuard>)> =
+        backend_stream
+    else {
+        // the client sees an orderly close instead of a connection left open
+        let _ = front_stream.shutdown().await;
+        return;
+    };
+    let connected = backend_stream.peer_addr().ok();
+    trace!("{header} Connect to remote {connected:?} is ok!");
+    if let Some(connected) = connected {
+        conn.set_guest_addr(connected);
+    }
 
-                let _w = front_stream.write(&buf[..n]).unwrap();
-                trace!("{header} [REMOTE] write {} bytes to client", _w);
+    let applied = front_stream
+        .apply_options(&target.socket_options)
+        .and_then(|_| target.socket_options.apply(&backend_stream));
+    if let Err(e) = applied {
+        warn!("{header} failed to set socket options: {e}");
+    }
+
+    if let Some(version) = target.proxy_protocol {
+        let sent = match front_stream.addrs() {
+            Ok(Some((src, dst))) => {
+                let header = proxy_protocol::header(version, src, dst);
+                backend_stream.write_all(&header).await
             }
-            local_tcp_handler.join().unwrap();
-            info!("{header} transfer thread is finished!");
-        });
+            Ok(None) => {
+                let header = proxy_protocol::unknown_header(version);
+                backend_stream.write_all(&header).await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = sent {
+            RuleCounters::incr(&counters.transfer_errors);
+            warn!("{header} failed to send the PROXY protocol header: {e}");
+            return;
+        }
     }
 
-    backend_stream
+    tokio::select! {
+        transferred = transfer(&header, front_stream, backend_stream, &options, &conn.bytes) => {
+            if let Err(e) = transferred {
+                RuleCounters::incr(&counters.transfer_errors);
+                info!("{header} transfer is aborted: {e}");
+            } else {
+                info!("{header} transfer is finished!");
+            }
+        }
+        // a change of the address of the other family doesn't affect the connection
+        _ = route_drained(&mut route, |t| connected.is_some_and(|c| t.reaches(c)), options.drain_timeout) => {
+            info!("{header} Close the connection related with old routing rule");
+        }
+        _ = &mut conn.killed => {
+            info!("{header} Killed the connection (id: {})", conn.id);
+        }
+    }
 }
 
-#[derive(Debug)]
-pub enum PortforwardRequest {
-    NewLocalClientConnected {
-        frontend_stream: TcpStream,
-        src_port: u16,
-    },
-    UpdateRoutingRule {
-        src_port: u16,
-        dst_ip: Ipv4Addr,
-        dst_port: u16,
-    },
+/*
+ *
+ * local client ---> pf_front server ---[channel]---> vmc session of the guest ---> guest server
+ *
+ * the channel is relayed by blocking threads, the same as the vmc session itself
+ */
+async fn tunnel_backend_stream(
+    header: String,
+    mut front_stream: FrontStream,
+    tunnel: TunnelTarget,
+    mut route: watch::Receiver<Route>,
+    options: Arc<PortforwardOptions>,
+    counters: Arc<RuleCounters>,
+    mut conn: ConnectionGuard,
+) {
+    let Some(mux) = tunnel.slot.get() else {
+        RuleCounters::incr(&counters.connect_failures);
+        warn!("{header} the guest has no tunnel session");
+        let _ = front_stream.shutdown().await;
+        return;
+    };
+
+    let channel_target = tunnel.target.clone();
+    let connect_timeout = options.connect_timeout;
+    let opening = tokio::task::spawn_blocking({
+        let (mux, channel_target) = (mux.clone(), channel_target.clone());
+        move || mux.open(channel_target, connect_timeout)
+    });
+    let channel = tokio::select! {
+        channel = opening => channel.expect("opening a channel panicked"),
+        _ = route_changed(&mut route, |t| t.tunnel.as_ref() == Some(&tunnel)) => return,
+        _ = &mut conn.killed => return,
+    };
+    let channel = match channel {
+        Ok(channel) => channel,
+        Err(e) => {
+            if e.kind() == std::io::ErrorKind::TimedOut {
+                RuleCounters::incr(&counters.connect_timeouts);
+            } else {
+                RuleCounters::incr(&counters.connect_failures);
+            }
+            warn!("{header} failed to open a channel to {channel_target}: {e}");
+            let _ = front_stream.shutdown().await;
+            return;
+        }
+    };
+    trace!("{header} Channel to {channel_target} is opened!");
+    // a unix socket of the guest has no address
+    if let (Some(peer_addr), ChannelTarget::Port(guest_port)) = (mux.peer_addr(), &channel_target) {
+        conn.set_guest_addr(SocketAddr::new(peer_addr.ip(), *guest_port));
+    }
+
+    let front_stream = match front_stream.into_blocking() {
+        Ok(stream) => stream,
+        Err(e) => {
+            warn!("{header} failed to convert the client socket: {e}");
+            return;
+        }
+    };
+    // shuts down the relay from outside of the blocking threads
+    let Ok(killer) = front_stream.try_clone() else {
+        return;
+    };
+    let closer = channel.closer();
+
+    let (to_guest, to_client) = (conn.bytes.clone(), conn.bytes.clone());
+    let relaying = tokio::task::spawn_blocking(move || {
+        channel.relay(
+            front_stream,
+            move |data| to_guest.towards_guest().record(data),
+            move |data| to_client.towards_client().record(data),
+        )
+    });
+
+    tokio::select! {
+        relayed = relaying => {
+            if let Err(e) = relayed.expect("relaying a channel panicked") {
+                RuleCounters::incr(&counters.transfer_errors);
+                info!("{header} transfer is aborted: {e}");
+            } else {
+                info!("{header} transfer is finished!");
+            }
+            return;
+        }
+        _ = route_drained(&mut route, |t| t.tunnel.as_ref() == Some(&tunnel), options.drain_timeout) => {
+            info!("{header} Close the connection related with old routing rule");
+        }
+        _ = &mut conn.killed => {
+            info!("{header} Killed the connection (id: {})", conn.id);
+        }
+    }
+    closer.close();
+    let _ = killer.shutdown(std::net::Shutdown::Both);
 }
 
 /*
@@ -92,71 +726,638 @@ pub enum PortforwardRequest {
  *                         r/w <-> r/w
  */
 
-pub fn spawn_pf_front_server(src_port: u16, req: Sender<PortforwardRequest>) {
-    thread::spawn(move || {
-        let listner = TcpListener::bind(format!("0.0.0.0:{}", src_port))
-            .expect("failed to bind a port of {src_port}");
+// [::] accepts both of ipv4 and ipv6 clients, 0.0.0.0 is the fallback without ipv6
+fn bind_socket(
+    bind_addr: Option<IpAddr>,
+    port: u16,
+    ty: Type,
+    protocol: Protocol,
+) -> std::io::Result<Socket> {
+    let bind_exact = |addr: SocketAddr| -> std::io::Result<Socket> {
+        let socket = Socket::new(Domain::for_address(addr), ty, Some(protocol))?;
+        socket.set_reuse_address(ty == Type::STREAM)?;
+        socket.bind(&addr.into())?;
+        Ok(socket)
+    };
+    let bind_v6 = || -> std::io::Result<Socket> {
+        let socket = Socket::new(Domain::IPV6, ty, Some(protocol))?;
+        socket.set_only_v6(false)?;
+        socket.set_reuse_address(ty == Type::STREAM)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+        Ok(socket)
+    };
+    let bind_v4 = || -> std::io::Result<Socket> {
+        let socket = Socket::new(Domain::IPV4, ty, Some(protocol))?;
+        socket.set_reuse_address(ty == Type::STREAM)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())?;
+        Ok(socket)
+    };
 
-        for client in listner.incoming() {
-            let local_client = client.unwrap().try_clone().unwrap();
-            req.send(PortforwardRequest::NewLocalClientConnected {
-                frontend_stream: local_client,
-                src_port,
-            })
-            .expect("failed to send PortforwardRequest::NewClient");
+    let socket = match bind_addr {
+        Some(addr) => bind_exact(SocketAddr::new(addr, port))?,
+        None => bind_v6().or_else(|_| bind_v4())?,
+    };
+    socket.set_nonblocking(true)?;
+
+    Ok(socket)
+}
+
+fn bind_tcp_listener(bind_addr: Option<IpAddr>, port: u16) -> std::io::Result<TcpListener> {
+    let socket = bind_socket(bind_addr, port, Type::STREAM, Protocol::TCP)?;
+    socket.listen(1024)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+fn bind_udp_socket(bind_addr: Option<IpAddr>, port: u16) -> std::io::Result<UdpSocket> {
+    let socket = bind_socket(bind_addr, port, Type::DGRAM, Protocol::UDP)?;
+
+    UdpSocket::from_std(socket.into())
+}
+
+fn listen_addr(bind_addr: Option<IpAddr>, port: u16) -> SocketAddr {
+    SocketAddr::new(bind_addr.unwrap_or(Ipv6Addr::UNSPECIFIED.into()), port)
+}
+
+async fn pf_front_server(
+    src_port: u16,
+    bind_addr: Option<IpAddr>,
+    route: watch::Receiver<Route>,
+    options: Arc<PortforwardOptions>,
+    counters: Arc<RuleCounters>,
+    connections: Arc<ConnectionTable>,
+) {
+    let src = listen_addr(bind_addr, src_port);
+    let listener = match bind_tcp_listener(bind_addr, src_port) {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("[PORT FORWARDER (src: {src})] failed to bind: {e}");
+            return;
         }
-    });
+    };
+    let src = listener.local_addr().unwrap_or(src);
+
+    loop {
+        let (front_stream, client_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                RuleCounters::incr(&counters.accept_errors);
+                warn!("[PORT FORWARDER (src: {src})] failed to accept: {e}");
+                tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                continue;
+            }
+        };
+
+        let Some(target) = route.borrow().clone() else {
+            continue;
+        };
+        let header = format!(
+            "[PORT FORWARDER (src: {src} --> dst: {})]",
+            format_dst(
+                &target.dst_addrs,
+                target.tunnel.as_ref(),
+                target.pool.as_ref()
+            )
+        );
+
+        // closed before any connection to the remote is made
+        if !target.access.is_allowed(client_addr.ip()) {
+            RuleCounters::incr(&counters.rejected);
+            warn!("{header} Rejected a connection from {client_addr}");
+            continue;
+        }
+
+        let conn = connections.register(
+            PortforwardProtocol::Tcp,
+            src_port,
+            Some(client_addr),
+            &counters,
+        );
+        tokio::spawn(spawn_backend_stream(
+            header,
+            FrontStream::Tcp(front_stream),
+            target,
+            route.clone(),
+            options.clone(),
+            counters.clone(),
+            conn,
+        ));
+    }
 }
 
-pub fn start_port_forward_service(recv: Receiver<PortforwardRequest>) {
-    thread::spawn(move || {
-        let mut routing_table = HashMap::<u16, (Ipv4Addr, u16)>::new();
-        let mut backend_streams = HashMap::<u16, Vec<TcpStream>>::new();
-
-        loop {
-            match recv.recv().expect("failed to unwrap PortforwardRequest") {
-                PortforwardRequest::NewLocalClientConnected {
-                    frontend_stream: client,
-                    src_port,
-                } => {
-                    let (dst_ip, dst_port) = routing_table
-                        .get(&src_port)
-                        .expect("failed to lookup routing table");
-                    let header = format!(
-                        "[PORT FORWARDER (src: 0.0.0.0:{src_port} --> dst: {dst_ip}:{dst_port})]"
-                    );
+/*
+ *
+ * local client ---> host_path (unix socket) ---> pf_unix_front server ---[port forward]---> remote server
+ *
+ * the access list is left to the permissions of the socket file
+ */
+#[cfg(unix)]
+async fn pf_unix_front_server(
+    src_port: u16,
+    path: PathBuf,
+    route: watch::Receiver<Route>,
+    options: Arc<PortforwardOptions>,
+    counters: Arc<RuleCounters>,
+    connections: Arc<ConnectionTable>,
+) {
+    let src = path.display();
+    // removed when the task is aborted
+    let (listener, _socket_file) = match SocketFile::bind(&path) {
+        Ok(bound) => bound,
+        Err(e) => {
+            warn!("[PORT FORWARDER (src: {src})] failed to bind: {e}");
+            return;
+        }
+    };
+
+    loop {
+        let front_stream = match listener.accept().await {
+            Ok((front_stream, _)) => front_stream,
+            Err(e) => {
+                RuleCounters::incr(&counters.accept_errors);
+                warn!("[PORT FORWARDER (src: {src})] failed to accept: {e}");
+                tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                continue;
+            }
+        };
+
+        let Some(target) = route.borrow().clone() else {
+            continue;
+        };
+        let header = format!(
+            "[PORT FORWARDER (src: {src} --> dst: {})]",
+            format_dst(
+                &target.dst_addrs,
+                target.tunnel.as_ref(),
+                target.pool.as_ref()
+            )
+        );
+
+        let conn = connections.register(PortforwardProtocol::Tcp, src_port, None, &counters);
+        tokio::spawn(spawn_backend_stream(
+            header,
+            FrontStream::Unix(front_stream),
+            target,
+            route.clone(),
+            options.clone(),
+            counters.clone(),
+            conn,
+        ));
+    }
+}
+
+/*
+ *
+ * local client A ---> pf_udp_front server ---[session of A]---> remote server
+ * local client B --->        (src_port)    ---[session of B]---> remote server
+ *
+ * each session has its own backend socket, so that replies can be routed back to the client
+ */
+
+struct UdpSession {
+    to_backend: mpsc::Sender<Vec<u8>>,
+    dst: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+async fn udp_session(
+    header: String,
+    front_socket: Arc<UdpSocket>,
+    client_addr: SocketAddr,
+    backend: UdpSocket,
+    mut from_client: mpsc::Receiver<Vec<u8>>,
+    closed: UnboundedSender<SocketAddr>,
+    mut conn: ConnectionGuard,
+) {
+    info!("{header} New session for {client_addr} (id: {})", conn.id);
+    let mut buf = vec![0; UDP_BUF_SIZE];
 
-                    let backend_stream = spawn_backend_stream(header, client, *dst_ip, *dst_port);
-                    if let Some(backend_streams) = backend_streams.get_mut(dst_port) {
-                        backend_streams.push(backend_stream);
-                    } else {
-                        backend_streams.insert(*dst_port, vec![backend_stream]);
+    loop {
+        tokio::select! {
+            datagram = from_client.recv() => {
+                let Some(datagram) = datagram else {
+                    break;
+                };
+                trace!("{header} [CLIENT] read {} bytes from {client_addr}", datagram.len());
+                match backend.send(&datagram).await {
+                    Ok(n) => conn.bytes.towards_guest().record(&datagram[..n]),
+                    Err(e) => warn!("{header} failed to send to remote: {e}"),
+                }
+            }
+            received = backend.recv(&mut buf) => {
+                let Ok(n) = received else {
+                    continue;
+                };
+                trace!("{header} [REMOTE] read {n} bytes from remote");
+                match front_socket.send_to(&buf[..n], client_addr).await {
+                    Ok(n) => conn.bytes.towards_client().record(&buf[..n]),
+                    Err(e) => warn!("{header} failed to send to {client_addr}: {e}"),
+                }
+            }
+            _ = &mut conn.killed => {
+                info!("{header} Killed the session (id: {})", conn.id);
+                break;
+            }
+            // restarted on every datagram, so this fires only after being idle
+            _ = tokio::time::sleep(UDP_SESSION_IDLE_TIMEOUT) => {
+                break;
+            }
+        }
+    }
+
+    info!("{header} session for {client_addr} is closed");
+    let _ = closed.send(client_addr);
+}
+
+async fn open_udp_session(
+    src: SocketAddr,
+    front_socket: &Arc<UdpSocket>,
+    client_addr: SocketAddr,
+    dst_addrs: &[SocketAddr],
+    closed: &UnboundedSender<SocketAddr>,
+    conn: ConnectionGuard,
+) -> std::io::Result<UdpSession> {
+    // udp can't tell whether the remote is up, so only a family without a route falls back
+    let mut last_err = std::io::Error::new(std::io::ErrorKind::NotFound, "no destination");
+    let mut connected = None;
+    for dst in dst_addrs {
+        let local: SocketAddr = match dst {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let backend = UdpSocket::bind(local).await?;
+        match backend.connect(dst).await {
+            Ok(()) => {
+                connected = Some((backend, *dst));
+                break;
+            }
+            Err(e) => last_err = e,
+        }
+    }
+    let Some((backend, dst)) = connected else {
+        return Err(last_err);
+    };
+    conn.set_guest_addr(dst);
+
+    let (to_backend, from_client) = mpsc::channel(UDP_SESSION_QUEUE_LEN);
+    let header = format!("[UDP PORT FORWARDER (src: {src} --> dst: {dst})]");
+    let task = tokio::spawn(udp_session(
+        header,
+        front_socket.clone(),
+        client_addr,
+        backend,
+        from_client,
+        closed.clone(),
+        conn,
+    ));
+
+    Ok(UdpSession {
+        to_backend,
+        dst,
+        task,
+    })
+}
+
+async fn pf_udp_front_server(
+    src_port: u16,
+    bind_addr: Option<IpAddr>,
+    mut route: watch::Receiver<Route>,
+    counters: Arc<RuleCounters>,
+    connections: Arc<ConnectionTable>,
+) {
+    let src = listen_addr(bind_addr, src_port);
+    let socket = match bind_udp_socket(bind_addr, src_port) {
+        Ok(socket) => Arc::new(socket),
+        Err(e) => {
+            warn!("[UDP PORT FORWARDER (src: {src})] failed to bind: {e}");
+            return;
+        }
+    };
+    let src = socket.local_addr().unwrap_or(src);
+
+    let mut sessions = HashMap::<SocketAddr, UdpSession>::new();
+    let (closed, mut closed_recv) = mpsc::unbounded_channel();
+    let mut buf = vec![0; UDP_BUF_SIZE];
+
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => {
+                let (n, client_addr) = match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        RuleCounters::incr(&counters.accept_errors);
+                        trace!("[UDP PORT FORWARDER (src: {src})] failed to receive: {e}");
+                        continue;
+                    }
+                };
+                let Some(target) = route.borrow().clone() else {
+                    warn!("[UDP PORT FORWARDER (src: {src})] no routing rule, drop {n} bytes from {client_addr}");
+                    continue;
+                };
+
+                // not warned, every datagram of a rejected client would be logged
+                if !target.access.is_allowed(client_addr.ip()) {
+                    RuleCounters::incr(&counters.rejected);
+                    trace!("[UDP PORT FORWARDER (src: {src})] Rejected {n} bytes from {client_addr}");
+                    continue;
+                }
+
+                // a session which has just timed out is replaced with a new one
+                if sessions
+                    .get(&client_addr)
+                    .is_some_and(|session| session.to_backend.is_closed())
+                {
+                    sessions.remove(&client_addr);
+                }
+
+                let session = match sessions.entry(client_addr) {
+                    Entry::Occupied(e) => e.into_mut(),
+                    Entry::Vacant(e) => {
+                        let conn = connections.register(PortforwardProtocol::Udp, src_port, Some(client_addr), &counters);
+                        match open_udp_session(src, &socket, client_addr, &target.dst_addrs, &closed, conn).await {
+                            Ok(session) => {
+                                RuleCounters::incr(&counters.connections);
+                                e.insert(session)
+                            }
+                            Err(err) => {
+                                RuleCounters::incr(&counters.connect_failures);
+                                warn!("[UDP PORT FORWARDER (src: {src})] failed to open a session for {client_addr}: {err}");
+                                continue;
+                            }
+                        }
                     }
+                };
+
+                if session.to_backend.try_send(buf[..n].to_vec()).is_err() {
+                    trace!("[UDP PORT FORWARDER (src: {src})] drop {n} bytes from {client_addr}");
+                }
+            }
+            Some(client_addr) = closed_recv.recv() => {
+                if sessions
+                    .get(&client_addr)
+                    .is_some_and(|session| session.to_backend.is_closed())
+                {
+                    sessions.remove(&client_addr);
                 }
-                PortforwardRequest::UpdateRoutingRule {
-                    src_port,
-                    dst_ip,
-                    dst_port,
-                } => {
-                    info!("[Port Forward Service] Update Routing Table @ localhost:{src_port} -> {dst_ip:?}:{dst_port}");
-                    if let Some((old_dst_ip, old_dst_port)) =
-                        routing_table.insert(src_port, (dst_ip, dst_port))
-                    {
-                        if old_dst_ip != dst_ip || old_dst_port != dst_port {
-                            info!("[Port Forward Service] Routing Rule Changed! @ [src: {src_port}] [old dst: {old_dst_ip:?}:{old_dst_port}] [new dst: {dst_ip:?}:{dst_port}]");
-                            if let Some(e_backend_streams) = backend_streams.get_mut(&src_port) {
-                                info!("[Port Forward Service] Close all backend streams related with old routing rule");
-                                for stream in e_backend_streams {
-                                    stream
-                                        .shutdown(std::net::Shutdown::Both)
-                                        .expect("Failed to shutdown backend stream");
-                                }
-                                backend_streams.remove(&src_port);
+            }
+            Ok(()) = route.changed() => {
+                // sessions of an old routing rule are closed
+                let new_route = route.borrow().clone();
+                sessions.retain(|_, session| {
+                    let alive = new_route
+                        .as_ref()
+                        .is_some_and(|target| target.dst_addrs.contains(&session.dst));
+                    if !alive {
+                        session.task.abort();
+                    }
+                    alive
+                });
+            }
+        }
+    }
+}
+
+struct FrontServer {
+    route: watch::Sender<Route>,
+    listen: Listen,
+    task: JoinHandle<()>,
+    counters: Arc<RuleCounters>,
+    balancer: Balancer,
+}
+
+fn spawn_front_server(
+    protocol: PortforwardProtocol,
+    src_port: u16,
+    listen: Listen,
+    route: watch::Receiver<Route>,
+    options: &Arc<PortforwardOptions>,
+    counters: &Arc<RuleCounters>,
+    connections: &Arc<ConnectionTable>,
+) -> JoinHandle<()> {
+    let bind_addr = match listen {
+        Listen::Addr(bind_addr) => bind_addr,
+        #[cfg(unix)]
+        Listen::Path(path) => {
+            return tokio::spawn(pf_unix_front_server(
+                src_port,
+                path,
+                route,
+                options.clone(),
+                counters.clone(),
+                connections.clone(),
+            ))
+        }
+    };
+
+    match protocol {
+        PortforwardProtocol::Tcp => tokio::spawn(pf_front_server(
+            src_port,
+            bind_addr,
+            route,
+            options.clone(),
+            counters.clone(),
+            connections.clone(),
+        )),
+        PortforwardProtocol::Udp => tokio::spawn(pf_udp_front_server(
+            src_port,
+            bind_addr,
+            route,
+            counters.clone(),
+            connections.clone(),
+        )),
+    }
+}
+
+async fn port_forward_service(
+    options: Arc<PortforwardOptions>,
+    mut recv: UnboundedReceiver<PortforwardRequest>,
+) {
+    // each front server watches its own rule, so that accepts don't go through this service
+    let mut routing_table = HashMap::<(PortforwardProtocol, u16), FrontServer>::new();
+    let connections = Arc::new(ConnectionTable::default());
+
+    while let Some(req) = recv.recv().await {
+        match req {
+            PortforwardRequest::UpdateRoutingRule {
+                protocol,
+                src_port,
+                dst_addrs,
+                bind_addr,
+                access,
+                socket_options,
+                tunnel,
+                proxy_protocol,
+                host_path,
+                pool,
+            } => {
+                let pool = pool.map(|pool| *pool);
+                let dst = format_dst(&dst_addrs, tunnel.as_ref(), pool.as_ref());
+                let listen = match host_path {
+                    #[cfg(unix)]
+                    Some(path) if protocol == PortforwardProtocol::Tcp => Listen::Path(path),
+                    Some(path) => {
+                        warn!("[Port Forward Service] {} can't be listened @ {protocol:?} localhost:{src_port}", path.display());
+                        Listen::Addr(bind_addr)
+                    }
+                    None => Listen::Addr(bind_addr),
+                };
+                info!("[Port Forward Service] Update Routing Table @ {protocol:?} localhost:{src_port} -> {dst}");
+
+                match routing_table.entry((protocol, src_port)) {
+                    Entry::Occupied(mut e) => {
+                        let front_server = e.get_mut();
+                        // the listener is moved, and connections on the old address are left as they are
+                        if front_server.listen != listen {
+                            info!("[Port Forward Service] Rebind @ {protocol:?} localhost:{src_port} on {listen:?}");
+                            // the old listener has to be closed before binding a port which overlaps it
+                            front_server.task.abort();
+                            let _ = (&mut front_server.task).await;
+                            front_server.task = spawn_front_server(
+                                protocol,
+                                src_port,
+                                listen.clone(),
+                                front_server.route.subscribe(),
+                                &options,
+                                &front_server.counters,
+                                &connections,
+                            );
+                            front_server.listen = listen;
+                        }
+
+                        let route = &front_server.route;
+                        if let Some(old) = route.borrow().as_ref() {
+                            if old.dst_addrs != dst_addrs
+                                || old.tunnel != tunnel
+                                || old.pool != pool
+                            {
+                                let old_dst = format_dst(
+                                    &old.dst_addrs,
+                                    old.tunnel.as_ref(),
+                                    old.pool.as_ref(),
+                                );
+                                info!("[Port Forward Service] Routing Rule Changed! @ [src: {src_port}] [old dst: {old_dst}] [new dst: {dst}]");
                             }
                         }
+                        let new_route = Some(Target {
+                            dst_addrs,
+                            access,
+                            socket_options,
+                            tunnel,
+                            proxy_protocol,
+                            pool,
+                            balancer: front_server.balancer.clone(),
+                        });
+                        // connections related with the old rule are closed by themselves
+                        route.send_if_modified(|route| {
+                            let modified = *route != new_route;
+                            *route = new_route;
+                            modified
+                        });
+                    }
+                    Entry::Vacant(e) => {
+                        let balancer = Balancer::default();
+                        let (route, route_recv) = watch::channel(Some(Target {
+                            dst_addrs,
+                            access,
+                            socket_options,
+                            tunnel,
+                            proxy_protocol,
+                            pool,
+                            balancer: balancer.clone(),
+                        }));
+                        let counters = Arc::new(RuleCounters::default());
+                        // idle while the rule has no pool, and ends with the route
+                        if protocol == PortforwardProtocol::Tcp {
+                            tokio::spawn(pool::health_check(
+                                format!("[POOL (src: {src_port})]"),
+                                route.subscribe(),
+                                balancer.clone(),
+                                options.connect_timeout,
+                            ));
+                        }
+                        let task = spawn_front_server(
+                            protocol,
+                            src_port,
+                            listen.clone(),
+                            route_recv,
+                            &options,
+                            &counters,
+                            &connections,
+                        );
+
+                        e.insert(FrontServer {
+                            route,
+                            listen,
+                            task,
+                            counters,
+                            balancer,
+                        });
                     }
                 }
             }
+            PortforwardRequest::RemoveRoutingRule { protocol, src_port } => {
+                if let Some(front_server) = routing_table.remove(&(protocol, src_port)) {
+                    info!("[Port Forward Service] Remove Routing Rule @ {protocol:?} localhost:{src_port}");
+
+                    // closes the listener, which has to be gone before a rule reuses its address
+                    front_server.task.abort();
+                    let _ = front_server.task.await;
+                    if !options.drain_on_remove {
+                        front_server.route.send_replace(None);
+                    }
+                    connections.stop_capture(protocol, src_port);
+                }
+            }
+            PortforwardRequest::GetCounters(reply) => {
+                let _ = reply.send(
+                    routing_table
+                        .iter()
+                        .map(|(key, front_server)| (*key, front_server.counters.snapshot()))
+                        .collect(),
+                );
+            }
+            PortforwardRequest::GetConnections(reply) => {
+                let _ = reply.send(connections.snapshot());
+            }
+            PortforwardRequest::KillConnection(id, reply) => {
+                info!("[Port Forward Service] Kill Connection (id: {id})");
+                let _ = reply.send(connections.kill(id));
+            }
+            PortforwardRequest::StartCapture {
+                protocol,
+                src_port,
+                format,
+                path,
+                max_bytes,
+                reply,
+            } => {
+                let started = if routing_table.contains_key(&(protocol, src_port)) {
+                    connections
+                        .start_capture(protocol, src_port, format, &path, max_bytes)
+                        .map_err(|e| format!("failed to start the capture: {e}"))
+                } else {
+                    Err(format!("no rule on {protocol:?} {src_port}"))
+                };
+                let _ = reply.send(started);
+            }
+            PortforwardRequest::StopCapture(protocol, src_port, reply) => {
+                let _ = reply.send(connections.stop_capture(protocol, src_port));
+            }
         }
+    }
+}
+
+pub fn start_port_forward_service(
+    options: PortforwardOptions,
+    recv: UnboundedReceiver<PortforwardRequest>,
+) {
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .thread_name("port-forward")
+            .enable_all()
+            .build()
+            .expect("failed to build the runtime of port forward service");
+
+        runtime.block_on(port_forward_service(Arc::new(options), recv));
     });
 }
@@ -0,0 +1,461 @@
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use vmc_common::types::{CaptureFormat, PortforwardProtocol};
+
+// LINKTYPE_RAW, packets begin with the IP header
+const LINKTYPE_RAW: u16 = 101;
+// keeps a synthesized IP packet within the 16-bit total length
+const MAX_SEGMENT: usize = 32 * 1024;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ToGuest,
+    ToClient,
+}
+
+// a connection as it appears in a capture
+#[derive(Debug, Clone, Copy)]
+pub struct Endpoints {
+    pub id: u64,
+    pub protocol: PortforwardProtocol,
+    pub client: SocketAddr,
+    // the address the client connected to isn't kept, the port tells the rule
+    pub host: SocketAddr,
+}
+
+impl Endpoints {
+    fn oriented(&self, direction: Direction) -> (SocketAddr, SocketAddr) {
+        match direction {
+            Direction::ToGuest => (self.client, self.host),
+            Direction::ToClient => (self.host, self.client),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CaptureWriter {
+    header: String,
+    format: CaptureFormat,
+    file: BufWriter<File>,
+    written: u64,
+    max_bytes: u64,
+    // the next sequence numbers of each TCP connection, (client, host)
+    flows: HashMap<u64, (u32, u32)>,
+}
+
+impl CaptureWriter {
+    fn create(
+        header: String,
+        format: CaptureFormat,
+        path: &Path,
+        max_bytes: u64,
+    ) -> io::Result<Self> {
+        let mut writer = Self {
+            header,
+            format,
+            // an existing file is never overwritten
+            file: BufWriter::new(OpenOptions::new().write(true).create_new(true).open(path)?),
+            written: 0,
+            max_bytes,
+            flows: HashMap::new(),
+        };
+
+        if format == CaptureFormat::Pcapng {
+            let mut shb = vec![];
+            shb.extend_from_slice(&0x1A2B3C4Du32.to_le_bytes());
+            shb.extend_from_slice(&1u16.to_le_bytes());
+            shb.extend_from_slice(&0u16.to_le_bytes());
+            // the length of the section is unknown
+            shb.extend_from_slice(&(-1i64).to_le_bytes());
+
+            let mut idb = vec![];
+            idb.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+            idb.extend_from_slice(&0u16.to_le_bytes());
+            idb.extend_from_slice(&0u32.to_le_bytes());
+
+            let mut blocks = pcapng_block(0x0A0D0D0A, &shb);
+            blocks.extend(pcapng_block(0x00000001, &idb));
+            if !writer.write(&blocks)? {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "max_bytes is too small for the header",
+                ));
+            }
+        }
+
+        Ok(writer)
+    }
+
+    // returns false instead of writing once the capture would exceed max_bytes
+    fn write(&mut self, data: &[u8]) -> io::Result<bool> {
+        if self.written + data.len() as u64 > self.max_bytes {
+            return Ok(false);
+        }
+        self.file.write_all(data)?;
+        self.written += data.len() as u64;
+
+        Ok(true)
+    }
+
+    fn record(
+        &mut self,
+        endpoints: &Endpoints,
+        direction: Direction,
+        data: &[u8],
+    ) -> io::Result<bool> {
+        let now = Utc::now();
+
+        match self.format {
+            CaptureFormat::Transcript => {
+                let (src, dst) = endpoints.oriented(direction);
+                let mut text = format!(
+                    "{} #{} {src} -> {dst} ({} bytes)\n",
+                    now.format("%Y-%m-%d %H:%M:%S%.6f"),
+                    endpoints.id,
+                    data.len()
+                );
+                text.push_str(&hex_dump(data));
+                self.write(text.as_bytes())
+            }
+            CaptureFormat::Pcapng => {
+                let mut blocks = vec![];
+                match endpoints.protocol {
+                    PortforwardProtocol::Tcp => {
+                        for segment in data.chunks(MAX_SEGMENT) {
+                            for packet in self.tcp_packets(endpoints, direction, segment) {
+                                blocks.extend(enhanced_packet_block(&now, &packet));
+                            }
+                        }
+                    }
+                    PortforwardProtocol::Udp => {
+                        let (src, dst) = endpoints.oriented(direction);
+                        let packet = ip_packet(src, dst, 17, udp_datagram(src, dst, data));
+                        blocks.extend(enhanced_packet_block(&now, &packet));
+                    }
+                }
+                self.write(&blocks)
+            }
+        }
+    }
+
+    // a handshake is made up ahead of the first segment of a connection
+    fn tcp_packets(
+        &mut self,
+        endpoints: &Endpoints,
+        direction: Direction,
+        payload: &[u8],
+    ) -> Vec<Vec<u8>> {
+        let (client, host) = (endpoints.client, endpoints.host);
+        let mut packets = vec![];
+
+        let (client_seq, host_seq) = self.flows.entry(endpoints.id).or_insert_with(|| {
+            packets.extend([
+                tcp_packet(client, host, 0, 0, TCP_SYN, &[]),
+                tcp_packet(host, client, 0, 1, TCP_SYN | TCP_ACK, &[]),
+                tcp_packet(client, host, 1, 1, TCP_ACK, &[]),
+            ]);
+            (1, 1)
+        });
+        let (src, dst, seq, ack) = match direction {
+            Direction::ToGuest => (client, host, client_seq, *host_seq),
+            Direction::ToClient => (host, client, host_seq, *client_seq),
+        };
+        packets.push(tcp_packet(src, dst, *seq, ack, TCP_PSH | TCP_ACK, payload));
+        *seq = seq.wrapping_add(payload.len() as u32);
+
+        packets
+    }
+
+    fn closed(&mut self, endpoints: &Endpoints) -> io::Result<bool> {
+        match self.format {
+            CaptureFormat::Transcript => {
+                let text = format!(
+                    "{} #{} closed\n",
+                    Utc::now().format("%Y-%m-%d %H:%M:%S%.6f"),
+                    endpoints.id
+                );
+                self.write(text.as_bytes())
+            }
+            CaptureFormat::Pcapng => {
+                let Some((client_seq, host_seq)) = self.flows.remove(&endpoints.id) else {
+                    return Ok(true);
+                };
+                let now = Utc::now();
+                let (client, host) = (endpoints.client, endpoints.host);
+                let fin = TCP_FIN | TCP_ACK;
+                let (client_fin, host_fin) = (client_seq.wrapping_add(1), host_seq.wrapping_add(1));
+
+                let mut blocks = vec![];
+                for packet in [
+                    tcp_packet(client, host, client_seq, host_seq, fin, &[]),
+                    tcp_packet(host, client, host_seq, client_fin, fin, &[]),
+                    tcp_packet(client, host, client_fin, host_fin, TCP_ACK, &[]),
+                ] {
+                    blocks.extend(enhanced_packet_block(&now, &packet));
+                }
+                self.write(&blocks)
+            }
+        }
+    }
+}
+
+// the capture of a rule, the connections registered before it is started are captured as well
+#[derive(Debug, Default)]
+pub struct Capture {
+    // checked first, so that connections of a rule which isn't captured don't take the lock
+    active: AtomicBool,
+    writer: Mutex<Option<CaptureWriter>>,
+}
+
+impl Capture {
+    // replaces the running capture of the rule if any
+    pub fn start(
+        &self,
+        header: String,
+        format: CaptureFormat,
+        path: &Path,
+        max_bytes: u64,
+    ) -> io::Result<()> {
+        let writer = CaptureWriter::create(header, format, path, max_bytes)?;
+        info!("{} capturing into {}", writer.header, path.display());
+
+        let mut current = self.writer.lock().unwrap();
+        if let Some(mut old) = current.replace(writer) {
+            let _ = old.file.flush();
+        }
+        self.active.store(true, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    // returns the bytes written, None if the rule isn't captured
+    pub fn stop(&self) -> Option<u64> {
+        let mut writer = self.writer.lock().unwrap().take()?;
+        self.active.store(false, Ordering::Relaxed);
+
+        if let Err(e) = writer.file.flush() {
+            warn!("{} failed to write the capture: {e}", writer.header);
+        }
+        info!(
+            "{} capture stopped, {} bytes written",
+            writer.header, writer.written
+        );
+
+        Some(writer.written)
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub fn record(&self, endpoints: &Endpoints, direction: Direction, data: &[u8]) {
+        self.with_writer(|writer| writer.record(endpoints, direction, data));
+    }
+
+    pub fn closed(&self, endpoints: &Endpoints) {
+        self.with_writer(|writer| writer.closed(endpoints));
+    }
+
+    // the capture stops itself when it is full or fails to write
+    fn with_writer(&self, f: impl FnOnce(&mut CaptureWriter) -> io::Result<bool>) {
+        if !self.is_active() {
+            return;
+        }
+
+        let mut guard = self.writer.lock().unwrap();
+        let Some(writer) = guard.as_mut() else {
+            return;
+        };
+        match f(writer) {
+            Ok(true) => return,
+            Ok(false) => info!(
+                "{} capture reached {} bytes",
+                writer.header, writer.max_bytes
+            ),
+            Err(e) => warn!("{} failed to write the capture: {e}", writer.header),
+        }
+
+        let mut writer = guard.take().unwrap();
+        self.active.store(false, Ordering::Relaxed);
+        let _ = writer.file.flush();
+    }
+}
+
+/*
+ * 0000  47 45 54 20 2f 20 48 54  54 50 2f 31 2e 31 0d 0a  |GET / HTTP/1.1..|
+ */
+fn hex_dump(data: &[u8]) -> String {
+    let mut text = String::new();
+
+    for (i, line) in data.chunks(16).enumerate() {
+        let mut hex = String::new();
+        for (j, byte) in line.iter().enumerate() {
+            if j == 8 {
+                hex.push(' ');
+            }
+            hex.push_str(&format!("{byte:02x} "));
+        }
+        let ascii: String = line
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        text.push_str(&format!("{:04x}  {hex:<49} |{ascii}|\n", i * 16));
+    }
+
+    text
+}
+
+fn pcapng_block(block_type: u32, body: &[u8]) -> Vec<u8> {
+    let padding = (4 - body.len() % 4) % 4;
+    let total_len = (12 + body.len() + padding) as u32;
+
+    let mut block = Vec::with_capacity(total_len as usize);
+    block.extend_from_slice(&block_type.to_le_bytes());
+    block.extend_from_slice(&total_len.to_le_bytes());
+    block.extend_from_slice(body);
+    block.extend(std::iter::repeat_n(0, padding));
+    block.extend_from_slice(&total_len.to_le_bytes());
+
+    block
+}
+
+// timestamps are in microseconds, the default resolution of an interface
+fn enhanced_packet_block(at: &DateTime<Utc>, packet: &[u8]) -> Vec<u8> {
+    let micros = at.timestamp_micros() as u64;
+
+    let mut body = Vec::with_capacity(20 + packet.len());
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(micros as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(packet);
+
+    pcapng_block(0x00000006, &body)
+}
+
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+fn ip_bytes(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+// the checksum of TCP and UDP covers a pseudo header of the addresses
+fn transport_checksum(src: SocketAddr, dst: SocketAddr, protocol: u8, segment: &[u8]) -> u16 {
+    let mut pseudo = ip_bytes(src.ip());
+    pseudo.extend(ip_bytes(dst.ip()));
+    if src.is_ipv4() {
+        pseudo.extend_from_slice(&[0, protocol]);
+        pseudo.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+    } else {
+        pseudo.extend_from_slice(&(segment.len() as u32).to_be_bytes());
+        pseudo.extend_from_slice(&[0, 0, 0, protocol]);
+    }
+    pseudo.extend_from_slice(segment);
+
+    checksum(&pseudo)
+}
+
+fn tcp_segment(
+    src: SocketAddr,
+    dst: SocketAddr,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut segment = Vec::with_capacity(20 + payload.len());
+    segment.extend_from_slice(&src.port().to_be_bytes());
+    segment.extend_from_slice(&dst.port().to_be_bytes());
+    segment.extend_from_slice(&seq.to_be_bytes());
+    segment.extend_from_slice(&ack.to_be_bytes());
+    segment.extend_from_slice(&[5 << 4, flags]);
+    segment.extend_from_slice(&u16::MAX.to_be_bytes());
+    segment.extend_from_slice(&[0, 0, 0, 0]);
+    segment.extend_from_slice(payload);
+
+    let sum = transport_checksum(src, dst, 6, &segment);
+    segment[16..18].copy_from_slice(&sum.to_be_bytes());
+
+    segment
+}
+
+fn tcp_packet(
+    src: SocketAddr,
+    dst: SocketAddr,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    ip_packet(src, dst, 6, tcp_segment(src, dst, seq, ack, flags, payload))
+}
+
+fn udp_datagram(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(8 + payload.len());
+    datagram.extend_from_slice(&src.port().to_be_bytes());
+    datagram.extend_from_slice(&dst.port().to_be_bytes());
+    datagram.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(payload);
+
+    let sum = transport_checksum(src, dst, 17, &datagram);
+    datagram[6..8].copy_from_slice(&sum.to_be_bytes());
+
+    datagram
+}
+
+fn ip_packet(src: SocketAddr, dst: SocketAddr, protocol: u8, payload: Vec<u8>) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(40 + payload.len());
+
+    if src.is_ipv4() {
+        packet.extend_from_slice(&[0x45, 0]);
+        packet.extend_from_slice(&((20 + payload.len()) as u16).to_be_bytes());
+        // no fragments, don't fragment
+        packet.extend_from_slice(&[0, 0, 0x40, 0, 64, protocol, 0, 0]);
+        packet.extend(ip_bytes(src.ip()));
+        packet.extend(ip_bytes(dst.ip()));
+        let sum = checksum(&packet);
+        packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    } else {
+        packet.extend_from_slice(&[0x60, 0, 0, 0]);
+        packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[protocol, 64]);
+        packet.extend(ip_bytes(src.ip()));
+        packet.extend(ip_bytes(dst.ip()));
+    }
+    packet.extend(payload);
+
+    packet
+}
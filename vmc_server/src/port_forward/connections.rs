@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::oneshot;
+use vmc_common::types::{CaptureFormat, PortforwardConnection, PortforwardProtocol};
+
+use super::capture::{Capture, Direction, Endpoints};
+use super::RuleCounters;
+
+#[derive(Debug, Default)]
+struct ByteCounts {
+    to_guest: AtomicU64,
+    from_guest: AtomicU64,
+}
+
+// the bytes of a connection, also added to the totals of its rule and written to its capture
+#[derive(Clone)]
+pub struct ConnectionBytes {
+    connection: Arc<ByteCounts>,
+    rule: Arc<RuleCounters>,
+    capture: Arc<Capture>,
+    endpoints: Endpoints,
+}
+
+impl ConnectionBytes {
+    pub fn towards_guest(&self) -> ByteCounter<'_> {
+        ByteCounter {
+            connection: &self.connection.to_guest,
+            rule: &self.rule.bytes_to_guest,
+            capture: &self.capture,
+            endpoints: &self.endpoints,
+            direction: Direction::ToGuest,
+        }
+    }
+
+    pub fn towards_client(&self) -> ByteCounter<'_> {
+        ByteCounter {
+            connection: &self.connection.from_guest,
+            rule: &self.rule.bytes_from_guest,
+            capture: &self.capture,
+            endpoints: &self.endpoints,
+            direction: Direction::ToClient,
+        }
+    }
+}
+
+// counts the bytes of a direction both for the connection and for its rule
+#[derive(Clone, Copy)]
+pub struct ByteCounter<'a> {
+    connection: &'a AtomicU64,
+    rule: &'a AtomicU64,
+    capture: &'a Capture,
+    endpoints: &'a Endpoints,
+    direction: Direction,
+}
+
+impl ByteCounter<'_> {
+    // for the data which can't be captured
+    pub fn add(&self, n: usize) {
+        self.connection.fetch_add(n as u64, Ordering::Relaxed);
+        self.rule.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn record(&self, data: &[u8]) {
+        self.add(data.len());
+        self.capture.record(self.endpoints, self.direction, data);
+    }
+
+    // splice(2) doesn't see the data, so it is not used while this is true
+    pub fn is_captured(&self) -> bool {
+        self.capture.is_active()
+    }
+}
+
+#[derive(Debug)]
+struct LiveConnection {
+    protocol: PortforwardProtocol,
+    host_port: u16,
+    client_addr: Option<SocketAddr>,
+    guest_addr: Option<SocketAddr>,
+    started_at: Instant,
+    bytes: Arc<ByteCounts>,
+    kill: Option<oneshot::Sender<()>>,
+}
+
+// the connections of every rule, so that a connection can be listed and killed by its id
+#[derive(Debug, Default)]
+pub struct ConnectionTable {
+    next_id: AtomicU64,
+    connections: Mutex<HashMap<u64, LiveConnection>>,
+    // kept per rule even while not capturing, so that a capture covers the connections alive when it starts
+    captures: Mutex<HashMap<(PortforwardProtocol, u16), Arc<Capture>>>,
+}
+
+// unregisters the connection when the task handling it is finished
+pub struct ConnectionGuard {
+    pub id: u64,
+    // resolves when the connection is killed
+    pub killed: oneshot::Receiver<()>,
+    pub bytes: ConnectionBytes,
+    table: Arc<ConnectionTable>,
+}
+
+impl ConnectionGuard {
+    pub fn set_guest_addr(&self, guest_addr: SocketAddr) {
+        if let Some(connection) = self.table.connections.lock().unwrap().get_mut(&self.id) {
+            connection.guest_addr = Some(guest_addr);
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.bytes.capture.closed(&self.bytes.endpoints);
+        self.table.connections.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl ConnectionTable {
+    pub fn register(
+        self: &Arc<Self>,
+        protocol: PortforwardProtocol,
+        host_port: u16,
+        // None for a client of a unix socket
+        client_addr: Option<SocketAddr>,
+        rule: &Arc<RuleCounters>,
+    ) -> ConnectionGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let bytes = Arc::new(ByteCounts::default());
+        let (kill, killed) = oneshot::channel();
+        // clients of a dual-stack listener appear as ::ffff:a.b.c.d
+        let client_addr =
+            client_addr.map(|addr| SocketAddr::new(addr.ip().to_canonical(), addr.port()));
+
+        self.connections.lock().unwrap().insert(
+            id,
+            LiveConnection {
+                protocol,
+                host_port,
+                client_addr,
+                guest_addr: None,
+                started_at: Instant::now(),
+                bytes: bytes.clone(),
+                kill: Some(kill),
+            },
+        );
+
+        ConnectionGuard {
+            id,
+            killed,
+            bytes: ConnectionBytes {
+                connection: bytes,
+                rule: rule.clone(),
+                capture: self.capture_of(protocol, host_port),
+                endpoints: Endpoints {
+                    id,
+                    protocol,
+                    // clients of a unix socket are told apart by a made up port
+                    client: client_addr.unwrap_or(SocketAddr::new(
+                        IpAddr::V4(Ipv4Addr::LOCALHOST),
+                        (id % u16::MAX as u64) as u16 + 1,
+                    )),
+                    host: SocketAddr::new(
+                        match client_addr.map(|addr| addr.ip()) {
+                            Some(IpAddr::V6(_)) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                            _ => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                        },
+                        host_port,
+                    ),
+                },
+            },
+            table: self.clone(),
+        }
+    }
+
+    pub fn kill(&self, id: u64) -> bool {
+        let kill = self
+            .connections
+            .lock()
+            .unwrap()
+            .get_mut(&id)
+            .and_then(|connection| connection.kill.take());
+
+        kill.is_some_and(|kill| kill.send(()).is_ok())
+    }
+
+    fn capture_of(&self, protocol: PortforwardProtocol, host_port: u16) -> Arc<Capture> {
+        self.captures
+            .lock()
+            .unwrap()
+            .entry((protocol, host_port))
+            .or_default()
+            .clone()
+    }
+
+    pub fn start_capture(
+        &self,
+        protocol: PortforwardProtocol,
+        host_port: u16,
+        format: CaptureFormat,
+        path: &Path,
+        max_bytes: u64,
+    ) -> io::Result<()> {
+        let header = format!("[CAPTURE ({protocol:?} {host_port})]");
+        self.capture_of(protocol, host_port)
+            .start(header, format, path, max_bytes)
+    }
+
+    // returns the bytes written, None if the rule isn't captured
+    pub fn stop_capture(&self, protocol: PortforwardProtocol, host_port: u16) -> Option<u64> {
+        let capture = self
+            .captures
+            .lock()
+            .unwrap()
+            .get(&(protocol, host_port))
+            .cloned()?;
+        capture.stop()
+    }
+
+    // hostnames are left to the caller, the forwarder doesn't know them
+    pub fn snapshot(&self) -> Vec<PortforwardConnection> {
+        let mut connections: Vec<_> = self
+            .connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, connection)| PortforwardConnection {
+                id: *id,
+                hostname: None,
+                protocol: connection.protocol,
+                host_port: connection.host_port,
+                client_addr: connection.client_addr,
+                guest_addr: connection.guest_addr,
+                duration_ms: connection.started_at.elapsed().as_millis() as u64,
+                bytes_to_guest: connection.bytes.to_guest.load(Ordering::Relaxed),
+                bytes_from_guest: connection.bytes.from_guest.load(Ordering::Relaxed),
+            })
+            .collect();
+        connections.sort_by_key(|connection| connection.id);
+
+        connections
+    }
+}
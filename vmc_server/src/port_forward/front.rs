@@ -0,0 +1,115 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use vmc_common::relay::AnyStream;
+
+use super::TcpSocketOptions;
+
+// where a front server listens
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Listen {
+    // None is [::], or 0.0.0.0 without ipv6
+    Addr(Option<IpAddr>),
+    #[cfg(unix)]
+    Path(PathBuf),
+}
+
+// a client accepted by a front server
+#[derive(Debug)]
+pub enum FrontStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl FrontStream {
+    pub async fn shutdown(&mut self) -> io::Result<()> {
+        match self {
+            FrontStream::Tcp(stream) => stream.shutdown().await,
+            #[cfg(unix)]
+            FrontStream::Unix(stream) => stream.shutdown().await,
+        }
+    }
+
+    // a unix socket has none of them
+    pub fn apply_options(&self, options: &TcpSocketOptions) -> io::Result<()> {
+        match self {
+            FrontStream::Tcp(stream) => options.apply(stream),
+            #[cfg(unix)]
+            FrontStream::Unix(_) => Ok(()),
+        }
+    }
+
+    // (client, the address it connected to), None for a unix socket
+    pub fn addrs(&self) -> io::Result<Option<(SocketAddr, SocketAddr)>> {
+        match self {
+            FrontStream::Tcp(stream) => Ok(Some((stream.peer_addr()?, stream.local_addr()?))),
+            #[cfg(unix)]
+            FrontStream::Unix(_) => Ok(None),
+        }
+    }
+
+    // for the blocking threads relaying a channel
+    pub fn into_blocking(self) -> io::Result<AnyStream> {
+        match self {
+            FrontStream::Tcp(stream) => {
+                let stream = stream.into_std()?;
+                stream.set_nonblocking(false)?;
+                Ok(AnyStream::Tcp(stream))
+            }
+            #[cfg(unix)]
+            FrontStream::Unix(stream) => {
+                let stream = stream.into_std()?;
+                stream.set_nonblocking(false)?;
+                Ok(AnyStream::Unix(stream))
+            }
+        }
+    }
+}
+
+// the socket file is left by a listener unless it is removed
+#[cfg(unix)]
+pub struct SocketFile {
+    path: PathBuf,
+    // the file may be replaced by another listener by the time this one is closed
+    dev: u64,
+    ino: u64,
+}
+
+#[cfg(unix)]
+impl SocketFile {
+    // an existing file is never replaced, it may be the socket of someone else
+    pub fn bind(path: &Path) -> io::Result<(tokio::net::UnixListener, Self)> {
+        use std::os::unix::fs::MetadataExt;
+
+        let listener = tokio::net::UnixListener::bind(path)?;
+        let meta = std::fs::symlink_metadata(path)?;
+
+        Ok((
+            listener,
+            Self {
+                path: path.to_path_buf(),
+                dev: meta.dev(),
+                ino: meta.ino(),
+            },
+        ))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        use std::os::unix::fs::MetadataExt;
+
+        if std::fs::symlink_metadata(&self.path)
+            .is_ok_and(|meta| (meta.dev(), meta.ino()) == (self.dev, self.ino))
+        {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
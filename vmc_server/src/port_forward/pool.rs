@@ -0,0 +1,236 @@
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use vmc_common::types::PoolBalance;
+
+use super::{connect_backend, format_dst_addrs, PortforwardOptions, Route, RuleCounters};
+
+// a client which hasn't connected for this long is balanced again
+const STICKY_IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+// the addresses of a member in the order to try, the other family is the fallback
+type Member = Vec<SocketAddr>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolTarget {
+    pub members: Vec<Member>,
+    pub balance: PoolBalance,
+    pub sticky: bool,
+    pub health_check: Option<Duration>,
+}
+
+// keyed by the addresses of the members, so that the state survives changes of the member list
+#[derive(Debug, Default)]
+struct BalancerState {
+    next: usize,
+    active: HashMap<Member, usize>,
+    down: HashSet<Member>,
+    sticky: HashMap<IpAddr, (Member, Instant)>,
+}
+
+// the state of balancing a rule, shared by its connections and its health checks
+#[derive(Debug, Clone, Default)]
+pub struct Balancer(Arc<Mutex<BalancerState>>);
+
+impl PartialEq for Balancer {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Balancer {}
+
+// counted as an active connection of the member until dropped
+pub struct MemberGuard {
+    member: Member,
+    balancer: Balancer,
+}
+
+impl MemberGuard {
+    pub fn addrs(&self) -> &[SocketAddr] {
+        &self.member
+    }
+}
+
+impl Drop for MemberGuard {
+    fn drop(&mut self) {
+        let mut state = self.balancer.0.lock().unwrap();
+        if let Some(active) = state.active.get_mut(&self.member) {
+            *active -= 1;
+            if *active == 0 {
+                state.active.remove(&self.member);
+            }
+        }
+    }
+}
+
+impl Balancer {
+    // Picks a member other than the tried ones, members which are down are skipped unless every one is down.
+    fn pick(
+        &self,
+        pool: &PoolTarget,
+        client_ip: Option<IpAddr>,
+        tried: &[Member],
+    ) -> Option<MemberGuard> {
+        let mut state = self.0.lock().unwrap();
+
+        let candidates: Vec<&Member> = pool.members.iter().filter(|m| !tried.contains(m)).collect();
+        let alive: Vec<&Member> = candidates
+            .iter()
+            .copied()
+            .filter(|m| !state.down.contains(*m))
+            .collect();
+        let candidates = if alive.is_empty() { candidates } else { alive };
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let sticky = client_ip
+            .filter(|_| pool.sticky)
+            .and_then(|ip| state.sticky.get(&ip))
+            .map(|(member, _)| member)
+            .filter(|member| candidates.contains(member))
+            .cloned();
+        let member = match sticky {
+            Some(member) => member,
+            None => {
+                let start = state.next % candidates.len();
+                state.next = state.next.wrapping_add(1);
+                match pool.balance {
+                    PoolBalance::RoundRobin => candidates[start].clone(),
+                    // ties are broken in turn, so that idle members share the load
+                    PoolBalance::LeastConnections => (0..candidates.len())
+                        .map(|i| candidates[(start + i) % candidates.len()])
+                        .min_by_key(|member| state.active.get(*member).copied().unwrap_or(0))
+                        .unwrap()
+                        .clone(),
+                }
+            }
+        };
+
+        if let Some(ip) = client_ip.filter(|_| pool.sticky) {
+            state
+                .sticky
+                .retain(|_, (_, used)| used.elapsed() < STICKY_IDLE_TIMEOUT);
+            state.sticky.insert(ip, (member.clone(), Instant::now()));
+        }
+        *state.active.entry(member.clone()).or_default() += 1;
+
+        Some(MemberGuard {
+            member,
+            balancer: self.clone(),
+        })
+    }
+
+    // returns whether the member was the other way
+    fn set_down(&self, member: &Member, down: bool) -> bool {
+        let mut state = self.0.lock().unwrap();
+        if down {
+            state.down.insert(member.clone())
+        } else {
+            state.down.remove(member)
+        }
+    }
+}
+
+// Connects to a member picked for the client, another member is tried when it fails.
+pub async fn connect_member(
+    header: &str,
+    pool: &PoolTarget,
+    balancer: &Balancer,
+    client_ip: Option<IpAddr>,
+    options: &PortforwardOptions,
+    counters: &RuleCounters,
+) -> Option<(TcpStream, MemberGuard)> {
+    // the other members are tried instead of retrying the failed one
+    let options = PortforwardOptions {
+        connect_retries: 0,
+        ..options.clone()
+    };
+    let mut tried = vec![];
+
+    while let Some(member) = balancer.pick(pool, client_ip, &tried) {
+        if let Some(backend_stream) =
+            connect_backend(header, member.addrs(), &options, counters).await
+        {
+            return Some((backend_stream, member));
+        }
+
+        // brought back by the health checks
+        if pool.health_check.is_some() && balancer.set_down(&member.member, true) {
+            warn!("{header} {} is down", format_dst_addrs(&member.member));
+        }
+        tried.push(member.member.clone());
+    }
+
+    None
+}
+
+async fn is_up(member: Member, timeout: Duration) -> bool {
+    for addr in member {
+        if let Ok(Ok(_)) = tokio::time::timeout(timeout, TcpStream::connect(addr)).await {
+            return true;
+        }
+    }
+
+    false
+}
+
+/*
+ *
+ * health check ---> member A (up)
+ *              ---> member B (down, skipped by new connections)
+ *
+ * every member of the pool is connected at each interval, connections are closed right after
+ */
+pub async fn health_check(
+    header: String,
+    mut route: watch::Receiver<Route>,
+    balancer: Balancer,
+    connect_timeout: Duration,
+) {
+    loop {
+        let pool = route
+            .borrow()
+            .as_ref()
+            .and_then(|target| target.pool.clone());
+        let Some((pool, interval)) = pool.and_then(|pool| pool.health_check.map(|i| (pool, i)))
+        else {
+            if route.changed().await.is_err() {
+                return;
+            }
+            continue;
+        };
+
+        let mut probes = JoinSet::new();
+        for member in pool.members {
+            probes.spawn(async move {
+                let up = is_up(member.clone(), connect_timeout).await;
+                (member, up)
+            });
+        }
+        while let Some(Ok((member, up))) = probes.join_next().await {
+            if balancer.set_down(&member, !up) {
+                if up {
+                    info!("{header} {} is back", format_dst_addrs(&member));
+                } else {
+                    warn!("{header} {} is down", format_dst_addrs(&member));
+                }
+            }
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            changed = route.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
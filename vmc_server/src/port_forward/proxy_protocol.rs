@@ -0,0 +1,84 @@
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use vmc_common::types::ProxyProtocolVersion;
+
+/*
+ *
+ * <v1>
+ *   "PROXY TCP4 192.168.2.10 192.168.2.1 51234 8080\r\n"
+ * <v2>
+ *   signature (12) | 0x21 | 0x11 (TCP4) or 0x21 (TCP6) | len (2) | src addr | dst addr | src port | dst port
+ *
+ * src is the client, and dst is the address the client connected to on this machine,
+ * a client of a unix socket has no address and is sent as "PROXY UNKNOWN" (v1) or LOCAL (v2)
+ */
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+// version 2, PROXY command
+const V2_PROXY: u8 = 0x21;
+// version 2, LOCAL command
+const V2_LOCAL: u8 = 0x20;
+const V2_UNSPEC: u8 = 0x00;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+fn to_v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+pub fn header(version: ProxyProtocolVersion, src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    // clients of a dual-stack listener appear as ::ffff:a.b.c.d
+    let (src_ip, dst_ip) = (src.ip().to_canonical(), dst.ip().to_canonical());
+    // both of them are ipv4, or both are ipv6 with a v4 one mapped
+    let (src_ip, dst_ip) = match (src_ip, dst_ip) {
+        (IpAddr::V4(_), IpAddr::V4(_)) => (src_ip, dst_ip),
+        _ => (to_v6(src_ip).into(), to_v6(dst_ip).into()),
+    };
+
+    match version {
+        ProxyProtocolVersion::V1 => {
+            let family = if src_ip.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {family} {src_ip} {dst_ip} {} {}\r\n",
+                src.port(),
+                dst.port()
+            )
+            .into_bytes()
+        }
+        ProxyProtocolVersion::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            header.push(V2_PROXY);
+            match (src_ip, dst_ip) {
+                (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+                    header.push(V2_TCP4);
+                    header.extend(12u16.to_be_bytes());
+                    header.extend(src_ip.octets());
+                    header.extend(dst_ip.octets());
+                }
+                _ => {
+                    header.push(V2_TCP6);
+                    header.extend(36u16.to_be_bytes());
+                    header.extend(to_v6(src_ip).octets());
+                    header.extend(to_v6(dst_ip).octets());
+                }
+            }
+            header.extend(src.port().to_be_bytes());
+            header.extend(dst.port().to_be_bytes());
+
+            header
+        }
+    }
+}
+
+pub fn unknown_header(version: ProxyProtocolVersion) -> Vec<u8> {
+    match version {
+        ProxyProtocolVersion::V1 => b"PROXY UNKNOWN\r\n".to_vec(),
+        ProxyProtocolVersion::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            header.extend([V2_LOCAL, V2_UNSPEC, 0, 0]);
+
+            header
+        }
+    }
+}
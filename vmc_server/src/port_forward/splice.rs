@@ -0,0 +1,136 @@
+use log::trace;
+use std::io;
+use std::os::fd::{AsRawFd, RawFd};
+use tokio::io::{AsyncWriteExt, Interest};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+use super::connections::ByteCounter;
+
+/*
+ *
+ *   src socket --splice--> pipe --splice--> dst socket
+ *
+ * the data is moved in the kernel and never copied into the user space, so once a capture of
+ * the rule starts the rest of the connection falls back to the copy loop, which can record it
+ */
+
+struct Pipe {
+    read_fd: RawFd,
+    write_fd: RawFd,
+}
+
+impl Pipe {
+    fn new(size: usize) -> io::Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // failure only leaves the default size
+        unsafe { libc::fcntl(fds[1], libc::F_SETPIPE_SZ, size as libc::c_int) };
+
+        Ok(Self {
+            read_fd: fds[0],
+            write_fd: fds[1],
+        })
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read_fd);
+            libc::close(self.write_fd);
+        }
+    }
+}
+
+fn splice(fd_in: RawFd, fd_out: RawFd, len: usize) -> io::Result<usize> {
+    let n = unsafe {
+        libc::splice(
+            fd_in,
+            std::ptr::null_mut(),
+            fd_out,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+// None once the connection is captured, the pipe is empty then so the copy loop can take over
+async fn splice_from(
+    src: &TcpStream,
+    pipe: &Pipe,
+    len: usize,
+    bytes: ByteCounter<'_>,
+) -> io::Result<Option<usize>> {
+    loop {
+        src.readable().await?;
+        if bytes.is_captured() {
+            return Ok(None);
+        }
+        match src.try_io(Interest::READABLE, || {
+            splice(src.as_raw_fd(), pipe.write_fd, len)
+        }) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            ret => return ret.map(Some),
+        }
+    }
+}
+
+async fn splice_to(dst: &TcpStream, pipe: &Pipe, mut len: usize) -> io::Result<()> {
+    while len > 0 {
+        dst.writable().await?;
+        match dst.try_io(Interest::WRITABLE, || {
+            splice(pipe.read_fd, dst.as_raw_fd(), len)
+        }) {
+            Ok(n) => len -= n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn pump(
+    header: &str,
+    label: &str,
+    reader: OwnedReadHalf,
+    mut writer: OwnedWriteHalf,
+    buf_size: usize,
+    bytes: ByteCounter<'_>,
+) -> io::Result<()> {
+    let pipe = Pipe::new(buf_size).inspect_err(|e| {
+        trace!("{header} [{label}] failed to create a pipe: {e}");
+    })?;
+
+    loop {
+        let Some(n) = splice_from(reader.as_ref(), &pipe, buf_size, bytes)
+            .await
+            .inspect_err(|e| trace!("{header} [{label}] failed to read: {e}"))?
+        else {
+            trace!("{header} [{label}] captured, fall back to copying");
+            return super::pump(header, label, reader, writer, buf_size, bytes).await;
+        };
+        trace!("{header} [{label}] spliced {n} bytes");
+
+        if n == 0 {
+            trace!("{header} [{label}] EOF, shutdown the peer");
+            return writer.shutdown().await;
+        }
+
+        splice_to(writer.as_ref(), &pipe, n)
+            .await
+            .inspect_err(|e| trace!("{header} [{label}] failed to write: {e}"))?;
+        bytes.add(n);
+    }
+}
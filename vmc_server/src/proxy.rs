@@ -0,0 +1,414 @@
+mod reverse;
+
+pub use reverse::{start_reverse_proxy_service, ReverseProxyOptions};
+
+use log::{info, trace, warn};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use vmc_common::types::IpPreference;
+
+use crate::machine_map::MachineMap;
+use crate::port_forward::{AccessList, GuestAddrs};
+
+// a client which never finishes the handshake is dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_HTTP_HEADER_LEN: usize = 8 * 1024;
+// e.g. EMFILE, retrying immediately would just spin
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTH: u8 = 0;
+const SOCKS_NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const SOCKS_CMD_CONNECT: u8 = 1;
+const SOCKS_ATYP_IPV4: u8 = 1;
+const SOCKS_ATYP_DOMAIN: u8 = 3;
+const SOCKS_ATYP_IPV6: u8 = 4;
+
+#[derive(Debug, Clone)]
+pub struct ProxyOptions {
+    pub bind_addr: IpAddr,
+    pub port: u16,
+    pub access: AccessList,
+    pub connect_timeout: Duration,
+    // the interface of this machine facing the guests, the scope of link-local guest addrs
+    pub ipv6_interface: Option<String>,
+}
+
+// why a destination is not connected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Refusal {
+    // neither a registered hostname nor an address of a guest, the proxy never leaves the guests
+    NotAGuest,
+    Unreachable,
+    Refused,
+    TimedOut,
+}
+
+impl Refusal {
+    fn socks_reply(self) -> u8 {
+        match self {
+            Refusal::NotAGuest => 0x02,
+            Refusal::Unreachable => 0x04,
+            Refusal::Refused => 0x05,
+            Refusal::TimedOut => 0x04,
+        }
+    }
+
+    fn http_status(self) -> &'static str {
+        match self {
+            Refusal::NotAGuest => "403 Forbidden",
+            Refusal::Unreachable | Refusal::Refused => "502 Bad Gateway",
+            Refusal::TimedOut => "504 Gateway Timeout",
+        }
+    }
+}
+
+// hostnames are looked up in the machine map, and an address is accepted only if it is of a guest
+fn resolve_guest(
+    mmap: &Mutex<MachineMap>,
+    ipv6_interface: Option<&str>,
+    host: &str,
+    port: u16,
+) -> Option<Vec<SocketAddr>> {
+    let mmap = mmap.lock().unwrap();
+    let guest_addrs = |hostname: &str| {
+        mmap.get(hostname).map(|ipaddr_pair| {
+            GuestAddrs::from_machine_info(&ipaddr_pair.to_machine_info(hostname), ipv6_interface)
+        })
+    };
+
+    let Ok(ip) = host.parse::<IpAddr>() else {
+        return guest_addrs(host).map(|addrs| addrs.socket_addrs(port, IpPreference::V4));
+    };
+
+    let found = mmap
+        .iter()
+        .filter_map(|(hostname, _)| guest_addrs(hostname))
+        .flat_map(|addrs| addrs.socket_addrs(port, IpPreference::V4))
+        .find(|addr| addr.ip() == ip.to_canonical());
+
+    found.map(|addr| vec![addr])
+}
+
+// every address is tried in order, so an unreachable family falls back to the other
+async fn connect_guest(
+    header: &str,
+    dst_addrs: &[SocketAddr],
+    timeout: Duration,
+) -> Result<TcpStream, Refusal> {
+    let mut refusal = Refusal::Unreachable;
+
+    for dst in dst_addrs {
+        match tokio::time::timeout(timeout, TcpStream::connect(dst)).await {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(e)) => {
+                warn!("{header} failed to connect to {dst}: {e}");
+                refusal = if e.kind() == io::ErrorKind::ConnectionRefused {
+                    Refusal::Refused
+                } else {
+                    Refusal::Unreachable
+                };
+            }
+            Err(_) => {
+                warn!("{header} connecting to {dst} timed out");
+                refusal = Refusal::TimedOut;
+            }
+        }
+    }
+
+    Err(refusal)
+}
+
+struct ProxyContext {
+    mmap: Arc<Mutex<MachineMap>>,
+    options: ProxyOptions,
+}
+
+impl ProxyContext {
+    async fn connect(&self, header: &str, host: &str, port: u16) -> Result<TcpStream, Refusal> {
+        let dst_addrs = resolve_guest(
+            &self.mmap,
+            self.options.ipv6_interface.as_deref(),
+            host,
+            port,
+        )
+        .ok_or(Refusal::NotAGuest)?;
+
+        connect_guest(header, &dst_addrs, self.options.connect_timeout).await
+    }
+}
+
+/*
+ *
+ * <SOCKS5 (RFC 1928), no authentication and CONNECT only>
+ *   client --- [5, n, methods..] ---> proxy
+ *   client <-- [5, 0]            ---- proxy
+ *   client --- [5, 1, 0, atyp, addr.., port] ---> proxy ---> guest
+ *   client <-- [5, rep, 0, 1, bound addr.., port] ---- proxy
+ */
+async fn socks5_handshake(
+    header: &str,
+    client: &mut TcpStream,
+    ctx: &ProxyContext,
+) -> io::Result<Option<TcpStream>> {
+    // the version is already read
+    let n_methods = client.read_u8().await?;
+    let mut methods = vec![0; n_methods as usize];
+    client.read_exact(&mut methods).await?;
+    if !methods.contains(&SOCKS_NO_AUTH) {
+        client
+            .write_all(&[SOCKS_VERSION, SOCKS_NO_ACCEPTABLE_METHOD])
+            .await?;
+        return Ok(None);
+    }
+    client.write_all(&[SOCKS_VERSION, SOCKS_NO_AUTH]).await?;
+
+    let mut request = [0; 4];
+    client.read_exact(&mut request).await?;
+    let [version, cmd, _, atyp] = request;
+    if version != SOCKS_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported SOCKS version {version}"),
+        ));
+    }
+
+    let host = match atyp {
+        SOCKS_ATYP_IPV4 => {
+            let mut octets = [0; 4];
+            client.read_exact(&mut octets).await?;
+            IpAddr::from(octets).to_string()
+        }
+        SOCKS_ATYP_IPV6 => {
+            let mut octets = [0; 16];
+            client.read_exact(&mut octets).await?;
+            IpAddr::from(octets).to_string()
+        }
+        SOCKS_ATYP_DOMAIN => {
+            let len = client.read_u8().await?;
+            let mut domain = vec![0; len as usize];
+            client.read_exact(&mut domain).await?;
+            String::from_utf8_lossy(&domain).into_owned()
+        }
+        _ => {
+            socks5_reply(client, 0x08, None).await?;
+            return Ok(None);
+        }
+    };
+    let port = client.read_u16().await?;
+
+    if cmd != SOCKS_CMD_CONNECT {
+        socks5_reply(client, 0x07, None).await?;
+        return Ok(None);
+    }
+
+    info!("{header} SOCKS5 CONNECT {host}:{port}");
+    match ctx.connect(header, &host, port).await {
+        Ok(guest) => {
+            socks5_reply(client, 0, guest.local_addr().ok()).await?;
+            Ok(Some(guest))
+        }
+        Err(refusal) => {
+            warn!("{header} {host}:{port} is not connected: {refusal:?}");
+            socks5_reply(client, refusal.socks_reply(), None).await?;
+            Ok(None)
+        }
+    }
+}
+
+async fn socks5_reply(
+    client: &mut TcpStream,
+    rep: u8,
+    bound: Option<SocketAddr>,
+) -> io::Result<()> {
+    let bound = bound.unwrap_or(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+    let mut reply = vec![SOCKS_VERSION, rep, 0];
+    match bound.ip() {
+        IpAddr::V4(ip) => {
+            reply.push(SOCKS_ATYP_IPV4);
+            reply.extend(ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            reply.push(SOCKS_ATYP_IPV6);
+            reply.extend(ip.octets());
+        }
+    }
+    reply.extend(bound.port().to_be_bytes());
+
+    client.write_all(&reply).await
+}
+
+// "host:port" or "[v6addr]:port"
+fn parse_authority(authority: &str) -> Option<(String, u16)> {
+    let (host, port) = authority.rsplit_once(':')?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+
+    Some((host.to_string(), port.parse().ok()?))
+}
+
+// Reads until the end of the header, and returns its length, None if it is too long.
+// The bytes following it are left in `head`.
+async fn read_http_head(client: &mut TcpStream, head: &mut Vec<u8>) -> io::Result<Option<usize>> {
+    let mut buf = [0; 1024];
+
+    loop {
+        if let Some(pos) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+            return Ok(Some(pos + 4));
+        }
+        if head.len() > MAX_HTTP_HEADER_LEN {
+            return Ok(None);
+        }
+
+        let n = client.read(&mut buf).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+}
+
+async fn http_reply(client: &mut TcpStream, status: &str) -> io::Result<()> {
+    client
+        .write_all(format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n").as_bytes())
+        .await
+}
+
+/*
+ *
+ * <HTTP CONNECT>
+ *   client --- CONNECT my-vm:3000 HTTP/1.1 ---> proxy ---> guest
+ *   client <-- HTTP/1.1 200 Connection Established ---- proxy
+ *
+ * plain requests like "GET http://my-vm:3000/" are refused, a connection of them may be reused for
+ * other hosts, which a byte relay can't follow
+ */
+async fn http_connect_handshake(
+    header: &str,
+    client: &mut TcpStream,
+    first_byte: u8,
+    ctx: &ProxyContext,
+) -> io::Result<Option<TcpStream>> {
+    let mut head = vec![first_byte];
+    let Some(head_len) = read_http_head(client, &mut head).await? else {
+        http_reply(client, "431 Request Header Fields Too Large").await?;
+        return Ok(None);
+    };
+
+    let request_line = String::from_utf8_lossy(&head[..head_len]);
+    let request_line = request_line.lines().next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let (method, authority) = (parts.next(), parts.next());
+    if method != Some("CONNECT") {
+        warn!("{header} Refused {request_line:?}, only CONNECT is supported");
+        client
+            .write_all(
+                b"HTTP/1.1 405 Method Not Allowed\r\nAllow: CONNECT\r\nContent-Length: 0\r\n\r\n",
+            )
+            .await?;
+        return Ok(None);
+    }
+    let Some((host, port)) = authority.and_then(parse_authority) else {
+        http_reply(client, "400 Bad Request").await?;
+        return Ok(None);
+    };
+
+    info!("{header} HTTP CONNECT {host}:{port}");
+    match ctx.connect(header, &host, port).await {
+        Ok(mut guest) => {
+            client
+                .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                .await?;
+            // sent by the client without waiting for the reply
+            guest.write_all(&head[head_len..]).await?;
+            Ok(Some(guest))
+        }
+        Err(refusal) => {
+            warn!("{header} {host}:{port} is not connected: {refusal:?}");
+            http_reply(client, refusal.http_status()).await?;
+            Ok(None)
+        }
+    }
+}
+
+async fn handle_client(mut client: TcpStream, client_addr: SocketAddr, ctx: Arc<ProxyContext>) {
+    let header = format!("[PROXY (client: {client_addr})]");
+
+    // SOCKS5 starts with its version, and an HTTP request with a method name
+    let handshake = async {
+        match client.read_u8().await? {
+            SOCKS_VERSION => socks5_handshake(&header, &mut client, &ctx).await,
+            first_byte => http_connect_handshake(&header, &mut client, first_byte, &ctx).await,
+        }
+    };
+    let mut guest = match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+        Ok(Ok(Some(guest))) => guest,
+        Ok(Ok(None)) => return,
+        Ok(Err(e)) => {
+            trace!("{header} handshake failed: {e}");
+            return;
+        }
+        Err(_) => {
+            trace!("{header} handshake timed out");
+            return;
+        }
+    };
+
+    match tokio::io::copy_bidirectional(&mut client, &mut guest).await {
+        Ok((to_guest, to_client)) => info!(
+            "{header} transfer is finished! {to_guest} bytes to the guest, {to_client} bytes to the client"
+        ),
+        Err(e) => info!("{header} transfer is aborted: {e}"),
+    }
+}
+
+async fn proxy_service(options: ProxyOptions, mmap: Arc<Mutex<MachineMap>>) {
+    let listen_addr = SocketAddr::new(options.bind_addr, options.port);
+    let listener = match TcpListener::bind(listen_addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("[PROXY] failed to bind {listen_addr}: {e}");
+            return;
+        }
+    };
+    info!("[PROXY] SOCKS5 and HTTP CONNECT proxy is started on {listen_addr}");
+
+    let ctx = Arc::new(ProxyContext { mmap, options });
+    loop {
+        let (client, client_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("[PROXY] failed to accept: {e}");
+                tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                continue;
+            }
+        };
+
+        if !ctx.options.access.is_allowed(client_addr.ip()) {
+            warn!("[PROXY] Rejected a connection from {client_addr}");
+            continue;
+        }
+
+        tokio::spawn(handle_client(client, client_addr, ctx.clone()));
+    }
+}
+
+// resolves the hostnames of guests by the machine map, so that any guest port is reachable from the host
+pub fn start_proxy_service(options: ProxyOptions, mmap: Arc<Mutex<MachineMap>>) {
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .thread_name("proxy")
+            .enable_all()
+            .build()
+            .expect("failed to build the runtime of proxy service");
+
+        runtime.block_on(proxy_service(options, mmap));
+    });
+}
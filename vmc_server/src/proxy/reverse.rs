@@ -0,0 +1,288 @@
+use log::{info, trace, warn};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+
+use super::{
+    connect_guest, read_http_head, resolve_guest, Refusal, ACCEPT_ERROR_BACKOFF, HANDSHAKE_TIMEOUT,
+};
+use crate::machine_map::MachineMap;
+use crate::port_forward::AccessList;
+
+// removed from the request, the connection to the guest is not kept after the response
+const HOP_BY_HOP_HEADERS: [&str; 3] = ["connection", "keep-alive", "proxy-connection"];
+
+#[derive(Debug, Clone)]
+pub struct ReverseProxyOptions {
+    pub bind_addr: IpAddr,
+    pub port: u16,
+    pub access: AccessList,
+    pub connect_timeout: Duration,
+    pub ipv6_interface: Option<String>,
+    // "localhost" routes "my-vm.localhost" to my-vm
+    pub suffixes: Vec<String>,
+    pub guest_port: u16,
+    // hostname -> the port of the guest, guest_port if absent
+    pub guest_ports: HashMap<String, u16>,
+}
+
+impl ReverseProxyOptions {
+    // "my-vm.localhost:8000" -> "my-vm"
+    fn guest_name<'a>(&self, host: &'a str) -> Option<&'a str> {
+        let host = match host.rsplit_once(':') {
+            Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit()) => host,
+            _ => host,
+        };
+        let host = host.strip_suffix('.').unwrap_or(host);
+
+        self.suffixes.iter().find_map(|suffix| {
+            let name = host.strip_suffix(suffix.trim_start_matches('.'))?;
+            name.strip_suffix('.').filter(|name| !name.is_empty())
+        })
+    }
+}
+
+struct RequestHead<'a> {
+    request_line: &'a str,
+    headers: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> RequestHead<'a> {
+    fn parse(head: &'a str) -> Option<Self> {
+        let mut lines = head.split("\r\n").filter(|line| !line.is_empty());
+        let request_line = lines.next()?;
+        let headers = lines
+            .map(|line| {
+                let (name, value) = line.split_once(':')?;
+                Some((name.trim(), value.trim()))
+            })
+            .collect::<Option<_>>()?;
+
+        Some(Self {
+            request_line,
+            headers,
+        })
+    }
+
+    fn get(&self, name: &str) -> Option<&'a str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| *value)
+    }
+
+    // e.g. WebSocket, the connection is left to the guest after the request
+    fn is_upgrade(&self) -> bool {
+        self.get("upgrade").is_some()
+            && self.get("connection").is_some_and(|connection| {
+                connection
+                    .split(',')
+                    .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+            })
+    }
+
+    // the request sent to the guest, with X-Forwarded-* added
+    fn forwarded(&self, client_addr: SocketAddr, host: &str) -> String {
+        let upgrade = self.is_upgrade();
+        let client_ip = client_addr.ip().to_canonical();
+        let mut head = format!("{}\r\n", self.request_line);
+
+        for (name, value) in self.headers.iter() {
+            let lower = name.to_ascii_lowercase();
+            if !upgrade && HOP_BY_HOP_HEADERS.contains(&lower.as_str()) {
+                continue;
+            }
+            if lower.starts_with("x-forwarded-") && lower != "x-forwarded-for" {
+                continue;
+            }
+            if lower == "x-forwarded-for" {
+                head += &format!("{name}: {value}, {client_ip}\r\n");
+            } else {
+                head += &format!("{name}: {value}\r\n");
+            }
+        }
+        if self.get("x-forwarded-for").is_none() {
+            head += &format!("X-Forwarded-For: {client_ip}\r\n");
+        }
+        head += &format!("X-Forwarded-Host: {host}\r\nX-Forwarded-Proto: http\r\n");
+        if !upgrade {
+            head += "Connection: close\r\n";
+        }
+
+        head + "\r\n"
+    }
+}
+
+async fn reply_error(client: &mut TcpStream, status: &str, message: &str) -> io::Result<()> {
+    client
+        .write_all(
+            format!(
+                "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{message}\n",
+                message.len() + 1
+            )
+            .as_bytes(),
+        )
+        .await
+}
+
+struct ReverseProxyContext {
+    mmap: Arc<Mutex<MachineMap>>,
+    options: ReverseProxyOptions,
+}
+
+// Reads a request and connects to the guest named by its Host header, the request is already sent on return.
+async fn route_request(
+    header: &str,
+    client: &mut TcpStream,
+    client_addr: SocketAddr,
+    ctx: &ReverseProxyContext,
+) -> io::Result<Option<TcpStream>> {
+    let mut head = vec![];
+    let Some(head_len) = read_http_head(client, &mut head).await? else {
+        reply_error(
+            client,
+            "431 Request Header Fields Too Large",
+            "too long header",
+        )
+        .await?;
+        return Ok(None);
+    };
+    let request = String::from_utf8_lossy(&head[..head_len]).into_owned();
+    let Some(request) = RequestHead::parse(&request) else {
+        reply_error(client, "400 Bad Request", "malformed request").await?;
+        return Ok(None);
+    };
+
+    let Some(host) = request.get("host") else {
+        reply_error(client, "400 Bad Request", "no Host header").await?;
+        return Ok(None);
+    };
+    let Some(name) = ctx.options.guest_name(host) else {
+        reply_error(client, "404 Not Found", &format!("{host} names no guest")).await?;
+        return Ok(None);
+    };
+    let port = ctx
+        .options
+        .guest_ports
+        .get(name)
+        .copied()
+        .unwrap_or(ctx.options.guest_port);
+    info!("{header} {} -> {name}:{port}", request.request_line);
+
+    let dst_addrs = resolve_guest(&ctx.mmap, ctx.options.ipv6_interface.as_deref(), name, port);
+    let guest = match dst_addrs {
+        Some(dst_addrs) => connect_guest(header, &dst_addrs, ctx.options.connect_timeout).await,
+        None => Err(Refusal::NotAGuest),
+    };
+    let mut guest = match guest {
+        Ok(guest) => guest,
+        Err(Refusal::NotAGuest) => {
+            reply_error(
+                client,
+                "404 Not Found",
+                &format!("no guest is named {name}"),
+            )
+            .await?;
+            return Ok(None);
+        }
+        Err(refusal) => {
+            warn!("{header} {name}:{port} is not connected: {refusal:?}");
+            let message = format!("{name}:{port} is not reachable");
+            reply_error(client, refusal.http_status(), &message).await?;
+            return Ok(None);
+        }
+    };
+
+    guest
+        .write_all(request.forwarded(client_addr, host).as_bytes())
+        .await?;
+    // e.g. a part of the body
+    guest.write_all(&head[head_len..]).await?;
+
+    Ok(Some(guest))
+}
+
+/*
+ *
+ * browser --- GET / (Host: my-vm.localhost) ---> reverse proxy ---> my-vm:guest_port
+ *
+ * one request is sent for each connection to the guest, so a keep-alive connection of the browser
+ * is closed after the response, and the next request is routed by its own Host header
+ */
+async fn handle_client(
+    mut client: TcpStream,
+    client_addr: SocketAddr,
+    ctx: Arc<ReverseProxyContext>,
+) {
+    let header = format!("[HTTP PROXY (client: {client_addr})]");
+
+    let routed = route_request(&header, &mut client, client_addr, &ctx);
+    let mut guest = match tokio::time::timeout(HANDSHAKE_TIMEOUT, routed).await {
+        Ok(Ok(Some(guest))) => guest,
+        Ok(Ok(None)) => return,
+        Ok(Err(e)) => {
+            trace!("{header} failed to read a request: {e}");
+            return;
+        }
+        Err(_) => {
+            trace!("{header} reading a request timed out");
+            return;
+        }
+    };
+
+    match tokio::io::copy_bidirectional(&mut client, &mut guest).await {
+        Ok((to_guest, to_client)) => trace!(
+            "{header} transfer is finished! {to_guest} bytes to the guest, {to_client} bytes to the client"
+        ),
+        Err(e) => info!("{header} transfer is aborted: {e}"),
+    }
+}
+
+async fn reverse_proxy_service(options: ReverseProxyOptions, mmap: Arc<Mutex<MachineMap>>) {
+    let listen_addr = SocketAddr::new(options.bind_addr, options.port);
+    let listener = match TcpListener::bind(listen_addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("[HTTP PROXY] failed to bind {listen_addr}: {e}");
+            return;
+        }
+    };
+    info!("[HTTP PROXY] reverse proxy is started on {listen_addr}");
+
+    let ctx = Arc::new(ReverseProxyContext { mmap, options });
+    loop {
+        let (client, client_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("[HTTP PROXY] failed to accept: {e}");
+                tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                continue;
+            }
+        };
+
+        if !ctx.options.access.is_allowed(client_addr.ip()) {
+            warn!("[HTTP PROXY] Rejected a connection from {client_addr}");
+            continue;
+        }
+
+        tokio::spawn(handle_client(client, client_addr, ctx.clone()));
+    }
+}
+
+// routes HTTP requests by their Host header, so that guests can share a port
+pub fn start_reverse_proxy_service(options: ReverseProxyOptions, mmap: Arc<Mutex<MachineMap>>) {
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .thread_name("reverse-proxy")
+            .enable_all()
+            .build()
+            .expect("failed to build the runtime of reverse proxy service");
+
+        runtime.block_on(reverse_proxy_service(options, mmap));
+    });
+}
@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use vmc_common::mux::Mux;
+use vmc_common::protocol::ChannelTarget;
+
+// the tunnel session attached by a guest, empty while the guest is not connected
+#[derive(Debug, Clone, Default)]
+pub struct TunnelSlot(Arc<Mutex<Option<Mux>>>);
+
+// rules are compared by slot, so a reconnected session doesn't look like a routing change
+impl PartialEq for TunnelSlot {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for TunnelSlot {}
+
+impl TunnelSlot {
+    // a live session is never replaced, false if the slot is taken
+    pub fn attach(&self, mux: Mux) -> bool {
+        let mut slot = self.0.lock().unwrap();
+        if slot.is_some() {
+            return false;
+        }
+        *slot = Some(mux);
+        true
+    }
+
+    pub fn detach(&self, mux: &Mux) {
+        let mut slot = self.0.lock().unwrap();
+        if slot.as_ref().is_some_and(|m| m.is_same(mux)) {
+            *slot = None;
+        }
+    }
+
+    pub fn get(&self) -> Option<Mux> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+// hostname -> slot, a slot is kept even while its guest is disconnected
+#[derive(Debug, Default)]
+pub struct TunnelSlots(Mutex<HashMap<String, TunnelSlot>>);
+
+impl TunnelSlots {
+    pub fn slot(&self, hostname: &str) -> TunnelSlot {
+        self.0
+            .lock()
+            .unwrap()
+            .entry(hostname.to_string())
+            .or_default()
+            .clone()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunnelTarget {
+    pub slot: TunnelSlot,
+    pub target: ChannelTarget,
+}
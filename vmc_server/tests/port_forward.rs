@@ -0,0 +1,1512 @@
+/*
+ * Forwarded TCP connections and UDP sessions against servers on the loopback.
+ *
+ *   client ---> port forwarder (src_port) ---> loopback server (dst_port)
+ */
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket,
+};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::oneshot;
+use vmc_common::mux::Mux;
+use vmc_common::protocol::{ChannelTarget, TunnelFrame};
+use vmc_common::relay::AnyStream;
+use vmc_common::types::{
+    CaptureFormat, PoolBalance, PortforwardConnection, PortforwardCounters, PortforwardProtocol,
+    ProxyProtocolVersion, SerializedDataContainer,
+};
+use vmc_server::port_forward::{
+    start_port_forward_service, AccessList, PoolTarget, PortforwardOptions, PortforwardRequest,
+    TcpSocketOptions,
+};
+use vmc_server::tunnel::{TunnelSlot, TunnelTarget};
+
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn spawn_server<F>(handler: F) -> u16
+where
+    F: Fn(TcpStream) + Send + Sync + Copy + 'static,
+{
+    spawn_server_on(Ipv4Addr::LOCALHOST.into(), handler)
+}
+
+fn spawn_server_on<F>(ip: IpAddr, handler: F) -> u16
+where
+    F: Fn(TcpStream) + Send + Sync + Copy + 'static,
+{
+    let listener = TcpListener::bind((ip, 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            stream.set_read_timeout(Some(IO_TIMEOUT)).unwrap();
+            thread::spawn(move || handler(stream));
+        }
+    });
+
+    port
+}
+
+fn unused_port() -> u16 {
+    TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+struct Rule {
+    dst_addrs: Vec<SocketAddr>,
+    bind_addr: Option<IpAddr>,
+    access: AccessList,
+    socket_options: TcpSocketOptions,
+    tunnel: Option<TunnelTarget>,
+    proxy_protocol: Option<ProxyProtocolVersion>,
+    host_path: Option<PathBuf>,
+    pool: Option<PoolTarget>,
+}
+
+impl Rule {
+    fn to(dst_addrs: Vec<SocketAddr>) -> Self {
+        Self {
+            dst_addrs,
+            bind_addr: None,
+            access: AccessList::default(),
+            socket_options: TcpSocketOptions::default(),
+            tunnel: None,
+            proxy_protocol: None,
+            host_path: None,
+            pool: None,
+        }
+    }
+}
+
+fn spawn_forwarder(
+    options: PortforwardOptions,
+    socket_options: TcpSocketOptions,
+    dst_port: u16,
+) -> u16 {
+    spawn_forwarder_with(
+        options,
+        Rule {
+            socket_options,
+            ..Rule::to(vec![SocketAddr::from((Ipv4Addr::LOCALHOST, dst_port))])
+        },
+    )
+}
+
+fn spawn_forwarder_with(options: PortforwardOptions, rule: Rule) -> u16 {
+    let (src_port, pf_req) = start_forwarder(options, rule);
+
+    // keeps the service alive
+    std::mem::forget(pf_req);
+
+    src_port
+}
+
+fn update_rule(pf_req: &UnboundedSender<PortforwardRequest>, src_port: u16, rule: Rule) {
+    pf_req
+        .send(PortforwardRequest::UpdateRoutingRule {
+            protocol: PortforwardProtocol::Tcp,
+            src_port,
+            dst_addrs: rule.dst_addrs,
+            bind_addr: rule.bind_addr,
+            access: rule.access,
+            socket_options: rule.socket_options,
+            tunnel: rule.tunnel,
+            proxy_protocol: rule.proxy_protocol,
+            host_path: rule.host_path,
+            pool: rule.pool.map(Box::new),
+        })
+        .unwrap();
+}
+
+fn start_forwarder(
+    options: PortforwardOptions,
+    rule: Rule,
+) -> (u16, UnboundedSender<PortforwardRequest>) {
+    let src_port = unused_port();
+    let (pf_req, pf_recv) = unbounded_channel();
+    start_port_forward_service(options, pf_recv);
+
+    let (bind_addr, host_path) = (rule.bind_addr, rule.host_path.clone());
+    update_rule(&pf_req, src_port, rule);
+
+    // the probe is also forwarded, so servers have to put up with an empty connection
+    let probe_ip = bind_addr.unwrap_or(Ipv4Addr::LOCALHOST.into());
+    let probe = || match &host_path {
+        Some(path) => AnyStream::connect(&format!("unix:{}", path.display()), IO_TIMEOUT),
+        None => AnyStream::connect(&SocketAddr::new(probe_ip, src_port).to_string(), IO_TIMEOUT),
+    };
+    while probe().is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    (src_port, pf_req)
+}
+
+fn connect(port: u16) -> TcpStream {
+    connect_to(Ipv4Addr::LOCALHOST.into(), port)
+}
+
+fn connect_to(ip: IpAddr, port: u16) -> TcpStream {
+    let stream = TcpStream::connect((ip, port)).unwrap();
+    stream.set_read_timeout(Some(IO_TIMEOUT)).unwrap();
+
+    stream
+}
+
+fn engines() -> Vec<PortforwardOptions> {
+    let mut engines = vec![
+        PortforwardOptions::default(),
+        PortforwardOptions {
+            buf_size: 1024,
+            ..Default::default()
+        },
+    ];
+    if cfg!(target_os = "linux") {
+        engines.push(PortforwardOptions {
+            use_splice: true,
+            ..Default::default()
+        });
+    }
+
+    engines
+}
+
+// replies the number of bytes received before EOF
+fn count_until_eof(mut stream: TcpStream) {
+    let mut received = vec![];
+    if stream.read_to_end(&mut received).is_ok() {
+        let _ = write!(stream, "received {}", received.len());
+    }
+}
+
+#[test]
+fn client_half_close_is_propagated() {
+    let server_port = spawn_server(count_until_eof);
+
+    for options in engines() {
+        let port = spawn_forwarder(options, TcpSocketOptions::default(), server_port);
+        let mut stream = connect(port);
+
+        stream.write_all(&[7; 100 * 1024]).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "received 102400");
+    }
+}
+
+#[test]
+fn server_half_close_is_propagated() {
+    static RECEIVED: std::sync::Mutex<Option<Sender<Vec<u8>>>> = std::sync::Mutex::new(None);
+
+    // greets, half-closes and keeps reading
+    fn greet_and_read(mut stream: TcpStream) {
+        if stream
+            .write_all(b"hello")
+            .and_then(|_| stream.shutdown(Shutdown::Write))
+            .is_err()
+        {
+            return;
+        }
+
+        let mut received = vec![];
+        if stream.read_to_end(&mut received).is_ok() && !received.is_empty() {
+            let sender = RECEIVED.lock().unwrap().clone().unwrap();
+            sender.send(received).unwrap();
+        }
+    }
+
+    let (sender, receiver) = channel();
+    *RECEIVED.lock().unwrap() = Some(sender);
+    let server_port = spawn_server(greet_and_read);
+
+    for options in engines() {
+        let port = spawn_forwarder(options, TcpSocketOptions::default(), server_port);
+        let mut stream = connect(port);
+
+        let mut greeting = vec![];
+        stream.read_to_end(&mut greeting).unwrap();
+        assert_eq!(greeting, b"hello");
+
+        stream.write_all(b"world").unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(receiver.recv_timeout(IO_TIMEOUT).unwrap(), b"world");
+    }
+}
+
+fn echo(mut stream: TcpStream) {
+    let mut reader = stream.try_clone().unwrap();
+    if io::copy(&mut reader, &mut stream).is_ok() {
+        let _ = stream.shutdown(Shutdown::Write);
+    }
+}
+
+fn echo_through(port: u16, data: &[u8]) -> Vec<u8> {
+    echo_through_stream(connect(port), data)
+}
+
+fn echo_through_stream(mut stream: TcpStream, data: &[u8]) -> Vec<u8> {
+    let mut writer = stream.try_clone().unwrap();
+    let data = data.to_vec();
+    let sender = thread::spawn(move || {
+        writer.write_all(&data).unwrap();
+        writer.shutdown(Shutdown::Write).unwrap();
+    });
+
+    let mut echoed = vec![];
+    stream.read_to_end(&mut echoed).unwrap();
+    sender.join().unwrap();
+
+    echoed
+}
+
+#[test]
+fn large_transfer_is_intact() {
+    let server_port = spawn_server(echo);
+    let data: Vec<u8> = (0..16 * 1024 * 1024u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+        .collect();
+
+    for options in engines() {
+        let port = spawn_forwarder(options, TcpSocketOptions::default(), server_port);
+        assert!(echo_through(port, &data) == data);
+    }
+}
+
+#[test]
+fn concurrent_connections_are_intact() {
+    let server_port = spawn_server(echo);
+
+    for options in engines() {
+        let port = spawn_forwarder(options, TcpSocketOptions::default(), server_port);
+        let clients: Vec<_> = (0..64u8)
+            .map(|i| {
+                thread::spawn(move || {
+                    let data = vec![i; 256 * 1024];
+                    echo_through(port, &data) == data
+                })
+            })
+            .collect();
+
+        assert!(clients.into_iter().all(|client| client.join().unwrap()));
+    }
+}
+
+#[test]
+fn socket_options_are_applied() {
+    let server_port = spawn_server(echo);
+    let socket_options = TcpSocketOptions {
+        nodelay: true,
+        keepalive: Some(Duration::from_secs(30)),
+    };
+
+    for options in engines() {
+        let port = spawn_forwarder(options, socket_options, server_port);
+        assert_eq!(echo_through(port, b"ping"), b"ping");
+    }
+}
+
+#[test]
+fn ipv6_target_is_reachable_from_both_families() {
+    let server_port = spawn_server_on(Ipv6Addr::LOCALHOST.into(), echo);
+    let port = spawn_forwarder_with(
+        PortforwardOptions::default(),
+        Rule::to(vec![SocketAddr::from((Ipv6Addr::LOCALHOST, server_port))]),
+    );
+
+    for client_ip in [
+        IpAddr::from(Ipv4Addr::LOCALHOST),
+        Ipv6Addr::LOCALHOST.into(),
+    ] {
+        let stream = connect_to(client_ip, port);
+        assert_eq!(echo_through_stream(stream, b"ping"), b"ping");
+    }
+}
+
+#[test]
+fn unreachable_family_falls_back_to_the_other() {
+    let server_port = spawn_server(echo);
+    let port = spawn_forwarder_with(
+        PortforwardOptions::default(),
+        Rule::to(vec![
+            SocketAddr::from((Ipv6Addr::LOCALHOST, unused_port())),
+            SocketAddr::from((Ipv4Addr::LOCALHOST, server_port)),
+        ]),
+    );
+
+    assert_eq!(echo_through(port, b"ping"), b"ping");
+}
+
+fn local_rule(dst_port: u16) -> Rule {
+    Rule::to(vec![SocketAddr::from((Ipv4Addr::LOCALHOST, dst_port))])
+}
+
+// closed without a byte, by either of FIN or RST
+fn is_rejected(port: u16) -> bool {
+    let mut stream = connect(port);
+    let mut received = vec![];
+
+    match stream.read_to_end(&mut received) {
+        Ok(_) => received.is_empty(),
+        Err(e) => e.kind() != io::ErrorKind::WouldBlock && e.kind() != io::ErrorKind::TimedOut,
+    }
+}
+
+#[test]
+fn rejected_client_never_reaches_the_server() {
+    static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_connection(_stream: TcpStream) {
+        CONNECTIONS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    let server_port = spawn_server(count_connection);
+    let port = spawn_forwarder_with(
+        PortforwardOptions::default(),
+        Rule {
+            access: AccessList::parse(&["10.0.0.0/8".to_string()], &[]).unwrap(),
+            ..local_rule(server_port)
+        },
+    );
+
+    assert!(is_rejected(port));
+    assert_eq!(CONNECTIONS.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn deny_list_wins_over_allow_list() {
+    let server_port = spawn_server(echo);
+    let allowed = spawn_forwarder_with(
+        PortforwardOptions::default(),
+        Rule {
+            access: AccessList::parse(&["127.0.0.0/8".to_string()], &["10.0.0.0/8".to_string()])
+                .unwrap(),
+            ..local_rule(server_port)
+        },
+    );
+    let denied = spawn_forwarder_with(
+        PortforwardOptions::default(),
+        Rule {
+            access: AccessList::parse(&["127.0.0.0/8".to_string()], &["127.0.0.1".to_string()])
+                .unwrap(),
+            ..local_rule(server_port)
+        },
+    );
+
+    assert_eq!(echo_through(allowed, b"ping"), b"ping");
+    assert!(is_rejected(denied));
+}
+
+#[test]
+fn bind_addr_limits_the_listener() {
+    let server_port = spawn_server(echo);
+    let port = spawn_forwarder_with(
+        PortforwardOptions::default(),
+        Rule {
+            bind_addr: Some(Ipv4Addr::LOCALHOST.into()),
+            ..local_rule(server_port)
+        },
+    );
+
+    assert_eq!(echo_through(port, b"ping"), b"ping");
+    assert!(TcpStream::connect((Ipv6Addr::LOCALHOST, port)).is_err());
+}
+
+#[test]
+fn rebound_rule_keeps_listening() {
+    let server_port = spawn_server(echo);
+    let (port, pf_req) = start_forwarder(PortforwardOptions::default(), local_rule(server_port));
+
+    // the new listener overlaps the old one, so it can only be bound once the old one is closed
+    for bind_addr in [Some(Ipv4Addr::LOCALHOST.into()), None].repeat(5) {
+        update_rule(
+            &pf_req,
+            port,
+            Rule {
+                bind_addr,
+                ..local_rule(server_port)
+            },
+        );
+        // connections accepted by the old listener are reset when it is closed
+        assert!(wait_until(|| {
+            let mut echoed = vec![];
+            TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+                .and_then(|mut stream| {
+                    stream.write_all(b"ping")?;
+                    stream.shutdown(Shutdown::Write)?;
+                    stream.read_to_end(&mut echoed)
+                })
+                .is_ok_and(|_| echoed == b"ping")
+        }));
+    }
+}
+
+// counters are updated after the write, so the peer may see the bytes a bit earlier
+fn wait_until<F: Fn() -> bool>(cond: F) -> bool {
+    let deadline = Instant::now() + IO_TIMEOUT;
+    while !cond() {
+        if Instant::now() > deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    true
+}
+
+fn connection_of(
+    pf_req: &UnboundedSender<PortforwardRequest>,
+    stream: &TcpStream,
+) -> Option<PortforwardConnection> {
+    let (reply, reply_recv) = oneshot::channel();
+    pf_req
+        .send(PortforwardRequest::GetConnections(reply))
+        .unwrap();
+    let client_addr = stream.local_addr().unwrap();
+
+    reply_recv
+        .blocking_recv()
+        .unwrap()
+        .into_iter()
+        .find(|connection| connection.client_addr == Some(client_addr))
+}
+
+fn counters_of(pf_req: &UnboundedSender<PortforwardRequest>, port: u16) -> PortforwardCounters {
+    let (reply, reply_recv) = oneshot::channel();
+    pf_req.send(PortforwardRequest::GetCounters(reply)).unwrap();
+
+    reply_recv.blocking_recv().unwrap()[&(PortforwardProtocol::Tcp, port)].clone()
+}
+
+#[test]
+fn refused_backend_is_retried_and_counted() {
+    let options = PortforwardOptions {
+        connect_retries: 1,
+        connect_retry_interval: Duration::from_millis(10),
+        ..Default::default()
+    };
+    let (port, pf_req) = start_forwarder(options, local_rule(unused_port()));
+
+    // the client is closed instead of being left hanging
+    assert!(is_rejected(port));
+
+    // two attempts for each of the probe and the client
+    assert!(wait_until(
+        || counters_of(&pf_req, port).connect_failures == 4
+    ));
+    assert_eq!(counters_of(&pf_req, port).connect_timeouts, 0);
+
+    // the service outlives the failures
+    let server_port = spawn_server(echo);
+    change_destination(&pf_req, port, server_port);
+    assert!(wait_until(|| echo_through(port, b"ping") == b"ping"));
+}
+
+#[test]
+fn unresponsive_backend_times_out() {
+    // SYNs beyond the backlog of a listener which never accepts are dropped
+    let listener =
+        socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+    listener
+        .bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0)).into())
+        .unwrap();
+    listener.listen(0).unwrap();
+    let backend = listener.local_addr().unwrap().as_socket().unwrap();
+    let _queued: Vec<_> = (0..4)
+        .filter_map(|_| TcpStream::connect_timeout(&backend, Duration::from_millis(100)).ok())
+        .collect();
+
+    let options = PortforwardOptions {
+        connect_timeout: Duration::from_millis(100),
+        connect_retries: 0,
+        ..Default::default()
+    };
+    let (port, pf_req) = start_forwarder(options, Rule::to(vec![backend]));
+
+    assert!(is_rejected(port));
+    assert!(wait_until(
+        || counters_of(&pf_req, port).connect_timeouts == 2
+    ));
+}
+
+#[test]
+fn traffic_is_counted_per_connection_and_rule() {
+    let server_port = spawn_server(echo);
+
+    for options in engines() {
+        let (port, pf_req) = start_forwarder(options, local_rule(server_port));
+        let mut stream = connect(port);
+
+        stream.write_all(&[7; 1000]).unwrap();
+        stream.read_exact(&mut [0; 1000]).unwrap();
+
+        assert!(wait_until(|| connection_of(&pf_req, &stream).is_some_and(
+            |c| c.bytes_to_guest == 1000 && c.bytes_from_guest == 1000
+        )));
+        let connection = connection_of(&pf_req, &stream).unwrap();
+        assert_eq!(connection.host_port, port);
+        assert_eq!(
+            connection.guest_addr,
+            Some(SocketAddr::from((Ipv4Addr::LOCALHOST, server_port)))
+        );
+
+        let (reply, reply_recv) = oneshot::channel();
+        pf_req.send(PortforwardRequest::GetCounters(reply)).unwrap();
+        let counters =
+            reply_recv.blocking_recv().unwrap()[&(PortforwardProtocol::Tcp, port)].clone();
+        assert!(counters.bytes_to_guest >= 1000 && counters.bytes_from_guest >= 1000);
+    }
+}
+
+#[test]
+fn killed_connection_is_closed() {
+    let server_port = spawn_server(echo);
+    let (port, pf_req) = start_forwarder(PortforwardOptions::default(), local_rule(server_port));
+    let mut stream = connect(port);
+
+    stream.write_all(b"ping").unwrap();
+    stream.read_exact(&mut [0; 4]).unwrap();
+    let id = connection_of(&pf_req, &stream).unwrap().id;
+
+    let kill = |id| {
+        let (reply, reply_recv) = oneshot::channel();
+        pf_req
+            .send(PortforwardRequest::KillConnection(id, reply))
+            .unwrap();
+        reply_recv.blocking_recv().unwrap()
+    };
+    assert!(kill(id));
+
+    let mut received = vec![];
+    assert!(stream
+        .read_to_end(&mut received)
+        .map_or(true, |_| received.is_empty()));
+    assert!(wait_until(|| connection_of(&pf_req, &stream).is_none()));
+    assert!(!kill(id));
+}
+
+fn change_destination(pf_req: &UnboundedSender<PortforwardRequest>, port: u16, dst_port: u16) {
+    update_rule(pf_req, port, local_rule(dst_port));
+}
+
+fn is_closed(stream: &mut TcpStream) -> bool {
+    let mut received = vec![];
+    stream
+        .read_to_end(&mut received)
+        .map_or(true, |_| received.is_empty())
+}
+
+fn remove_rule(pf_req: &UnboundedSender<PortforwardRequest>, port: u16) {
+    pf_req
+        .send(PortforwardRequest::RemoveRoutingRule {
+            protocol: PortforwardProtocol::Tcp,
+            src_port: port,
+        })
+        .unwrap();
+}
+
+#[test]
+fn removed_rule_closes_listener_and_connections() {
+    let server_port = spawn_server(echo);
+    let (port, pf_req) = start_forwarder(PortforwardOptions::default(), local_rule(server_port));
+    let mut live = connect(port);
+    live.write_all(b"ping").unwrap();
+    live.read_exact(&mut [0; 4]).unwrap();
+
+    remove_rule(&pf_req, port);
+    assert!(is_closed(&mut live));
+    assert!(wait_until(|| TcpStream::connect((
+        Ipv4Addr::LOCALHOST,
+        port
+    ))
+    .is_err()));
+}
+
+#[test]
+fn removed_rule_is_drained_with_drain_on_remove() {
+    let server_port = spawn_server(echo);
+    let options = PortforwardOptions {
+        drain_on_remove: true,
+        ..Default::default()
+    };
+    let (port, pf_req) = start_forwarder(options, local_rule(server_port));
+    let mut live = connect(port);
+    live.write_all(b"ping").unwrap();
+    live.read_exact(&mut [0; 4]).unwrap();
+
+    remove_rule(&pf_req, port);
+    assert!(wait_until(|| TcpStream::connect((
+        Ipv4Addr::LOCALHOST,
+        port
+    ))
+    .is_err()));
+    assert_eq!(echo_through_stream(live, b"pong"), b"pong");
+}
+
+#[test]
+fn connection_of_old_rule_is_closed_at_once() {
+    let old_port = spawn_server(echo);
+    let (port, pf_req) = start_forwarder(PortforwardOptions::default(), local_rule(old_port));
+    let mut stream = connect(port);
+    stream.write_all(b"ping").unwrap();
+    stream.read_exact(&mut [0; 4]).unwrap();
+
+    change_destination(&pf_req, port, spawn_server(echo));
+    assert!(is_closed(&mut stream));
+}
+
+fn tell_port_and_echo(mut stream: TcpStream) {
+    let port = stream.local_addr().unwrap().port();
+    if writeln!(stream, "{port}").is_ok() {
+        echo(stream);
+    }
+}
+
+#[test]
+fn connection_of_old_rule_is_drained() {
+    let drain_timeout = Duration::from_millis(500);
+    let old_port = spawn_server(tell_port_and_echo);
+    let (port, pf_req) = start_forwarder(
+        PortforwardOptions {
+            drain_timeout: Some(drain_timeout),
+            ..Default::default()
+        },
+        local_rule(old_port),
+    );
+    let mut stream = connect(port);
+    assert_eq!(member_of(&stream), old_port);
+
+    let new_port = spawn_server(reply_port);
+    change_destination(&pf_req, port, new_port);
+    let changed_at = Instant::now();
+    // a connection made while the rule is changing may be closed without a member
+    let sent_to = || {
+        let mut line = String::new();
+        BufReader::new(&connect(port)).read_line(&mut line).ok()?;
+        line.trim().parse::<u16>().ok()
+    };
+    assert!(wait_until(|| sent_to() == Some(new_port)));
+
+    // the old destination is still reachable by the existing connection until the timeout
+    stream.write_all(b"pong").unwrap();
+    let mut echoed = [0; 4];
+    stream.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"pong");
+    assert!(is_closed(&mut stream));
+    assert!(changed_at.elapsed() >= drain_timeout);
+}
+
+/*
+ *   client ---> port forwarder (src_port) ---[channel]---> guest session ---> loopback server
+ */
+
+// attaches a session whose guest end connects channels to the loopback, or to a unix socket
+fn attach_guest(slot: &TunnelSlot) {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let guest_stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (host_stream, _) = listener.accept().unwrap();
+
+    let guest = Mux::new(&guest_stream).unwrap();
+    thread::spawn(move || {
+        let _ = guest.serve(guest_stream, |channel, target| {
+            let addr = match target {
+                ChannelTarget::Port(port) => format!("127.0.0.1:{port}"),
+                ChannelTarget::Path(path) => format!("unix:{path}"),
+            };
+            thread::spawn(move || match AnyStream::connect(&addr, IO_TIMEOUT) {
+                Ok(local) => {
+                    if channel.accept().is_ok() {
+                        let _ = channel.relay(local, |_| {}, |_| {});
+                    }
+                }
+                Err(e) => channel.reject(e.to_string()),
+            });
+        });
+    });
+
+    let host = Mux::new(&host_stream).unwrap();
+    assert!(slot.attach(host.clone()));
+    thread::spawn(move || {
+        let _ = host.serve(host_stream, |channel, _| {
+            channel.reject("not allowed".to_string())
+        });
+    });
+}
+
+fn tunnel_rule(slot: &TunnelSlot, guest_port: u16) -> Rule {
+    Rule {
+        tunnel: Some(TunnelTarget {
+            slot: slot.clone(),
+            target: ChannelTarget::Port(guest_port),
+        }),
+        ..Rule::to(vec![])
+    }
+}
+
+#[test]
+fn live_tunnel_session_is_not_replaced() {
+    let server_port = spawn_server(echo);
+    let slot = TunnelSlot::default();
+    attach_guest(&slot);
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let _intruder = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (host_stream, _) = listener.accept().unwrap();
+    assert!(!slot.attach(Mux::new(&host_stream).unwrap()));
+
+    let port = spawn_forwarder_with(
+        PortforwardOptions::default(),
+        tunnel_rule(&slot, server_port),
+    );
+    assert_eq!(
+        echo_through_stream(connect(port), b"still here"),
+        b"still here"
+    );
+}
+
+#[test]
+fn tunneled_transfer_is_intact() {
+    let server_port = spawn_server(echo);
+    let slot = TunnelSlot::default();
+    attach_guest(&slot);
+    let port = spawn_forwarder_with(
+        PortforwardOptions::default(),
+        tunnel_rule(&slot, server_port),
+    );
+
+    // far beyond the window of a channel, so that credits have to be returned
+    let data: Vec<u8> = (0..4 * 1024 * 1024u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+        .collect();
+    let streams: Vec<_> = (0..4).map(|_| connect(port)).collect();
+    let echoed: Vec<_> = streams
+        .into_iter()
+        .map(|stream| {
+            let data = data.clone();
+            thread::spawn(move || echo_through_stream(stream, &data))
+        })
+        .collect();
+
+    for echoed in echoed {
+        assert!(echoed.join().unwrap() == data);
+    }
+}
+
+#[test]
+fn tunneled_connection_is_closed_without_session() {
+    let server_port = spawn_server(echo);
+    let slot = TunnelSlot::default();
+    let (port, pf_req) = start_forwarder(
+        PortforwardOptions::default(),
+        tunnel_rule(&slot, server_port),
+    );
+
+    let mut received = vec![];
+    assert!(connect(port)
+        .read_to_end(&mut received)
+        .map_or(true, |_| received.is_empty()));
+
+    let (reply, reply_recv) = oneshot::channel();
+    pf_req.send(PortforwardRequest::GetCounters(reply)).unwrap();
+    let counters = reply_recv.blocking_recv().unwrap()[&(PortforwardProtocol::Tcp, port)].clone();
+    assert!(counters.connect_failures >= 1);
+
+    // a session attached later is used by the next connection
+    attach_guest(&slot);
+    assert_eq!(echo_through(port, b"hello"), b"hello");
+}
+
+// a session whose peer is driven by the test frame by frame
+fn raw_session() -> (Mux, TcpStream, TcpStream) {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    peer.set_read_timeout(Some(IO_TIMEOUT)).unwrap();
+
+    (Mux::new(&stream).unwrap(), stream, peer)
+}
+
+fn send_frame(peer: &mut TcpStream, frame: &TunnelFrame) {
+    peer.write_all(
+        &SerializedDataContainer::from_serializable_data(frame)
+            .unwrap()
+            .to_one_vec(),
+    )
+    .unwrap();
+}
+
+fn recv_frame(peer: &mut TcpStream) -> TunnelFrame {
+    SerializedDataContainer::from_reader(peer)
+        .unwrap()
+        .to_serializable_data()
+        .unwrap()
+}
+
+#[test]
+fn dropped_channel_is_closed_on_the_peer() {
+    let (mux, stream, mut peer) = raw_session();
+    thread::spawn({
+        let mux = mux.clone();
+        move || {
+            mux.serve(stream, |channel, _| {
+                channel.reject("not allowed".to_string())
+            })
+        }
+    });
+
+    let opening = thread::spawn(move || mux.open(ChannelTarget::Port(80), IO_TIMEOUT));
+    let TunnelFrame::Open { channel, .. } = recv_frame(&mut peer) else {
+        panic!("a channel is not opened");
+    };
+    send_frame(
+        &mut peer,
+        &TunnelFrame::Opened {
+            channel,
+            result: Ok(()),
+        },
+    );
+
+    drop(opening.join().unwrap().unwrap());
+    assert!(matches!(
+        recv_frame(&mut peer),
+        TunnelFrame::Close { channel: closed } if closed == channel
+    ));
+}
+
+#[test]
+fn open_of_a_live_channel_is_refused() {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let local_port = listener.local_addr().unwrap().port();
+    let (mux, stream, mut peer) = raw_session();
+    thread::spawn(move || {
+        mux.serve(stream, |channel, _| {
+            let local = TcpStream::connect((Ipv4Addr::LOCALHOST, local_port)).unwrap();
+            channel.accept().unwrap();
+            thread::spawn(move || channel.relay(AnyStream::Tcp(local), |_| {}, |_| {}));
+        })
+    });
+
+    let open = TunnelFrame::Open {
+        channel: 7,
+        target: ChannelTarget::Port(local_port),
+    };
+    send_frame(&mut peer, &open);
+    assert!(matches!(
+        recv_frame(&mut peer),
+        TunnelFrame::Opened {
+            channel: 7,
+            result: Ok(())
+        }
+    ));
+    let (mut local, _) = listener.accept().unwrap();
+
+    send_frame(&mut peer, &open);
+    assert!(matches!(
+        recv_frame(&mut peer),
+        TunnelFrame::Opened {
+            channel: 7,
+            result: Err(_)
+        }
+    ));
+
+    // the live channel is left as it is
+    send_frame(
+        &mut peer,
+        &TunnelFrame::Data {
+            channel: 7,
+            data: b"ping".to_vec(),
+        },
+    );
+    let mut received = [0; 4];
+    local.read_exact(&mut received).unwrap();
+    assert_eq!(&received, b"ping");
+}
+
+// replies everything received before EOF
+fn reply_received(mut stream: TcpStream) {
+    let mut received = vec![];
+    if stream.read_to_end(&mut received).is_ok() {
+        let _ = stream.write_all(&received);
+    }
+}
+
+fn received_through(port: u16) -> (Vec<u8>, SocketAddr) {
+    let mut stream = connect(port);
+    stream.write_all(b"data").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+
+    let mut received = vec![];
+    stream.read_to_end(&mut received).unwrap();
+    (received, stream.local_addr().unwrap())
+}
+
+#[test]
+fn proxy_protocol_header_carries_client_address() {
+    let server_port = spawn_server(reply_received);
+
+    let port = spawn_forwarder_with(
+        PortforwardOptions::default(),
+        Rule {
+            proxy_protocol: Some(ProxyProtocolVersion::V1),
+            ..local_rule(server_port)
+        },
+    );
+    let (received, client_addr) = received_through(port);
+    assert_eq!(
+        String::from_utf8(received).unwrap(),
+        format!(
+            "PROXY TCP4 127.0.0.1 127.0.0.1 {} {port}\r\ndata",
+            client_addr.port()
+        )
+    );
+
+    let port = spawn_forwarder_with(
+        PortforwardOptions::default(),
+        Rule {
+            proxy_protocol: Some(ProxyProtocolVersion::V2),
+            ..local_rule(server_port)
+        },
+    );
+    let (received, client_addr) = received_through(port);
+    let mut expected = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c\x7f\0\0\x01\x7f\0\0\x01".to_vec();
+    expected.extend(client_addr.port().to_be_bytes());
+    expected.extend(port.to_be_bytes());
+    expected.extend(b"data");
+    assert_eq!(received, expected);
+}
+
+/*
+ *   unix client ---> port forwarder (host_path) ---> loopback server
+ *   client ---> port forwarder (src_port) ---[channel]---> guest session ---> unix server (guest_path)
+ */
+
+#[cfg(unix)]
+fn socket_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("vmc-test-{}-{name}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    path
+}
+
+#[cfg(unix)]
+#[test]
+fn unix_socket_front_reaches_tcp_server() {
+    let server_port = spawn_server(reply_received);
+    let path = socket_path("front");
+    let (src_port, pf_req) = start_forwarder(
+        PortforwardOptions::default(),
+        Rule {
+            proxy_protocol: Some(ProxyProtocolVersion::V1),
+            host_path: Some(path.clone()),
+            ..local_rule(server_port)
+        },
+    );
+
+    let mut stream = UnixStream::connect(&path).unwrap();
+    stream.set_read_timeout(Some(IO_TIMEOUT)).unwrap();
+    stream.write_all(b"data").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut received = vec![];
+    stream.read_to_end(&mut received).unwrap();
+    // a unix client has no address to tell
+    assert_eq!(received, b"PROXY UNKNOWN\r\ndata");
+
+    // the socket file is removed with the rule
+    pf_req
+        .send(PortforwardRequest::RemoveRoutingRule {
+            protocol: PortforwardProtocol::Tcp,
+            src_port,
+        })
+        .unwrap();
+    assert!(wait_until(|| !path.exists()));
+}
+
+// the requests before it are handled by the time it is replied
+fn wait_for_service(pf_req: &UnboundedSender<PortforwardRequest>) {
+    let (reply, reply_recv) = oneshot::channel();
+    pf_req
+        .send(PortforwardRequest::GetConnections(reply))
+        .unwrap();
+    reply_recv.blocking_recv().unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn socket_file_of_others_is_left_as_is() {
+    let server_port = spawn_server(reply_received);
+    let path = socket_path("others");
+    let others = UnixListener::bind(&path).unwrap();
+    let (src_port, pf_req) = start_forwarder(
+        PortforwardOptions::default(),
+        Rule {
+            host_path: Some(path.clone()),
+            ..local_rule(server_port)
+        },
+    );
+
+    remove_rule(&pf_req, src_port);
+    wait_for_service(&pf_req);
+    let _stream = UnixStream::connect(&path).unwrap();
+    others.set_nonblocking(true).unwrap();
+    // the probe of start_forwarder and this one
+    assert!(others.accept().is_ok() && others.accept().is_ok());
+}
+
+#[cfg(unix)]
+#[test]
+fn readded_rule_keeps_its_socket_file() {
+    let server_port = spawn_server(reply_received);
+    let path = socket_path("readded");
+    let rule = || Rule {
+        host_path: Some(path.clone()),
+        ..local_rule(server_port)
+    };
+    let (src_port, pf_req) = start_forwarder(PortforwardOptions::default(), rule());
+
+    // the old listener must not remove the file of the new one
+    for _ in 0..10 {
+        remove_rule(&pf_req, src_port);
+        update_rule(&pf_req, src_port, rule());
+        wait_for_service(&pf_req);
+        assert!(wait_until(|| UnixStream::connect(&path).is_ok()));
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn tunneled_unix_socket_of_guest_is_reachable() {
+    let path = socket_path("guest");
+    let listener = UnixListener::bind(&path).unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                let mut reader = stream.try_clone().unwrap();
+                if io::copy(&mut reader, &mut stream).is_ok() {
+                    let _ = stream.shutdown(Shutdown::Write);
+                }
+            });
+        }
+    });
+
+    let slot = TunnelSlot::default();
+    attach_guest(&slot);
+    let port = spawn_forwarder_with(
+        PortforwardOptions::default(),
+        Rule {
+            tunnel: Some(TunnelTarget {
+                slot: slot.clone(),
+                target: ChannelTarget::Path(path.to_string_lossy().into_owned()),
+            }),
+            ..Rule::to(vec![])
+        },
+    );
+
+    let data: Vec<u8> = (0..1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+    assert_eq!(echo_through(port, &data), data);
+    let _ = std::fs::remove_file(&path);
+}
+
+/*
+ *   client ---> port forwarder (src_port) ---> member A (loopback server)
+ *                                        \--> member B (loopback server)
+ */
+
+// tells its port, and holds the connection until EOF
+fn reply_port(mut stream: TcpStream) {
+    let port = stream.local_addr().unwrap().port();
+    if writeln!(stream, "{port}").is_ok() {
+        let _ = stream.read_to_end(&mut vec![]);
+    }
+}
+
+fn pool_rule(member_ports: &[u16], balance: PoolBalance, sticky: bool) -> Rule {
+    Rule {
+        pool: Some(PoolTarget {
+            members: member_ports
+                .iter()
+                .map(|port| vec![SocketAddr::from((Ipv4Addr::LOCALHOST, *port))])
+                .collect(),
+            balance,
+            sticky,
+            health_check: Some(Duration::from_millis(100)),
+        }),
+        ..Rule::to(vec![])
+    }
+}
+
+// the port of the member the connection is sent to
+fn member_of(stream: &TcpStream) -> u16 {
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).unwrap();
+    line.trim().parse().unwrap()
+}
+
+fn members_of_connections(port: u16, n: usize) -> Vec<u16> {
+    (0..n).map(|_| member_of(&connect(port))).collect()
+}
+
+#[test]
+fn pool_balances_round_robin() {
+    let members = [spawn_server(reply_port), spawn_server(reply_port)];
+    let port = spawn_forwarder_with(
+        PortforwardOptions::default(),
+        pool_rule(&members, PoolBalance::RoundRobin, false),
+    );
+
+    // the probe of the forwarder may be balanced between the first two
+    let sent = members_of_connections(port, 5);
+    assert!(sent[1..].windows(2).all(|pair| pair[0] != pair[1]));
+    assert!(members.iter().all(|member| sent.contains(member)));
+}
+
+#[test]
+fn pool_least_connections_prefers_idle_member() {
+    let members = [spawn_server(reply_port), spawn_server(reply_port)];
+    let (port, pf_req) = start_forwarder(
+        PortforwardOptions::default(),
+        pool_rule(&members, PoolBalance::LeastConnections, false),
+    );
+    // the probe of start_forwarder would count for its member
+    assert!(wait_until(|| {
+        let (reply, reply_recv) = oneshot::channel();
+        pf_req
+            .send(PortforwardRequest::GetConnections(reply))
+            .unwrap();
+        reply_recv.blocking_recv().unwrap().is_empty()
+    }));
+
+    let held = connect(port);
+    let held_member = member_of(&held);
+    let other = connect(port);
+    assert_ne!(member_of(&other), held_member);
+
+    other.shutdown(Shutdown::Both).unwrap();
+    assert!(wait_until(|| connection_of(&pf_req, &other).is_none()));
+    drop(other);
+
+    // round-robin would go back to the member of the held connection
+    for _ in 0..2 {
+        let stream = connect(port);
+        assert_ne!(member_of(&stream), held_member);
+        stream.shutdown(Shutdown::Both).unwrap();
+        assert!(wait_until(|| connection_of(&pf_req, &stream).is_none()));
+    }
+}
+
+#[test]
+fn sticky_pool_keeps_client_on_member() {
+    let members = [spawn_server(reply_port), spawn_server(reply_port)];
+    let port = spawn_forwarder_with(
+        PortforwardOptions::default(),
+        pool_rule(&members, PoolBalance::RoundRobin, true),
+    );
+
+    let sent = members_of_connections(port, 4);
+    assert!(sent.iter().all(|member| *member == sent[0]));
+}
+
+#[test]
+fn pool_skips_dead_member() {
+    let live = spawn_server(reply_port);
+    let port = spawn_forwarder_with(
+        PortforwardOptions::default(),
+        pool_rule(&[unused_port(), live], PoolBalance::RoundRobin, false),
+    );
+
+    // the dead member is tried at most once, and removed by the health check
+    let sent = members_of_connections(port, 4);
+    assert!(sent.iter().all(|member| *member == live));
+}
+
+fn start_capture(
+    pf_req: &UnboundedSender<PortforwardRequest>,
+    port: u16,
+    format: CaptureFormat,
+    path: &Path,
+    max_bytes: u64,
+) -> Result<(), String> {
+    let (reply, reply_recv) = oneshot::channel();
+    pf_req
+        .send(PortforwardRequest::StartCapture {
+            protocol: PortforwardProtocol::Tcp,
+            src_port: port,
+            format,
+            path: path.to_path_buf(),
+            max_bytes,
+            reply,
+        })
+        .unwrap();
+    reply_recv.blocking_recv().unwrap()
+}
+
+fn stop_capture(pf_req: &UnboundedSender<PortforwardRequest>, port: u16) -> Option<u64> {
+    let (reply, reply_recv) = oneshot::channel();
+    pf_req
+        .send(PortforwardRequest::StopCapture(
+            PortforwardProtocol::Tcp,
+            port,
+            reply,
+        ))
+        .unwrap();
+    reply_recv.blocking_recv().unwrap()
+}
+
+// captures are only written to new files, so a file left over by an earlier run is removed
+fn capture_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("vmc-test-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+#[test]
+fn captured_transcript_holds_both_directions() {
+    // splice(2) is given up while capturing
+    for (i, options) in engines().into_iter().enumerate() {
+        let server_port = spawn_server(echo);
+        let (port, pf_req) = start_forwarder(options, local_rule(server_port));
+        let path = capture_path(&format!("transcript-{i}.txt"));
+
+        start_capture(&pf_req, port, CaptureFormat::Transcript, &path, 1 << 20).unwrap();
+        let stream = connect(port);
+        assert_eq!(
+            echo_through_stream(stream.try_clone().unwrap(), b"hello capture"),
+            b"hello capture"
+        );
+        assert!(wait_until(|| connection_of(&pf_req, &stream).is_none()));
+
+        let written = stop_capture(&pf_req, port).unwrap();
+        let transcript = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written, transcript.len() as u64);
+        assert_eq!(transcript.matches("|hello capture|").count(), 2);
+        assert!(transcript.contains(&format!(":{port} (13 bytes)")));
+        assert!(transcript.contains("closed"));
+        assert_eq!(stop_capture(&pf_req, port), None);
+    }
+}
+
+#[test]
+fn capture_never_overwrites_an_existing_file() {
+    let server_port = spawn_server(echo);
+    let (port, pf_req) = start_forwarder(PortforwardOptions::default(), local_rule(server_port));
+    let path = capture_path("existing.txt");
+    std::fs::write(&path, "keep me").unwrap();
+
+    assert!(start_capture(&pf_req, port, CaptureFormat::Transcript, &path, 1 << 20).is_err());
+    assert_eq!(stop_capture(&pf_req, port), None);
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep me");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn capture_covers_connections_opened_before_it() {
+    // a spliced connection falls back to copying once captured
+    for (i, options) in engines().into_iter().enumerate() {
+        let server_port = spawn_server(echo);
+        let (port, pf_req) = start_forwarder(options, local_rule(server_port));
+        let path = capture_path(&format!("live-{i}.txt"));
+
+        let mut stream = connect(port);
+        let mut echoed = [0; 6];
+        stream.write_all(b"before").unwrap();
+        stream.read_exact(&mut echoed).unwrap();
+
+        start_capture(&pf_req, port, CaptureFormat::Transcript, &path, 1 << 20).unwrap();
+        stream.write_all(b"after!").unwrap();
+        stream.read_exact(&mut echoed).unwrap();
+        assert_eq!(&echoed, b"after!");
+        stream.shutdown(Shutdown::Write).unwrap();
+        assert!(is_closed(&mut stream));
+        assert!(wait_until(|| connection_of(&pf_req, &stream).is_none()));
+
+        stop_capture(&pf_req, port).unwrap();
+        let transcript = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(transcript.matches("|after!|").count(), 2);
+    }
+}
+
+#[test]
+fn pcapng_capture_stops_at_max_bytes() {
+    let server_port = spawn_server(echo);
+    let (port, pf_req) = start_forwarder(PortforwardOptions::default(), local_rule(server_port));
+    let path = capture_path("bounded.pcapng");
+
+    start_capture(&pf_req, port, CaptureFormat::Pcapng, &path, 4096).unwrap();
+    let data = vec![b'x'; 64 * 1024];
+    assert_eq!(echo_through(port, &data), data);
+
+    // stopped by itself once full, which is over by the time the connection is closed
+    assert!(wait_until(|| {
+        let (reply, reply_recv) = oneshot::channel();
+        pf_req
+            .send(PortforwardRequest::GetConnections(reply))
+            .unwrap();
+        reply_recv.blocking_recv().unwrap().is_empty()
+    }));
+    assert_eq!(stop_capture(&pf_req, port), None);
+    let capture = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(capture.len() <= 4096);
+    assert_eq!(capture[..4], 0x0A0D0D0Au32.to_le_bytes());
+}
+
+#[test]
+fn capture_of_unknown_rule_is_refused() {
+    let (_, pf_req) = start_forwarder(PortforwardOptions::default(), local_rule(unused_port()));
+    let path = capture_path("unknown.txt");
+
+    assert!(start_capture(
+        &pf_req,
+        unused_port(),
+        CaptureFormat::Transcript,
+        &path,
+        4096
+    )
+    .is_err());
+    assert!(!path.exists());
+}
+
+/*
+ * UDP datagrams, each client address has a session with its own backend socket
+ *
+ *   client A ---> port forwarder (src_port) ---> session A ---> loopback echo server
+ *   client B --->                           ---> session B --->
+ */
+
+fn spawn_udp_echo() -> u16 {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let port = socket.local_addr().unwrap().port();
+
+    thread::spawn(move || {
+        let mut buf = vec![0; 65536];
+        while let Ok((n, peer)) = socket.recv_from(&mut buf) {
+            let _ = socket.send_to(&buf[..n], peer);
+        }
+    });
+
+    port
+}
+
+fn udp_client(port: u16) -> UdpSocket {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    socket.connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+    socket.set_read_timeout(Some(IO_TIMEOUT)).unwrap();
+
+    socket
+}
+
+fn start_udp_forwarder(dst_port: u16) -> (u16, UnboundedSender<PortforwardRequest>) {
+    let src_port = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let (pf_req, pf_recv) = unbounded_channel();
+    start_port_forward_service(PortforwardOptions::default(), pf_recv);
+
+    let rule = local_rule(dst_port);
+    pf_req
+        .send(PortforwardRequest::UpdateRoutingRule {
+            protocol: PortforwardProtocol::Udp,
+            src_port,
+            dst_addrs: rule.dst_addrs,
+            bind_addr: rule.bind_addr,
+            access: rule.access,
+            socket_options: rule.socket_options,
+            tunnel: None,
+            proxy_protocol: None,
+            host_path: None,
+            pool: None,
+        })
+        .unwrap();
+
+    // datagrams sent before the socket is bound are lost, so the probe is repeated
+    let probe = udp_client(src_port);
+    probe
+        .set_read_timeout(Some(Duration::from_millis(50)))
+        .unwrap();
+    loop {
+        let _ = probe.send(b"probe");
+        if probe.recv(&mut [0; 16]).is_ok() {
+            break;
+        }
+    }
+
+    (src_port, pf_req)
+}
+
+fn udp_sessions(pf_req: &UnboundedSender<PortforwardRequest>) -> Vec<PortforwardConnection> {
+    let (reply, reply_recv) = oneshot::channel();
+    pf_req
+        .send(PortforwardRequest::GetConnections(reply))
+        .unwrap();
+
+    reply_recv
+        .blocking_recv()
+        .unwrap()
+        .into_iter()
+        .filter(|connection| connection.protocol == PortforwardProtocol::Udp)
+        .collect()
+}
+
+#[test]
+fn udp_datagrams_are_forwarded_per_client() {
+    let (port, pf_req) = start_udp_forwarder(spawn_udp_echo());
+    let clients = [udp_client(port), udp_client(port)];
+
+    for round in 0..3 {
+        for (i, client) in clients.iter().enumerate() {
+            client
+                .send(format!("client {i} round {round}").as_bytes())
+                .unwrap();
+        }
+        for (i, client) in clients.iter().enumerate() {
+            let mut buf = [0; 64];
+            let n = client.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..n], format!("client {i} round {round}").as_bytes());
+        }
+    }
+
+    // a reply is counted after it is sent, so it may be received first
+    for client in clients.iter() {
+        let client_addr = client.local_addr().unwrap();
+        assert!(wait_until(|| udp_sessions(&pf_req).iter().any(|session| {
+            session.client_addr == Some(client_addr)
+                && session.host_port == port
+                && session.bytes_to_guest == 48
+                && session.bytes_from_guest == 48
+        })));
+    }
+}
+
+#[test]
+fn udp_session_is_closed_with_its_rule() {
+    let (port, pf_req) = start_udp_forwarder(spawn_udp_echo());
+    let client = udp_client(port);
+    client.send(b"ping").unwrap();
+    client.recv(&mut [0; 4]).unwrap();
+    assert!(!udp_sessions(&pf_req).is_empty());
+
+    pf_req
+        .send(PortforwardRequest::RemoveRoutingRule {
+            protocol: PortforwardProtocol::Udp,
+            src_port: port,
+        })
+        .unwrap();
+    assert!(wait_until(|| udp_sessions(&pf_req).is_empty()));
+}
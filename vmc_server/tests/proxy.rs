@@ -0,0 +1,344 @@
+/*
+ * SOCKS5, HTTP CONNECT and the reverse proxy to a "guest" on the loopback.
+ *
+ *   client ---> proxy (proxy_port) ---> loopback server, registered as the address of "vm"
+ */
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use vmc_common::types::MachineInfo;
+use vmc_server::machine_map::MachineMap;
+use vmc_server::port_forward::AccessList;
+use vmc_server::proxy::{
+    start_proxy_service, start_reverse_proxy_service, ProxyOptions, ReverseProxyOptions,
+};
+
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn unused_port() -> u16 {
+    TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn spawn_echo_server() -> u16 {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                let mut reader = stream.try_clone().unwrap();
+                if io::copy(&mut reader, &mut stream).is_ok() {
+                    let _ = stream.shutdown(Shutdown::Write);
+                }
+            });
+        }
+    });
+
+    port
+}
+
+// "vm" is registered at the loopback
+fn guest_map() -> Arc<Mutex<MachineMap>> {
+    let mut mmap = MachineMap::new(0);
+    mmap.update(&MachineInfo {
+        hostname: "vm".to_string(),
+        ipv4_addr: "127.0.0.1".to_string(),
+        ipv6_addr: None,
+    });
+
+    Arc::new(Mutex::new(mmap))
+}
+
+fn wait_for_listener(port: u16) {
+    while TcpStream::connect((Ipv4Addr::LOCALHOST, port)).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn spawn_proxy() -> u16 {
+    let port = unused_port();
+    start_proxy_service(
+        ProxyOptions {
+            bind_addr: Ipv4Addr::LOCALHOST.into(),
+            port,
+            access: AccessList::default(),
+            connect_timeout: Duration::from_secs(5),
+            ipv6_interface: None,
+        },
+        guest_map(),
+    );
+    wait_for_listener(port);
+
+    port
+}
+
+fn connect(port: u16) -> TcpStream {
+    let stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+    stream.set_read_timeout(Some(IO_TIMEOUT)).unwrap();
+
+    stream
+}
+
+// returns the reply code
+fn socks5_connect(stream: &mut TcpStream, atyp: u8, addr: &[u8], port: u16) -> u8 {
+    stream.write_all(&[5, 1, 0]).unwrap();
+    let mut method = [0; 2];
+    stream.read_exact(&mut method).unwrap();
+    assert_eq!(method, [5, 0]);
+
+    let mut request = vec![5, 1, 0, atyp];
+    request.extend_from_slice(addr);
+    request.extend(port.to_be_bytes());
+    stream.write_all(&request).unwrap();
+
+    // always replied with an ipv4 address
+    let mut reply = [0; 10];
+    stream.read_exact(&mut reply).unwrap();
+    reply[1]
+}
+
+fn socks5_domain(name: &str) -> Vec<u8> {
+    let mut addr = vec![name.len() as u8];
+    addr.extend_from_slice(name.as_bytes());
+
+    addr
+}
+
+fn assert_echoed(stream: &mut TcpStream) {
+    stream.write_all(b"hello").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+
+    let mut echoed = vec![];
+    stream.read_to_end(&mut echoed).unwrap();
+    assert_eq!(echoed, b"hello");
+}
+
+fn http_connect(stream: &mut TcpStream, authority: &str) -> String {
+    write!(
+        stream,
+        "CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n\r\n"
+    )
+    .unwrap();
+
+    let mut head = vec![];
+    let mut byte = [0];
+    while !head.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+
+    String::from_utf8(head)
+        .unwrap()
+        .lines()
+        .next()
+        .unwrap()
+        .to_string()
+}
+
+#[test]
+fn socks5_reaches_guest_by_hostname_and_address() {
+    let server_port = spawn_echo_server();
+    let proxy_port = spawn_proxy();
+
+    let mut stream = connect(proxy_port);
+    assert_eq!(
+        socks5_connect(&mut stream, 3, &socks5_domain("vm"), server_port),
+        0
+    );
+    assert_echoed(&mut stream);
+
+    let mut stream = connect(proxy_port);
+    assert_eq!(
+        socks5_connect(&mut stream, 1, &[127, 0, 0, 1], server_port),
+        0
+    );
+    assert_echoed(&mut stream);
+}
+
+#[test]
+fn socks5_refuses_other_than_guests() {
+    let server_port = spawn_echo_server();
+    let proxy_port = spawn_proxy();
+
+    let mut stream = connect(proxy_port);
+    assert_eq!(
+        socks5_connect(&mut stream, 3, &socks5_domain("unknown"), server_port),
+        2
+    );
+
+    let mut stream = connect(proxy_port);
+    assert_eq!(
+        socks5_connect(&mut stream, 1, &[127, 0, 0, 2], server_port),
+        2
+    );
+
+    // a guest without the port listened
+    let mut stream = connect(proxy_port);
+    assert_eq!(
+        socks5_connect(&mut stream, 3, &socks5_domain("vm"), unused_port()),
+        5
+    );
+}
+
+#[test]
+fn http_connect_reaches_guest() {
+    let server_port = spawn_echo_server();
+    let proxy_port = spawn_proxy();
+
+    let mut stream = connect(proxy_port);
+    assert_eq!(
+        http_connect(&mut stream, &format!("vm:{server_port}")),
+        "HTTP/1.1 200 Connection Established"
+    );
+    assert_echoed(&mut stream);
+
+    let mut stream = connect(proxy_port);
+    assert_eq!(
+        http_connect(&mut stream, &format!("unknown:{server_port}")),
+        "HTTP/1.1 403 Forbidden"
+    );
+}
+
+#[test]
+fn plain_http_request_is_refused() {
+    let proxy_port = spawn_proxy();
+
+    let mut stream = connect(proxy_port);
+    write!(
+        stream,
+        "GET http://vm:3000/ HTTP/1.1\r\nHost: vm:3000\r\n\r\n"
+    )
+    .unwrap();
+
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).unwrap();
+    assert!(reply.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+}
+
+fn spawn_reverse_proxy(guest_port: u16) -> u16 {
+    let port = unused_port();
+    start_reverse_proxy_service(
+        ReverseProxyOptions {
+            bind_addr: Ipv4Addr::LOCALHOST.into(),
+            port,
+            access: AccessList::default(),
+            connect_timeout: Duration::from_secs(5),
+            ipv6_interface: None,
+            suffixes: vec!["localhost".to_string(), ".vm.test".to_string()],
+            guest_port,
+            guest_ports: HashMap::new(),
+        },
+        guest_map(),
+    );
+    wait_for_listener(port);
+
+    port
+}
+
+// replies the request header it received as the body, or echoes after 101 on an upgrade
+fn spawn_http_server() -> u16 {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut head = String::new();
+                while !head.ends_with("\r\n\r\n") {
+                    if reader.read_line(&mut head).unwrap_or(0) == 0 {
+                        return;
+                    }
+                }
+
+                if head.contains("Upgrade: websocket") {
+                    stream
+                        .write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n")
+                        .unwrap();
+                    let _ = io::copy(&mut reader, &mut stream);
+                } else {
+                    write!(
+                        stream,
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{head}",
+                        head.len()
+                    )
+                    .unwrap();
+                }
+            });
+        }
+    });
+
+    port
+}
+
+fn http_get(port: u16, host: &str) -> String {
+    let mut stream = connect(port);
+    write!(
+        stream,
+        "GET /path HTTP/1.1\r\nHost: {host}\r\nConnection: keep-alive\r\n\r\n"
+    )
+    .unwrap();
+
+    // the connection is closed after the response
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn reverse_proxy_routes_by_host() {
+    let server_port = spawn_http_server();
+    let proxy_port = spawn_reverse_proxy(server_port);
+
+    for host in [
+        format!("vm.localhost:{proxy_port}"),
+        "vm.vm.test".to_string(),
+    ] {
+        let response = http_get(proxy_port, &host);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("GET /path HTTP/1.1\r\n"));
+        assert!(response.contains(&format!("Host: {host}\r\n")));
+        assert!(response.contains("X-Forwarded-For: 127.0.0.1\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(!response.contains("keep-alive"));
+    }
+
+    assert!(http_get(proxy_port, "unknown.localhost").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    assert!(http_get(proxy_port, "vm.example.com").starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
+
+#[test]
+fn reverse_proxy_passes_websocket_upgrade() {
+    let server_port = spawn_http_server();
+    let proxy_port = spawn_reverse_proxy(server_port);
+
+    let mut stream = connect(proxy_port);
+    stream
+        .write_all(b"GET /ws HTTP/1.1\r\nHost: vm.localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n")
+        .unwrap();
+
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut status = String::new();
+    reader.read_line(&mut status).unwrap();
+    assert_eq!(status, "HTTP/1.1 101 Switching Protocols\r\n");
+    let mut line = String::new();
+    while line != "\r\n" {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+    }
+
+    // frames are opaque to the proxy
+    stream.write_all(b"frame").unwrap();
+    let mut echoed = [0; 5];
+    reader.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"frame");
+}